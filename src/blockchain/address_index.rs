@@ -0,0 +1,85 @@
+use crate::{Error, Result};
+
+//? The key hash is length prefixed so one address can't be a prefix of another
+pub(crate) static ADDRESS_PREFIX: &[u8] = "addr-".as_bytes();
+static UNSPENT_PREFIX: &[u8] = "addr-utxo-".as_bytes();
+static HISTORY_PREFIX: &[u8] = "addr-tx-".as_bytes();
+
+//? A main chain transaction that spends from or pays to an address
+pub struct AddressTransaction {
+    pub(crate) tx_id: Vec<u8>,
+    pub(crate) block_hash: Vec<u8>,
+    pub(crate) height: u64,
+}
+
+//? Unspent outputs of an address, the value is the output amount
+pub(crate) fn unspent_key(public_key_hash: &[u8], tx_id: &[u8], out: i64) -> Vec<u8> {
+    let mut key = unspent_prefix(public_key_hash);
+    key.extend_from_slice(tx_id);
+    key.extend_from_slice(&out.to_be_bytes());
+    key
+}
+
+pub(crate) fn unspent_prefix(public_key_hash: &[u8]) -> Vec<u8> {
+    let mut key = UNSPENT_PREFIX.to_vec();
+    key.push(public_key_hash.len() as u8);
+    key.extend_from_slice(public_key_hash);
+    key
+}
+
+//? Returns the outpoint of an unspent key found under unspent_prefix
+pub(crate) fn parse_unspent_key(prefix_length: usize, key: &[u8]) -> Result<(Vec<u8>, i64)> {
+    if key.len() < prefix_length + 8 {
+        return Err(Error::CustomError(
+            "Address index key is invalid!".to_owned(),
+        ));
+    }
+
+    let (tx_id, out) = key[prefix_length..].split_at(key.len() - prefix_length - 8);
+    let mut out_bytes = [0u8; 8];
+    out_bytes.copy_from_slice(out);
+
+    Ok((tx_id.to_vec(), i64::from_be_bytes(out_bytes)))
+}
+
+pub(crate) fn encode_amount(value: u64) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
+
+pub(crate) fn decode_amount(bytes: &[u8]) -> Result<u64> {
+    match bytes.try_into() {
+        Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+        Err(_) => Err(Error::CustomError(
+            "Address index amount is invalid!".to_owned(),
+        )),
+    }
+}
+
+//? Big endian height so an address history scans in chain order, the value is the block hash
+pub(crate) fn history_key(public_key_hash: &[u8], height: u64, tx_id: &[u8]) -> Vec<u8> {
+    let mut key = history_prefix(public_key_hash);
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(tx_id);
+    key
+}
+
+pub(crate) fn history_prefix(public_key_hash: &[u8]) -> Vec<u8> {
+    let mut key = HISTORY_PREFIX.to_vec();
+    key.push(public_key_hash.len() as u8);
+    key.extend_from_slice(public_key_hash);
+    key
+}
+
+pub(crate) fn parse_history_key(prefix_length: usize, key: &[u8]) -> Result<(u64, Vec<u8>)> {
+    if key.len() < prefix_length + 8 {
+        return Err(Error::CustomError(
+            "Address index key is invalid!".to_owned(),
+        ));
+    }
+
+    let (height, tx_id) = key[prefix_length..].split_at(8);
+    let mut height_bytes = [0u8; 8];
+    height_bytes.copy_from_slice(height);
+
+    Ok((u64::from_be_bytes(height_bytes), tx_id.to_vec()))
+}
@@ -1,22 +1,60 @@
-use std::time::SystemTime;
+use std::{sync::atomic::AtomicBool, time::SystemTime};
 
-use super::{merkle::MerkleTree, proof::ProofOfWork, transaction::Transaction};
+use super::{merkle::MerkleTree, miner::Miner, proof::initial_bits, transaction::Transaction};
 
 use serde_derive::{Deserialize, Serialize};
 
 use crate::Result;
 
-#[derive(Serialize, Deserialize)]
+pub(crate) static BLOCK_VERSION: u32 = 1;
+
+//? Everything the proof of work commits to, the transactions are committed through the merkle root
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockHeader {
+    pub(crate) version: u32,
+    pub(crate) prevhash: Vec<u8>,
+    pub(crate) merkle_root: Vec<u8>,
+    pub(crate) timestamp: u64,
+    pub(crate) bits: u32,
+    pub(crate) nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Block {
+    pub(crate) header: BlockHeader,
     pub(crate) transactions: Vec<Transaction>,
-    pub(crate) prevhash: Vec<u8>,
     pub(crate) hash: Vec<u8>,
-    pub(crate) nonce: u64,
     pub(crate) height: u64,
-    pub(crate) timestamp: u64,
 }
 
-impl<'a> Block {
+//? What is stored next to the header, the block hash is the key of both
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BlockBody {
+    pub(crate) transactions: Vec<Transaction>,
+    pub(crate) height: u64,
+}
+
+impl<'a> BlockHeader {
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    pub(crate) fn deserialize(bytes: &'a [u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+impl<'a> BlockBody {
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    pub(crate) fn deserialize(bytes: &'a [u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+impl Block {
     pub(crate) fn hash_transactions(&self) -> Result<Vec<u8>> {
         let mut hashes = vec![];
         for tx in self.transactions.iter() {
@@ -29,38 +67,56 @@ impl<'a> Block {
     }
 
     pub(crate) fn genesis(coinbase: Transaction) -> Result<Self> {
-        Self::create_block(vec![coinbase], vec![], 0)
+        let mut block = Self::new_template(vec![coinbase], vec![], 0, initial_bits(), 0)?;
+        Miner::default().mine(&mut block, &AtomicBool::new(false))?;
+
+        Ok(block)
     }
 
-    pub(crate) fn create_block(
+    //? A block ready to be mined, its hash stays empty until a nonce is found
+    pub(crate) fn new_template(
         transactions: Vec<Transaction>,
         prevhash: Vec<u8>,
         height: u64,
+        bits: u32,
+        min_timestamp: u64,
     ) -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("System time is earlier than Unix epoch")
+            .as_secs();
+
         let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prevhash,
+                merkle_root: vec![],
+                timestamp: timestamp.max(min_timestamp),
+                bits,
+                nonce: 0u64,
+            },
             transactions,
-            prevhash,
             hash: vec![],
-            nonce: 0u64,
             height,
-            timestamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("System time is earlier than Unix epoch")
-                .as_secs(),
         };
-        let (nonce, block_hash) = ProofOfWork::new_proof(&block).run()?;
-
-        block.nonce = nonce;
-        block.hash = block_hash.to_vec();
+        block.header.merkle_root = block.hash_transactions()?;
 
         Ok(block)
     }
 
-    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(&self)?)
+    pub(crate) fn from_parts(header: BlockHeader, body: BlockBody, hash: Vec<u8>) -> Self {
+        Self {
+            header,
+            transactions: body.transactions,
+            hash,
+            height: body.height,
+        }
     }
 
-    pub(crate) fn deserialize(bytes: &'a [u8]) -> Result<Self> {
-        Ok(bincode::deserialize(bytes)?)
+    pub(crate) fn body(&self) -> BlockBody {
+        BlockBody {
+            transactions: self.transactions.clone(),
+            height: self.height,
+        }
     }
 }
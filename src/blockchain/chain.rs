@@ -1,12 +1,37 @@
-use std::{collections::HashMap, path::Path};
-
-use super::{block::Block, transaction::Transaction, tx::TxOutputs};
-
-use crate::{blockchain::proof::ProofOfWork, Error, Result};
-
+use std::{collections::HashMap, path::Path, sync::atomic::AtomicBool};
+
+use super::{
+    block::{Block, BlockBody, BlockHeader},
+    consensus::block_subsidy,
+    index::BlockIndex,
+    mempool::Mempool,
+    miner::Miner,
+    transaction::Transaction,
+    tx::TxOutputs,
+    txindex::{self, TransactionInfo, TXINDEX_KEY, TX_PREFIX},
+    undo::BlockUndo,
+    utxo::{self, UTXOView},
+    validation::{check_inputs, MAX_BLOCK_SIZE},
+};
+
+use crate::{
+    blockchain::proof::{block_work, retarget, ProofOfWork, RETARGET_INTERVAL},
+    wallet::Wallets,
+    Error, Result,
+};
+
+use num_bigint::BigInt;
 use sled::{Batch, Config, Db};
 
 static LH_KEY: &[u8; 2] = b"LH";
+static INDEX_PREFIX: &[u8] = "index-".as_bytes();
+static MAIN_PREFIX: &[u8] = "main-".as_bytes();
+//? Dense locator entries before the steps start doubling
+static LOCATOR_DENSE: usize = 10;
+static HEADER_PREFIX: &[u8] = "header-".as_bytes();
+static BODY_PREFIX: &[u8] = "body-".as_bytes();
+//? Transactions the wallet broadcast from this node, kept to replace them while unconfirmed
+static WALLET_TX_PREFIX: &[u8] = "wallet-tx-".as_bytes();
 static DB_PATH: &str = "./tmp/blocks/block";
 
 #[derive(Clone)]
@@ -26,11 +51,59 @@ impl BlockChain {
             ));
         }
 
-        let genesis = Block::genesis(Transaction::coinbase_tx(address)?)?;
         let database: Db = Config::default().path(database_path).open()?;
+        Self::create(database, address)
+    }
+
+    //? A chain in a throwaway database, gone when the last handle is dropped
+    #[cfg(test)]
+    pub(crate) fn temporary(address: &str) -> Result<Self> {
+        Self::create(Config::new().temporary(true).open()?, address)
+    }
+
+    //? A mined block on top of any stored block, its coinbase takes the subsidy without the fees
+    #[cfg(test)]
+    pub(crate) fn child_block(
+        &self,
+        parent: &[u8],
+        transactions: Vec<Transaction>,
+        address: &str,
+    ) -> Result<Block> {
+        let header = match self.get_header(parent)? {
+            Some(header) => header,
+            None => {
+                return Err(Error::CustomError(
+                    "Parent block doesn't exists!".to_owned(),
+                ))
+            }
+        };
+        let height = self.get_index(parent)?.height + 1;
+
+        let mut block_transactions =
+            vec![Transaction::coinbase_tx(address, block_subsidy(height))?];
+        block_transactions.extend(transactions);
+        let mut block = Block::new_template(
+            block_transactions,
+            parent.to_vec(),
+            height,
+            self.next_bits(&header, height - 1)?,
+            self.median_time_past(&header)?,
+        )?;
+        Miner::default().mine(&mut block, &AtomicBool::new(false))?;
+
+        Ok(block)
+    }
+
+    fn create(database: Db, address: &str) -> Result<Self> {
+        let genesis = Block::genesis(Transaction::coinbase_tx(address, block_subsidy(0))?)?;
 
         let mut batch = Batch::default();
-        batch.insert(genesis.hash.clone(), genesis.serialize()?);
+        store_block(&mut batch, &genesis)?;
+        batch.insert(
+            index_key(&genesis.hash),
+            BlockIndex::new(genesis.height, &block_work(genesis.header.bits)).serialize()?,
+        );
+        batch.insert(main_key(genesis.height), genesis.hash.clone());
         batch.insert(LH_KEY, genesis.hash.clone());
         database.apply_batch(batch)?;
 
@@ -64,94 +137,370 @@ impl BlockChain {
         }
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        for tx in transactions.iter() {
-            self.verify_transaction(tx)?;
+    //? Picks the candidates paying the most per byte that fit in a block, the coinbase collects their fees
+    pub fn block_template(
+        &self,
+        candidates: Vec<Transaction>,
+        mine_address: &str,
+    ) -> Result<Block> {
+        let tip = match self.get_header(&self.lasthash)? {
+            Some(header) => header,
+            None => return Err(Error::CustomError("Tip block doesn't exists!".to_owned())),
+        };
+        let tip_height = self.get_index(&self.lasthash)?.height;
+
+        let mut view = UTXOView::new(&self.database);
+        //? Fees are only estimated here, a candidate may spend another one that isn't connected yet
+        let unconfirmed: HashMap<Vec<u8>, Vec<u64>> = candidates
+            .iter()
+            .map(|tx| {
+                let values = tx.outputs.iter().map(|tx_output| tx_output.value).collect();
+                (tx.id.clone(), values)
+            })
+            .collect();
+        let mut candidates: Vec<(Transaction, u64, usize)> = candidates
+            .into_iter()
+            .filter(|tx| !tx.is_coinbase())
+            .filter_map(|tx| {
+                let fee = estimate_fee(&tx, &view, &unconfirmed)?;
+                let size = tx.size().ok()?;
+                Some((tx, fee, size))
+            })
+            .collect();
+        candidates.sort_by(|(_, fee_a, size_a), (_, fee_b, size_b)| {
+            (*fee_b as u128 * *size_a as u128).cmp(&(*fee_a as u128 * *size_b as u128))
+        });
+
+        let mut undo = BlockUndo::default();
+        let mut transactions = vec![];
+        let mut fees = 0u64;
+        let mut size = Transaction::coinbase_tx(mine_address, 0)?.size()?;
+
+        //? A candidate spending another one only fits once its parent is in, so retry until nothing changes
+        let mut added = true;
+        while added {
+            added = false;
+            let mut remaining = vec![];
+
+            for (tx, estimated_fee, tx_size) in candidates {
+                let fee = match check_inputs(&tx, &view, tip_height + 1) {
+                    Ok(fee) if size + tx_size <= MAX_BLOCK_SIZE => fee,
+                    _ => {
+                        remaining.push((tx, estimated_fee, tx_size));
+                        continue;
+                    }
+                };
+
+                view.connect_transaction(&tx, tip_height + 1, &mut undo)?;
+                fees = fees.saturating_add(fee);
+                size += tx_size;
+                transactions.push(tx);
+                added = true;
+            }
+            candidates = remaining;
         }
 
-        let lasthash = self.database.get(LH_KEY)?.unwrap().to_vec();
-        let last_block_bytes = self.database.get(lasthash.clone())?.unwrap();
-        let last_block: Block = bincode::deserialize(&last_block_bytes)?;
+        let value = block_subsidy(tip_height + 1).saturating_add(fees);
+        transactions.insert(0, Transaction::coinbase_tx(mine_address, value)?);
+
+        Block::new_template(
+            transactions,
+            self.lasthash.clone(),
+            tip_height + 1,
+            self.next_bits(&tip, tip_height)?,
+            self.median_time_past(&tip)?,
+        )
+    }
 
-        let new_block = Block::create_block(transactions, lasthash, last_block.height + 1)?;
+    pub fn mine_block(
+        &mut self,
+        transactions: Vec<Transaction>,
+        mine_address: &str,
+    ) -> Result<Block> {
+        let mut new_block = self.block_template(transactions, mine_address)?;
+        Miner::default().mine(&mut new_block, &AtomicBool::new(false))?;
+        self.add_block(&new_block)?;
+
+        Ok(new_block)
+    }
+
+    //? Headers are indexed ahead of their bodies during sync, the tip only moves with full blocks
+    pub fn add_header(&mut self, header: &BlockHeader) -> Result<Vec<u8>> {
+        let hash = ProofOfWork::new_proof(header).hash()?;
+        if self.database.contains_key(index_key(&hash))? {
+            return Ok(hash);
+        }
+
+        let height = self.validate_header(header, &hash)?;
+        let work = self.get_work(&header.prevhash)? + block_work(header.bits);
 
         let mut batch = Batch::default();
-        batch.insert(new_block.hash.clone(), new_block.serialize()?);
-        batch.insert(LH_KEY, new_block.hash.clone());
+        batch.insert(header_key(&hash), header.serialize()?);
+        batch.insert(
+            index_key(&hash),
+            BlockIndex::new(height, &work).serialize()?,
+        );
         self.database.apply_batch(batch)?;
 
-        self.lasthash = new_block.hash.clone();
-
-        Ok(new_block)
+        Ok(hash)
     }
 
-    pub fn add_block(&mut self, block: &Block) -> Result<()> {
-        if self.database.get(&block.hash)?.is_none() {
-            self.database
-                .insert(&block.hash, bincode::serialize(block)?)?;
+    pub fn add_block(&mut self, block: &Block) -> Result<ChainUpdate> {
+        if self.has_block(&block.hash)? {
+            return Ok(ChainUpdate::default());
+        }
 
-            let lasthash = self.database.get(LH_KEY)?.unwrap().to_vec();
-            let last_block_bytes = self.database.get(lasthash)?.unwrap();
-            let last_block: Block = bincode::deserialize(&last_block_bytes)?;
+        self.validate_block(block)?;
 
-            if block.height > last_block.height {
-                self.database.insert(LH_KEY, block.hash.clone())?;
-                self.lasthash = block.hash.clone();
-            }
+        let work = self.get_work(&block.header.prevhash)? + block_work(block.header.bits);
+
+        let mut batch = Batch::default();
+        store_block(&mut batch, block)?;
+        batch.insert(
+            index_key(&block.hash),
+            BlockIndex::new(block.height, &work).serialize()?,
+        );
+        self.database.apply_batch(batch)?;
+
+        //? Only switch when the new branch carries strictly more work, the first seen tip wins ties
+        if work <= self.get_work(&self.lasthash)? {
+            return Ok(ChainUpdate::default());
         }
 
-        Ok(())
-    }
+        let update = self.find_fork(block)?;
+        let mut batch = Batch::default();
+        let checked = self
+            .validate_update(&update)
+            .and_then(|_| utxo::write_update(&self.database, &update, &mut batch));
+        if let Err(err) = checked {
+            let mut batch = Batch::default();
+            batch.remove(header_key(&block.hash));
+            batch.remove(body_key(&block.hash));
+            batch.remove(index_key(&block.hash));
+            self.database.apply_batch(batch)?;
+
+            return Err(err);
+        }
 
-    pub fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
-        let block_bytes = self.database.get(block_hash)?;
-        if block_bytes.is_some() {
-            let block: Block = bincode::deserialize(&block_bytes.unwrap())?;
-            return Ok(Some(block));
+        //? The tip moves in the same batch as the UTXO set, a crash leaves both before or both after the block
+        for disconnected in update.disconnected.iter() {
+            batch.remove(main_key(disconnected.height));
+        }
+        for connected in update.connected.iter() {
+            batch.insert(main_key(connected.height), connected.hash.clone());
         }
+        batch.insert(LH_KEY, block.hash.clone());
+        self.database.apply_batch(batch)?;
+        self.lasthash = block.hash.clone();
 
-        Ok(None)
+        Ok(update)
     }
 
-    pub fn get_block_hashes(&self) -> Result<Vec<Vec<u8>>> {
-        let mut hashes = vec![];
-        let mut iter = self.iterator();
+    pub fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        read_block(&self.database, block_hash)
+    }
 
-        while let Some(block) = iter.next()? {
-            hashes.push(block.hash)
+    pub fn get_header(&self, block_hash: &[u8]) -> Result<Option<BlockHeader>> {
+        match self.database.get(header_key(block_hash))? {
+            Some(bytes) => Ok(Some(BlockHeader::deserialize(&bytes)?)),
+            None => Ok(None),
         }
+    }
 
-        Ok(hashes)
+    pub fn has_block(&self, block_hash: &[u8]) -> Result<bool> {
+        Ok(self.database.contains_key(body_key(block_hash))?)
     }
 
     pub fn get_best_height(&self) -> Result<u64> {
         let lasthash = self.database.get(LH_KEY)?.unwrap().to_vec();
-        let last_block_bytes = self.database.get(lasthash)?.unwrap();
-        let last_block: Block = bincode::deserialize(&last_block_bytes).unwrap();
 
-        Ok(last_block.height)
+        Ok(self.get_index(&lasthash)?.height)
     }
 
-    pub fn verify_transaction(&self, tx: &Transaction) -> Result<()> {
-        if tx.is_coinbase() {
-            return Ok(());
+    //? Main chain hashes from the tip, one by one at first then doubling the step down to genesis
+    pub fn block_locator(&self) -> Result<Vec<Vec<u8>>> {
+        let mut locator = vec![];
+        let mut height = self.get_best_height()?;
+        let mut step = 1;
+
+        loop {
+            locator.push(self.get_main_hash(height)?);
+            if height == 0 {
+                break;
+            }
+
+            if locator.len() >= LOCATOR_DENSE {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
         }
 
-        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        Ok(locator)
+    }
 
-        for tx_input in tx.inputs.iter() {
-            if let Some(prev_tx) = self.find_transaction(&tx_input.id)? {
-                prev_txs.insert(hex::encode(&prev_tx.id), prev_tx);
+    //? Main chain headers after the first locator hash we share, up to the stop hash or the limit
+    pub fn locate_headers(
+        &self,
+        locator: &[Vec<u8>],
+        stop: &[u8],
+        limit: usize,
+    ) -> Result<Vec<BlockHeader>> {
+        let mut height = 0;
+        for hash in locator {
+            if let Some(bytes) = self.database.get(index_key(hash))? {
+                let index = BlockIndex::deserialize(&bytes)?;
+                if self.get_main_hash(index.height).ok().as_ref() == Some(hash) {
+                    height = index.height;
+                    break;
+                }
+            }
+        }
+
+        let mut headers = vec![];
+        while headers.len() < limit {
+            height += 1;
+            let hash = match self.database.get(main_key(height))? {
+                Some(hash) => hash.to_vec(),
+                None => break,
+            };
+
+            match self.get_header(&hash)? {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+            if hash == stop {
+                break;
+            }
+        }
+
+        Ok(headers)
+    }
+
+    pub(crate) fn get_index(&self, block_hash: &[u8]) -> Result<BlockIndex> {
+        match self.database.get(index_key(block_hash))? {
+            Some(bytes) => BlockIndex::deserialize(&bytes),
+            None => Err(Error::CustomError("Block index doesn't exists!".to_owned())),
+        }
+    }
+
+    pub fn get_main_hash(&self, height: u64) -> Result<Vec<u8>> {
+        match self.database.get(main_key(height))? {
+            Some(hash) => Ok(hash.to_vec()),
+            None => Err(Error::CustomError(format!(
+                "Main chain block at height {} doesn't exists!",
+                height
+            ))),
+        }
+    }
+
+    fn get_work(&self, block_hash: &[u8]) -> Result<BigInt> {
+        Ok(self.get_index(block_hash)?.work())
+    }
+
+    fn find_fork(&self, new_tip: &Block) -> Result<ChainUpdate> {
+        let mut update = ChainUpdate::default();
+
+        let mut old = match self.get_block(&self.lasthash)? {
+            Some(block) => block,
+            None => return Err(Error::CustomError("Tip block doesn't exists!".to_owned())),
+        };
+        let mut new = new_tip.clone();
+
+        while old.hash != new.hash {
+            if old.height >= new.height {
+                let parent = self.get_parent(&old)?;
+                update.disconnected.push(old);
+                old = parent;
             } else {
-                return Err(Error::CustomError(
-                    "Previous transaction doesn't exists (verify)!".to_owned(),
-                ));
+                let parent = self.get_parent(&new)?;
+                update.connected.push(new);
+                new = parent;
             }
         }
+        update.connected.reverse();
 
-        tx.verify(&prev_txs)?;
+        Ok(update)
+    }
 
-        Ok(())
+    fn get_parent(&self, block: &Block) -> Result<Block> {
+        match self.get_block(&block.header.prevhash)? {
+            Some(parent) => Ok(parent),
+            None => Err(Error::CustomError(
+                "Branches don't share a common ancestor!".to_owned(),
+            )),
+        }
+    }
+
+    pub(crate) fn next_bits(&self, parent: &BlockHeader, parent_height: u64) -> Result<u32> {
+        if !(parent_height + 1).is_multiple_of(RETARGET_INTERVAL) {
+            return Ok(parent.bits);
+        }
+
+        let mut first_timestamp = parent.timestamp;
+        let mut hash = parent.prevhash.clone();
+        for _ in 1..RETARGET_INTERVAL {
+            let header = match self.get_header(&hash)? {
+                Some(header) => header,
+                None => {
+                    return Err(Error::CustomError(
+                        "Retarget ancestor doesn't exists!".to_owned(),
+                    ))
+                }
+            };
+            first_timestamp = header.timestamp;
+            hash = header.prevhash;
+        }
+
+        Ok(retarget(
+            parent.bits,
+            parent.timestamp.saturating_sub(first_timestamp),
+        ))
+    }
+
+    //? Fee of a transaction spending outputs of the current UTXO set
+    //? Inputs come from the UTXO set or, with a memory pool, from the outputs of unconfirmed parents
+    pub fn transaction_fee(&self, tx: &Transaction, mempool: Option<&Mempool>) -> Result<u64> {
+        if self.get_transaction(&tx.id)?.is_some() {
+            return Err(Error::CustomError(
+                "Transaction is already confirmed!".to_owned(),
+            ));
+        }
+
+        let view = UTXOView::new(&self.database);
+        let mut input_value = 0u64;
+        for tx_input in tx.inputs.iter() {
+            let confirmed = view
+                .get_outputs(&tx_input.id)?
+                .and_then(|tx_outputs| tx_outputs.outputs.get(&tx_input.out).cloned());
+            let unconfirmed = || {
+                mempool
+                    .and_then(|mempool| mempool.get(&tx_input.id))
+                    .and_then(|parent| {
+                        usize::try_from(tx_input.out)
+                            .ok()
+                            .and_then(|out| parent.outputs.get(out))
+                    })
+                    .cloned()
+            };
+            match confirmed.or_else(unconfirmed) {
+                Some(prev_output) => input_value = input_value.saturating_add(prev_output.value),
+                None => {
+                    return Err(Error::CustomError(
+                        "Transaction input doesn't exists!".to_owned(),
+                    ))
+                }
+            }
+        }
+
+        let output_value = tx.outputs.iter().fold(0u64, |total, tx_output| {
+            total.saturating_add(tx_output.value)
+        });
+        match input_value.checked_sub(output_value) {
+            Some(fee) => Ok(fee),
+            None => Err(Error::CustomError(
+                "Transaction outputs exceed its inputs!".to_owned(),
+            )),
+        }
     }
 
     pub(crate) fn find_all_utxo(&self) -> Result<HashMap<String, TxOutputs>> {
@@ -174,9 +523,13 @@ impl BlockChain {
                     }
 
                     utxo.entry(tx_id.clone())
-                        .or_insert(TxOutputs::new())
+                        .or_insert_with(|| TxOutputs {
+                            height: block.height,
+                            coinbase: tx.is_coinbase(),
+                            ..TxOutputs::new()
+                        })
                         .outputs
-                        .push(tx_output.clone());
+                        .insert(out_index as i64, tx_output.clone());
                 }
 
                 if !tx.is_coinbase() {
@@ -190,7 +543,7 @@ impl BlockChain {
                 }
             }
 
-            if block.prevhash.is_empty() {
+            if block.header.prevhash.is_empty() {
                 break;
             }
         }
@@ -198,12 +551,21 @@ impl BlockChain {
         Ok(utxo)
     }
 
-    pub(crate) fn sign_transaction(&self, tx: &mut Transaction, address: &str) -> Result<()> {
-        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+    pub(crate) fn sign_transaction(
+        &self,
+        tx: &mut Transaction,
+        wallets: &Wallets,
+        mempool: Option<&Mempool>,
+    ) -> Result<()> {
+        let mut prev_outputs: HashMap<String, TxOutputs> = HashMap::new();
 
+        //? Unconfirmed change spends a transaction still in the memory pool
         for tx_input in tx.inputs.iter() {
-            if let Some(prev_tx) = self.find_transaction(&tx_input.id)? {
-                prev_txs.insert(hex::encode(&prev_tx.id), prev_tx);
+            let unconfirmed = mempool
+                .and_then(|mempool| mempool.get(&tx_input.id))
+                .cloned();
+            if let Some(prev_tx) = self.find_transaction(&tx_input.id)?.or(unconfirmed) {
+                prev_outputs.insert(hex::encode(&prev_tx.id), prev_tx.tx_outputs());
             } else {
                 return Err(Error::CustomError(
                     "Previous transaction doesn't exists (sign)!".to_owned(),
@@ -211,27 +573,184 @@ impl BlockChain {
             }
         }
 
-        tx.sign(address, &prev_txs)?;
+        tx.sign(wallets, &prev_outputs)?;
 
         Ok(())
     }
 
-    fn find_transaction(&self, id: &[u8]) -> Result<Option<Transaction>> {
+    //? Uses the transaction index once it has been built, otherwise walks the main chain from the tip
+    pub fn get_transaction(&self, id: &[u8]) -> Result<Option<TransactionInfo>> {
+        let best_height = self.get_best_height()?;
+
+        if txindex::is_enabled(&self.database)? {
+            let location = match txindex::get(&self.database, id)? {
+                Some(location) => location,
+                None => return Ok(None),
+            };
+            let block = match self.get_block(&location.block_hash)? {
+                Some(block) => block,
+                None => {
+                    return Err(Error::CustomError(
+                        "Indexed block doesn't exists!".to_owned(),
+                    ))
+                }
+            };
+
+            return match block.transactions.get(location.position as usize) {
+                Some(tx) if tx.id == id => Ok(Some(TransactionInfo {
+                    transaction: tx.clone(),
+                    block_hash: block.hash.clone(),
+                    block_height: block.height,
+                    confirmations: best_height - block.height + 1,
+                })),
+                _ => Err(Error::CustomError(
+                    "Indexed transaction doesn't exists!".to_owned(),
+                )),
+            };
+        }
+
         let mut iter = self.iterator();
         while let Some(block) = iter.next()? {
             for tx in block.transactions {
                 if tx.id == id {
-                    return Ok(Some(tx));
+                    return Ok(Some(TransactionInfo {
+                        transaction: tx,
+                        block_hash: block.hash.clone(),
+                        block_height: block.height,
+                        confirmations: best_height - block.height + 1,
+                    }));
                 }
             }
 
-            if block.prevhash.is_empty() {
+            if block.header.prevhash.is_empty() {
                 break;
             }
         }
 
         Ok(None)
     }
+
+    //? Builds the transaction index from the main chain and keeps it maintained from then on
+    pub fn reindex_transactions(&self) -> Result<usize> {
+        let mut batch = Batch::default();
+        for key in self.database.scan_prefix(TX_PREFIX).keys() {
+            batch.remove(key?);
+        }
+
+        let mut count = 0;
+        let mut iter = self.iterator();
+        while let Some(block) = iter.next()? {
+            txindex::insert(&mut batch, &block)?;
+            count += block.transactions.len();
+        }
+
+        batch.insert(TXINDEX_KEY, vec![]);
+        self.database.apply_batch(batch)?;
+
+        Ok(count)
+    }
+
+    pub fn save_wallet_transaction(&self, tx: &Transaction) -> Result<()> {
+        self.database
+            .insert(wallet_tx_key(&tx.id), tx.serialize()?)?;
+        Ok(())
+    }
+
+    pub fn get_wallet_transaction(&self, id: &[u8]) -> Result<Option<Transaction>> {
+        match self.database.get(wallet_tx_key(id))? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn find_transaction(&self, id: &[u8]) -> Result<Option<Transaction>> {
+        Ok(self.get_transaction(id)?.map(|info| info.transaction))
+    }
+}
+
+//? Inputs are valued from the UTXO set or from the unconfirmed transaction they spend
+fn estimate_fee(
+    tx: &Transaction,
+    view: &UTXOView<'_>,
+    unconfirmed: &HashMap<Vec<u8>, Vec<u64>>,
+) -> Option<u64> {
+    let mut input_value = 0u64;
+    for tx_input in tx.inputs.iter() {
+        let confirmed = view
+            .get_outputs(&tx_input.id)
+            .ok()?
+            .and_then(|tx_outputs| tx_outputs.outputs.get(&tx_input.out).map(|out| out.value));
+        let value = match confirmed {
+            Some(value) => value,
+            None => *unconfirmed
+                .get(&tx_input.id)?
+                .get(usize::try_from(tx_input.out).ok()?)?,
+        };
+        input_value = input_value.checked_add(value)?;
+    }
+
+    let output_value = tx
+        .outputs
+        .iter()
+        .try_fold(0u64, |total, tx_output| total.checked_add(tx_output.value))?;
+    input_value.checked_sub(output_value)
+}
+
+//? Blocks to roll back (tip first) and blocks to apply (oldest first) after a tip change
+#[derive(Default)]
+pub struct ChainUpdate {
+    pub(crate) disconnected: Vec<Block>,
+    pub(crate) connected: Vec<Block>,
+}
+
+fn store_block(batch: &mut Batch, block: &Block) -> Result<()> {
+    batch.insert(header_key(&block.hash), block.header.serialize()?);
+    batch.insert(body_key(&block.hash), block.body().serialize()?);
+    Ok(())
+}
+
+fn read_block(database: &Db, block_hash: &[u8]) -> Result<Option<Block>> {
+    let header = match database.get(header_key(block_hash))? {
+        Some(bytes) => BlockHeader::deserialize(&bytes)?,
+        None => return Ok(None),
+    };
+    let body = match database.get(body_key(block_hash))? {
+        Some(bytes) => BlockBody::deserialize(&bytes)?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Block::from_parts(header, body, block_hash.to_vec())))
+}
+
+fn header_key(block_hash: &[u8]) -> Vec<u8> {
+    let mut key = HEADER_PREFIX.to_vec();
+    key.extend_from_slice(block_hash);
+    key
+}
+
+fn body_key(block_hash: &[u8]) -> Vec<u8> {
+    let mut key = BODY_PREFIX.to_vec();
+    key.extend_from_slice(block_hash);
+    key
+}
+
+fn index_key(block_hash: &[u8]) -> Vec<u8> {
+    let mut key = INDEX_PREFIX.to_vec();
+    key.extend_from_slice(block_hash);
+    key
+}
+
+//? Big endian so the main chain scans in height order
+fn main_key(height: u64) -> Vec<u8> {
+    let mut key = MAIN_PREFIX.to_vec();
+    key.extend_from_slice(&height.to_be_bytes());
+    key
+}
+
+fn wallet_tx_key(tx_id: &[u8]) -> Vec<u8> {
+    let mut key = WALLET_TX_PREFIX.to_vec();
+    key.extend_from_slice(tx_id);
+    key
 }
 
 pub struct BlockChainIterator {
@@ -241,35 +760,75 @@ pub struct BlockChainIterator {
 
 impl BlockChainIterator {
     pub(crate) fn next(&mut self) -> Result<Option<Block>> {
-        match self.database.get(&self.current_hash)? {
+        match read_block(&self.database, &self.current_hash)? {
             None => Ok(None),
-            Some(bytes) => match Block::deserialize(&bytes) {
-                Ok(block) => {
-                    self.current_hash = block.prevhash.clone();
-                    Ok(Some(block))
-                }
-                Err(err) => Err(err),
-            },
+            Some(block) => {
+                self.current_hash = block.header.prevhash.clone();
+                Ok(Some(block))
+            }
         }
     }
 
     pub fn next_print(&mut self) -> Result<Option<()>> {
-        match self.database.get(&self.current_hash)? {
+        match read_block(&self.database, &self.current_hash)? {
             None => Ok(None),
-            Some(bytes) => match Block::deserialize(&bytes) {
-                Ok(block) => {
-                    self.current_hash = block.prevhash.clone();
-                    println!("PoW: {}", ProofOfWork::new_proof(&block).validate()?);
-                    println!("Hash: {:?}", hex::encode(&block.hash));
-                    println!("Prevhash: {:?}", hex::encode(&block.prevhash));
-                    println!("Transactions:");
-                    for tx in block.transactions.iter() {
-                        println!("{tx}");
-                    }
-                    Ok(Some(()))
+            Some(block) => {
+                self.current_hash = block.header.prevhash.clone();
+                println!("PoW: {}", ProofOfWork::new_proof(&block.header).validate()?);
+                println!("Hash: {:?}", hex::encode(&block.hash));
+                println!("Prevhash: {:?}", hex::encode(&block.header.prevhash));
+                println!("Merkle root: {:?}", hex::encode(&block.header.merkle_root));
+                println!("Timestamp: {}", block.header.timestamp);
+                println!("Bits: {:#010x}", block.header.bits);
+                println!("Transactions:");
+                for tx in block.transactions.iter() {
+                    println!("{tx}");
                 }
-                Err(err) => Err(err),
-            },
+                Ok(Some(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::blockchain::BlockRejection;
+
+    fn mine(block: &mut Block) {
+        Miner::default()
+            .mine(block, &AtomicBool::new(false))
+            .unwrap();
+    }
+
+    //? Blocks mined back to back take less than the target time, so the boundary raises the difficulty
+    #[test]
+    fn rejects_unexpected_bits_at_retarget() {
+        let address = Wallets::in_memory().add_wallet(None).unwrap();
+        let mut chain = BlockChain::temporary(&address).unwrap();
+        while chain.get_best_height().unwrap() + 1 < RETARGET_INTERVAL {
+            chain.mine_block(vec![], &address).unwrap();
         }
+
+        let template = chain.block_template(vec![], &address).unwrap();
+        let parent_bits = chain.get_header(&chain.lasthash).unwrap().unwrap().bits;
+        assert_ne!(template.header.bits, parent_bits);
+
+        let mut block = template.clone();
+        block.header.bits = parent_bits;
+        mine(&mut block);
+        match chain.add_block(&block) {
+            Err(Error::BlockRejected {
+                source: BlockRejection::BadDifficulty { bits, expected },
+            }) => assert_eq!((bits, expected), (parent_bits, template.header.bits)),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("block with the parent bits was accepted"),
+        }
+
+        let mut block = template;
+        mine(&mut block);
+        chain.add_block(&block).unwrap();
+        assert_eq!(chain.get_best_height().unwrap(), RETARGET_INTERVAL);
     }
 }
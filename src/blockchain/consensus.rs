@@ -0,0 +1,70 @@
+//? Money issuance rules, every node has to agree on them
+pub static INITIAL_SUBSIDY: u64 = 20;
+pub static HALVING_INTERVAL: u64 = 210; //? Blocks
+pub static MAX_MONEY: u64 = 8400;
+//? Blocks a coinbase output waits before it can be spent, until then a reorg could still take it away
+pub static COINBASE_MATURITY: u64 = 10;
+
+//? Coins the coinbase of the block at this height may create on top of the fees
+pub fn block_subsidy(height: u64) -> u64 {
+    match height {
+        0 => supply_at(0),
+        _ => supply_at(height) - supply_at(height - 1),
+    }
+}
+
+//? The genesis coinbase can't be reorganized away so it is spendable right away
+pub(crate) fn is_mature(coinbase_height: u64, spend_height: u64) -> bool {
+    coinbase_height == 0 || spend_height >= coinbase_height.saturating_add(COINBASE_MATURITY)
+}
+
+//? Coins issued by the blocks up to and including this height, never above MAX_MONEY
+pub fn supply_at(height: u64) -> u64 {
+    let mut supply = 0u64;
+    let mut era_start = 0u64;
+    let mut subsidy = INITIAL_SUBSIDY;
+
+    while era_start <= height && subsidy > 0 {
+        let era_end = height.min(era_start.saturating_add(HALVING_INTERVAL - 1));
+        supply = supply.saturating_add(subsidy.saturating_mul(era_end - era_start + 1));
+
+        era_start = era_start.saturating_add(HALVING_INTERVAL);
+        subsidy >>= 1;
+    }
+
+    supply.min(MAX_MONEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsidy_halves_until_zero() {
+        let mut subsidy = INITIAL_SUBSIDY;
+        let mut era_start = 0;
+        while subsidy > 0 {
+            assert_eq!(block_subsidy(era_start), subsidy);
+            assert_eq!(block_subsidy(era_start + HALVING_INTERVAL - 1), subsidy);
+
+            subsidy >>= 1;
+            era_start += HALVING_INTERVAL;
+            assert_eq!(block_subsidy(era_start), subsidy);
+        }
+
+        assert_eq!(block_subsidy(era_start + HALVING_INTERVAL), 0);
+        assert_eq!(block_subsidy(u64::MAX), 0);
+    }
+
+    #[test]
+    fn supply_stays_within_max_money() {
+        let mut supply = 0u64;
+        for height in 0..HALVING_INTERVAL * 64 {
+            supply += block_subsidy(height);
+            assert_eq!(supply_at(height), supply);
+        }
+
+        assert!(supply <= MAX_MONEY);
+        assert_eq!(supply_at(u64::MAX), supply);
+    }
+}
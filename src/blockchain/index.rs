@@ -0,0 +1,32 @@
+use num_bigint::{BigInt, Sign};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::Result;
+
+//? Kept for every validated header, whether or not its body has been downloaded yet
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct BlockIndex {
+    pub(crate) height: u64,
+    work: Vec<u8>,
+}
+
+impl<'a> BlockIndex {
+    pub(crate) fn new(height: u64, work: &BigInt) -> Self {
+        Self {
+            height,
+            work: work.to_bytes_be().1,
+        }
+    }
+
+    pub(crate) fn work(&self) -> BigInt {
+        BigInt::from_bytes_be(Sign::Plus, &self.work)
+    }
+
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    pub(crate) fn deserialize(bytes: &'a [u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
@@ -0,0 +1,792 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fs::{create_dir_all, rename, File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    block::Block, transaction::Transaction, undo::BlockUndo, utxo::UTXOView,
+    validation::check_inputs, BlockChain, ChainUpdate,
+};
+
+use crate::Result;
+
+static MEMPOOL_PATH: &str = "./tmp/mempool";
+//? Most unconfirmed transactions linked together, counting the new one
+static MAX_PACKAGE_COUNT: usize = 25;
+//? Most transactions a replacement may evict, counting the descendants of those it conflicts with
+static MAX_REPLACED_COUNT: usize = 100;
+static INCREMENTAL_RELAY_FEE: u64 = 1; //? Coins per 1000 bytes
+pub static DEFAULT_MAX_MEMPOOL_SIZE: usize = 5_000_000; //? Bytes of serialized transactions
+static MEMPOOL_EXPIRY: u64 = 3 * 24 * 60 * 60; //? Seconds
+
+#[derive(Error, Debug)]
+pub enum MempoolRejection {
+    #[error("transaction {0} is already in the memory pool")]
+    AlreadyKnown(String),
+    #[error("coinbase transaction {0} only belongs in a block")]
+    Coinbase(String),
+    #[error("transaction {0} spends an output already spent by {1}")]
+    Conflict(String, String),
+    #[error("transaction {0} would link more than {1} unconfirmed transactions")]
+    TooLongChain(String, usize),
+    #[error("transaction {0} pays too little to enter the full memory pool")]
+    PoolFull(String),
+    #[error("replacement {0} would evict more than {1} transactions")]
+    TooManyReplaced(String, usize),
+    #[error("replacement {0} spends an unconfirmed output the replaced transactions didn't")]
+    NewUnconfirmedInput(String),
+    #[error("replacement {0} doesn't pay a higher fee rate than {1}")]
+    LowFeeRate(String, String),
+    #[error("replacement {tx} pays {fee} but must pay at least {required}")]
+    InsufficientFee { tx: String, fee: u64, required: u64 },
+}
+
+//? Why a transaction left the pool
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    Confirmed,
+    Conflict,
+    Replaced,
+    Expired,
+    SizeLimit,
+    Reorg,
+}
+
+//? What happened to the pool since the changes were last taken, in order
+pub enum MempoolChange {
+    Added {
+        transaction: Transaction,
+        fee: u64,
+    },
+    Removed {
+        tx_id: Vec<u8>,
+        reason: RemovalReason,
+    },
+}
+
+struct MempoolEntry {
+    transaction: Transaction,
+    fee: u64,
+    size: usize,
+    time: u64,
+    sequence: u64,
+    //? In pool transactions it spends from and that spend from it
+    parents: HashSet<Vec<u8>>,
+    children: HashSet<Vec<u8>>,
+}
+
+//? What survives a restart, the arrival time keeps counting toward the expiry
+#[derive(Serialize, Deserialize)]
+struct SavedTransaction {
+    transaction: Transaction,
+    time: u64,
+}
+
+//? Unconfirmed transactions valid on top of the tip, no two of them spend the same output
+pub struct Mempool {
+    file: String,
+    entries: HashMap<Vec<u8>, MempoolEntry>,
+    spent: HashMap<(Vec<u8>, i64), Vec<u8>>,
+    size: usize,
+    max_size: usize,
+    next_sequence: u64,
+    changes: Vec<MempoolChange>,
+}
+
+impl Mempool {
+    //? Saved transactions are validated again on top of the current tip, the invalid and expired ones are dropped
+    pub fn load(node_id: &str, max_size: usize, chain: &BlockChain) -> Result<Self> {
+        let mut mempool = Self::new(
+            format!("{}/mempool_{}.data", MEMPOOL_PATH, node_id),
+            max_size,
+        );
+
+        if !Path::new(&mempool.file).exists() {
+            create_dir_all(MEMPOOL_PATH)?;
+            return Ok(mempool);
+        }
+
+        let mut file = File::open(&mempool.file)?;
+        let mut buffer = vec![];
+        file.read_to_end(&mut buffer)?;
+        //? A damaged file costs the saved transactions, not the node start
+        let saved: Vec<SavedTransaction> = match bincode::deserialize(&buffer) {
+            Ok(saved) => saved,
+            Err(err) => {
+                println!("Memory pool file is unreadable, starting empty: {:?}", err);
+                return Ok(mempool);
+            }
+        };
+
+        let horizon = now().saturating_sub(MEMPOOL_EXPIRY);
+        for saved in saved.into_iter().filter(|saved| saved.time >= horizon) {
+            mempool.readmit(saved.transaction, saved.time, chain);
+        }
+        mempool.changes.clear();
+
+        Ok(mempool)
+    }
+
+    pub(crate) fn new(file: String, max_size: usize) -> Self {
+        Self {
+            file,
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            size: 0,
+            max_size,
+            next_sequence: 0,
+            changes: vec![],
+        }
+    }
+
+    //? In arrival order so parents are accepted before their children on load
+    pub fn save(&self) -> Result<()> {
+        let saved: Vec<SavedTransaction> = self
+            .in_order(self.entries.keys().cloned())
+            .into_iter()
+            .map(|tx_id| SavedTransaction {
+                transaction: self.entries[&tx_id].transaction.clone(),
+                time: self.entries[&tx_id].time,
+            })
+            .collect();
+
+        //? Written aside then renamed over, a crash mid-write leaves the previous file whole
+        let temporary = format!("{}.tmp", self.file);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary)?;
+
+        let encoded = bincode::serialize(&saved)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        rename(&temporary, &self.file)?;
+        Ok(())
+    }
+
+    pub fn take_changes(&mut self) -> Vec<MempoolChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    //? Bytes of serialized transactions
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn contains(&self, tx_id: &[u8]) -> bool {
+        self.entries.contains_key(tx_id)
+    }
+
+    pub fn get(&self, tx_id: &[u8]) -> Option<&Transaction> {
+        self.entries.get(tx_id).map(|entry| &entry.transaction)
+    }
+
+    pub fn fee(&self, tx_id: &[u8]) -> Option<u64> {
+        self.entries.get(tx_id).map(|entry| entry.fee)
+    }
+
+    pub(crate) fn is_spent(&self, tx_id: &[u8], out: i64) -> bool {
+        self.spent.contains_key(&(tx_id.to_vec(), out))
+    }
+
+    //? Unspent outputs paying to the lock from pending transactions only the own keys signed,
+    //? as (tx id, out, value) in arrival order
+    pub(crate) fn change_outputs(
+        &self,
+        public_key_hash: &[u8],
+        own_keys: &[Vec<u8>],
+    ) -> Vec<(Vec<u8>, i64, u64)> {
+        let mut outputs = vec![];
+        for tx_id in self.in_order(self.entries.keys().cloned()) {
+            let tx = &self.entries[&tx_id].transaction;
+            let own = tx
+                .inputs
+                .iter()
+                .all(|tx_input| own_keys.contains(&tx_input.public_key));
+            if !own {
+                continue;
+            }
+
+            for (out, tx_output) in tx.outputs.iter().enumerate() {
+                if tx_output.public_key_hash == public_key_hash
+                    && !self.is_spent(&tx_id, out as i64)
+                {
+                    outputs.push((tx_id.clone(), out as i64, tx_output.value));
+                }
+            }
+        }
+        outputs
+    }
+
+    //? In arrival order, so parents always come before their children
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.in_order(self.entries.keys().cloned())
+            .into_iter()
+            .map(|tx_id| self.entries[&tx_id].transaction.clone())
+            .collect()
+    }
+
+    //? Validates the transaction on top of the UTXO set and its unconfirmed parents, returns its fee
+    //? A transaction spending the same outputs as replaceable ones evicts them when it pays enough more
+    pub fn accept(&mut self, tx: Transaction, chain: &BlockChain) -> Result<u64> {
+        let tx_id = hex::encode(&tx.id);
+
+        if tx.is_coinbase() {
+            return Err(MempoolRejection::Coinbase(tx_id).into());
+        }
+        if self.contains(&tx.id) {
+            return Err(MempoolRejection::AlreadyKnown(tx_id).into());
+        }
+
+        let conflicts: HashSet<Vec<u8>> = tx
+            .inputs
+            .iter()
+            .filter_map(|tx_input| self.spent.get(&(tx_input.id.clone(), tx_input.out)))
+            .cloned()
+            .collect();
+        let mut replaced = HashSet::new();
+        for conflict in conflicts.iter() {
+            if !self.entries[conflict].transaction.is_replaceable() {
+                return Err(MempoolRejection::Conflict(tx_id, hex::encode(conflict)).into());
+            }
+            replaced.extend(self.descendants(conflict));
+            replaced.insert(conflict.clone());
+        }
+        if replaced.len() > MAX_REPLACED_COUNT {
+            return Err(MempoolRejection::TooManyReplaced(tx_id, MAX_REPLACED_COUNT).into());
+        }
+
+        let parents: HashSet<Vec<u8>> = tx
+            .inputs
+            .iter()
+            .map(|tx_input| tx_input.id.clone())
+            .filter(|parent| self.contains(parent) && !replaced.contains(parent))
+            .collect();
+        if !conflicts.is_empty()
+            && parents.iter().any(|parent| {
+                !conflicts
+                    .iter()
+                    .any(|conflict| self.entries[conflict].parents.contains(parent))
+            })
+        {
+            return Err(MempoolRejection::NewUnconfirmedInput(tx_id).into());
+        }
+
+        let ancestors = self.ancestors(&parents);
+        if ancestors.len() + 1 > MAX_PACKAGE_COUNT
+            || ancestors
+                .iter()
+                .any(|ancestor| self.descendants(ancestor).len() + 2 > MAX_PACKAGE_COUNT)
+        {
+            return Err(MempoolRejection::TooLongChain(tx_id, MAX_PACKAGE_COUNT).into());
+        }
+
+        let height = chain.get_best_height()? + 1;
+        let mut view = UTXOView::new(&chain.database);
+        let mut undo = BlockUndo::default();
+        for ancestor in self.in_order(ancestors.into_iter()) {
+            view.connect_transaction(&self.entries[&ancestor].transaction, height, &mut undo)?;
+        }
+        let fee = check_inputs(&tx, &view, height)?;
+        let size = tx.size()?;
+
+        if !conflicts.is_empty() {
+            self.check_replacement(&tx_id, fee, size, &conflicts, &replaced)?;
+        }
+        let logged = self.changes.len();
+        let originals: Vec<(Transaction, u64)> = self
+            .in_order(replaced.iter().cloned())
+            .into_iter()
+            .map(|tx_id| {
+                let entry = &self.entries[&tx_id];
+                (entry.transaction.clone(), entry.time)
+            })
+            .collect();
+        for replaced in replaced.iter() {
+            self.remove(replaced, RemovalReason::Replaced);
+        }
+
+        self.insert(tx.clone(), fee, size, parents);
+        self.trim();
+        if !self.contains(&tx.id) {
+            //? A replacement the full pool evicts gives back what it replaced, only the other evictions stay logged
+            let mut restored = HashSet::from([tx.id.clone()]);
+            for (original, time) in originals {
+                let original_id = original.id.clone();
+                if self.readmit(original, time, chain) {
+                    restored.insert(original_id);
+                }
+            }
+            let later = self.changes.split_off(logged);
+            self.changes
+                .extend(later.into_iter().filter(|change| match change {
+                    MempoolChange::Added { transaction, .. } => !restored.contains(&transaction.id),
+                    MempoolChange::Removed { tx_id, .. } => !restored.contains(tx_id),
+                }));
+            return Err(MempoolRejection::PoolFull(tx_id).into());
+        }
+
+        Ok(fee)
+    }
+
+    //? Accepts again a transaction that left the pool, it keeps its arrival time
+    fn readmit(&mut self, tx: Transaction, time: u64, chain: &BlockChain) -> bool {
+        let tx_id = tx.id.clone();
+        if self.accept(tx, chain).is_err() {
+            return false;
+        }
+        if let Some(entry) = self.entries.get_mut(&tx_id) {
+            entry.time = time;
+        }
+        true
+    }
+
+    //? Parents neither in the pool nor with unspent outputs, the transaction may be an orphan waiting for them
+    pub fn missing_parents(&self, tx: &Transaction, chain: &BlockChain) -> Result<Vec<Vec<u8>>> {
+        let view = UTXOView::new(&chain.database);
+        let mut missing = vec![];
+
+        for tx_input in tx.inputs.iter() {
+            if self.contains(&tx_input.id) || missing.contains(&tx_input.id) {
+                continue;
+            }
+            if view.get_outputs(&tx_input.id)?.is_none() {
+                missing.push(tx_input.id.clone());
+            }
+        }
+
+        Ok(missing)
+    }
+
+    //? Confirmed and conflicting transactions leave the pool, those of disconnected blocks come back when still valid
+    pub fn update(&mut self, update: &ChainUpdate, chain: &BlockChain) {
+        for block in update.connected.iter() {
+            self.remove_for_block(block);
+        }
+
+        if update.disconnected.is_empty() {
+            return;
+        }
+
+        //? Pool transactions may spend outputs the reorg took away, so everything is validated again
+        //? Only the difference is logged, not every transaction leaving and coming back
+        let before: HashSet<Vec<u8>> = self.entries.keys().cloned().collect();
+        let logged = self.changes.len();
+        //? Transactions of disconnected blocks arrive now, those already pooled keep their arrival time
+        let arrival = now();
+        let mut transactions: Vec<(Transaction, u64)> = update
+            .disconnected
+            .iter()
+            .rev()
+            .flat_map(|block| block.transactions.iter().skip(1).cloned())
+            .map(|tx| (tx, arrival))
+            .collect();
+        transactions.extend(
+            self.in_order(self.entries.keys().cloned())
+                .into_iter()
+                .map(|tx_id| {
+                    let entry = &self.entries[&tx_id];
+                    (entry.transaction.clone(), entry.time)
+                }),
+        );
+
+        self.entries.clear();
+        self.spent.clear();
+        self.size = 0;
+        for (tx, time) in transactions {
+            self.readmit(tx, time, chain);
+        }
+
+        self.changes.truncate(logged);
+        for tx_id in before.iter() {
+            if !self.contains(tx_id) {
+                self.changes.push(MempoolChange::Removed {
+                    tx_id: tx_id.clone(),
+                    reason: RemovalReason::Reorg,
+                });
+            }
+        }
+        let added = self.in_order(
+            self.entries
+                .keys()
+                .filter(|tx_id| !before.contains(*tx_id))
+                .cloned(),
+        );
+        for tx_id in added {
+            let entry = &self.entries[&tx_id];
+            self.changes.push(MempoolChange::Added {
+                transaction: entry.transaction.clone(),
+                fee: entry.fee,
+            });
+        }
+    }
+
+    //? Returns how many transactions were dropped, children of an expired transaction go with it
+    pub fn expire(&mut self) -> usize {
+        let horizon = now().saturating_sub(MEMPOOL_EXPIRY);
+        let expired: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.time < horizon)
+            .map(|(tx_id, _)| tx_id.clone())
+            .collect();
+
+        expired
+            .iter()
+            .map(|tx_id| self.remove_with_descendants(tx_id, RemovalReason::Expired))
+            .sum()
+    }
+
+    //? The replacement beats the fee rate of every transaction it conflicts with and pays for all it evicts plus its own relay
+    fn check_replacement(
+        &self,
+        tx_id: &str,
+        fee: u64,
+        size: usize,
+        conflicts: &HashSet<Vec<u8>>,
+        replaced: &HashSet<Vec<u8>>,
+    ) -> Result<()> {
+        for conflict in conflicts.iter() {
+            let entry = &self.entries[conflict];
+            if compare_rates((fee, size), (entry.fee, entry.size)) != Ordering::Greater {
+                return Err(
+                    MempoolRejection::LowFeeRate(tx_id.to_owned(), hex::encode(conflict)).into(),
+                );
+            }
+        }
+
+        let replaced_fee = replaced.iter().fold(0u64, |total, tx_id| {
+            total.saturating_add(self.entries[tx_id].fee)
+        });
+        let required =
+            replaced_fee.saturating_add((size as u64 * INCREMENTAL_RELAY_FEE).div_ceil(1000));
+        if fee < required {
+            return Err(MempoolRejection::InsufficientFee {
+                tx: tx_id.to_owned(),
+                fee,
+                required,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, tx: Transaction, fee: u64, size: usize, parents: HashSet<Vec<u8>>) {
+        for parent in parents.iter() {
+            if let Some(entry) = self.entries.get_mut(parent) {
+                entry.children.insert(tx.id.clone());
+            }
+        }
+        for tx_input in tx.inputs.iter() {
+            self.spent
+                .insert((tx_input.id.clone(), tx_input.out), tx.id.clone());
+        }
+
+        self.size += size;
+        self.changes.push(MempoolChange::Added {
+            transaction: tx.clone(),
+            fee,
+        });
+        self.entries.insert(
+            tx.id.clone(),
+            MempoolEntry {
+                transaction: tx,
+                fee,
+                size,
+                time: now(),
+                sequence: self.next_sequence,
+                parents,
+                children: HashSet::new(),
+            },
+        );
+        self.next_sequence += 1;
+    }
+
+    fn remove(&mut self, tx_id: &[u8], reason: RemovalReason) {
+        let entry = match self.entries.remove(tx_id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.changes.push(MempoolChange::Removed {
+            tx_id: tx_id.to_vec(),
+            reason,
+        });
+
+        for tx_input in entry.transaction.inputs.iter() {
+            self.spent.remove(&(tx_input.id.clone(), tx_input.out));
+        }
+        for parent in entry.parents.iter() {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(tx_id);
+            }
+        }
+        for child in entry.children.iter() {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(tx_id);
+            }
+        }
+        self.size -= entry.size;
+    }
+
+    fn remove_with_descendants(&mut self, tx_id: &[u8], reason: RemovalReason) -> usize {
+        if !self.contains(tx_id) {
+            return 0;
+        }
+
+        let mut removed = self.descendants(tx_id);
+        removed.insert(tx_id.to_vec());
+        for tx_id in removed.iter() {
+            self.remove(tx_id, reason);
+        }
+
+        removed.len()
+    }
+
+    //? Children of a confirmed transaction stay, they now spend from the UTXO set
+    fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.transactions.iter() {
+            if self.contains(&tx.id) {
+                self.remove(&tx.id, RemovalReason::Confirmed);
+                continue;
+            }
+
+            if tx.is_coinbase() {
+                continue;
+            }
+            for tx_input in tx.inputs.iter() {
+                if let Some(spender) = self.spent.get(&(tx_input.id.clone(), tx_input.out)) {
+                    let spender = spender.clone();
+                    self.remove_with_descendants(&spender, RemovalReason::Conflict);
+                }
+            }
+        }
+    }
+
+    //? Evicts the transactions paying the least per byte, a parent is scored with its children so they can pay for it
+    fn trim(&mut self) {
+        while self.size > self.max_size {
+            let lowest = self
+                .entries
+                .keys()
+                .map(|tx_id| (tx_id, self.score(tx_id)))
+                .min_by(|(_, a), (_, b)| compare_rates(*a, *b))
+                .map(|(tx_id, _)| tx_id.clone());
+
+            match lowest {
+                Some(tx_id) => {
+                    self.remove_with_descendants(&tx_id, RemovalReason::SizeLimit);
+                }
+                None => break,
+            }
+        }
+    }
+
+    //? The best of its own fee rate and the one of the package with its descendants, as (fee, size)
+    fn score(&self, tx_id: &[u8]) -> (u64, usize) {
+        let entry = &self.entries[tx_id];
+        let mut package = (entry.fee, entry.size);
+        for descendant in self.descendants(tx_id) {
+            let descendant = &self.entries[&descendant];
+            package.0 = package.0.saturating_add(descendant.fee);
+            package.1 += descendant.size;
+        }
+
+        match compare_rates(package, (entry.fee, entry.size)) {
+            Ordering::Greater => package,
+            _ => (entry.fee, entry.size),
+        }
+    }
+
+    fn ancestors(&self, parents: &HashSet<Vec<u8>>) -> HashSet<Vec<u8>> {
+        let mut ancestors = HashSet::new();
+        let mut stack: Vec<Vec<u8>> = parents.iter().cloned().collect();
+
+        while let Some(tx_id) = stack.pop() {
+            if !ancestors.insert(tx_id.clone()) {
+                continue;
+            }
+            if let Some(entry) = self.entries.get(&tx_id) {
+                stack.extend(entry.parents.iter().cloned());
+            }
+        }
+
+        ancestors
+    }
+
+    fn descendants(&self, tx_id: &[u8]) -> HashSet<Vec<u8>> {
+        let mut descendants = HashSet::new();
+        let mut stack: Vec<Vec<u8>> = match self.entries.get(tx_id) {
+            Some(entry) => entry.children.iter().cloned().collect(),
+            None => vec![],
+        };
+
+        while let Some(tx_id) = stack.pop() {
+            if !descendants.insert(tx_id.clone()) {
+                continue;
+            }
+            if let Some(entry) = self.entries.get(&tx_id) {
+                stack.extend(entry.children.iter().cloned());
+            }
+        }
+
+        descendants
+    }
+
+    fn in_order(&self, tx_ids: impl Iterator<Item = Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut tx_ids: Vec<Vec<u8>> = tx_ids.collect();
+        tx_ids.sort_by_key(|tx_id| self.entries[tx_id].sequence);
+        tx_ids
+    }
+}
+
+fn compare_rates((fee_a, size_a): (u64, usize), (fee_b, size_b): (u64, usize)) -> Ordering {
+    (fee_a as u128 * size_b as u128).cmp(&(fee_b as u128 * size_a as u128))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time is earlier than Unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{blockchain::UTXOSet, wallet::Wallets, Error};
+
+    struct Fixture {
+        wallets: Wallets,
+        from: String,
+        to: String,
+        chain: BlockChain,
+    }
+
+    //? The genesis coinbase pays the sender and is spendable right away
+    fn fixture() -> Fixture {
+        let mut wallets = Wallets::in_memory();
+        let from = wallets.add_wallet(None).unwrap();
+        let to = wallets.add_wallet(None).unwrap();
+        let chain = BlockChain::temporary(&from).unwrap();
+        UTXOSet::new(chain.clone()).reindex().unwrap();
+
+        Fixture {
+            wallets,
+            from,
+            to,
+            chain,
+        }
+    }
+
+    impl Fixture {
+        //? Built on the confirmed outputs only, so every send spends the genesis coinbase
+        fn send(&mut self, amount: u64, fee: u64) -> Transaction {
+            let utxo_set = UTXOSet::new(self.chain.clone());
+            Transaction::new(
+                &mut self.wallets,
+                &self.from,
+                &self.to,
+                amount,
+                fee,
+                true,
+                &utxo_set,
+                None,
+            )
+            .unwrap()
+        }
+    }
+
+    fn rejection(result: Result<u64>) -> MempoolRejection {
+        match result {
+            Err(Error::TransactionRejected { source }) => source,
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("invalid transaction was accepted"),
+        }
+    }
+
+    #[test]
+    fn replacement_paying_more_evicts_the_original() {
+        let mut fixture = fixture();
+        let mut mempool = Mempool::new(String::new(), DEFAULT_MAX_MEMPOOL_SIZE);
+        let original = fixture.send(5, 1);
+        let replacement = fixture.send(5, 3);
+
+        mempool.accept(original.clone(), &fixture.chain).unwrap();
+        assert_eq!(
+            mempool.accept(replacement.clone(), &fixture.chain).unwrap(),
+            3
+        );
+
+        assert!(!mempool.contains(&original.id));
+        assert!(mempool.contains(&replacement.id));
+        assert_eq!(mempool.size(), replacement.size().unwrap());
+    }
+
+    #[test]
+    fn replacement_paying_too_little_is_rejected() {
+        let mut fixture = fixture();
+        let mut mempool = Mempool::new(String::new(), DEFAULT_MAX_MEMPOOL_SIZE);
+        let original = fixture.send(5, 1);
+        //? No change output, so the same fee is a higher rate but doesn't pay for the relay
+        let replacement = fixture.send(19, 1);
+        assert!(replacement.size().unwrap() < original.size().unwrap());
+
+        mempool.accept(original.clone(), &fixture.chain).unwrap();
+        match rejection(mempool.accept(replacement.clone(), &fixture.chain)) {
+            MempoolRejection::InsufficientFee { fee, required, .. } => {
+                assert_eq!(fee, 1);
+                assert!(required > 1);
+            }
+            other => panic!("unexpected rejection {:?}", other),
+        }
+
+        assert!(mempool.contains(&original.id));
+        assert!(!mempool.contains(&replacement.id));
+    }
+
+    #[test]
+    fn replacement_evicted_by_the_full_pool_restores_the_original() {
+        let mut fixture = fixture();
+        let original = fixture.send(19, 1);
+        let replacement = fixture.send(5, 3);
+        let mut mempool = Mempool::new(String::new(), original.size().unwrap());
+
+        mempool.accept(original.clone(), &fixture.chain).unwrap();
+        mempool.entries.get_mut(&original.id).unwrap().time = 1;
+        mempool.take_changes();
+
+        assert!(matches!(
+            rejection(mempool.accept(replacement.clone(), &fixture.chain)),
+            MempoolRejection::PoolFull(_)
+        ));
+
+        assert!(mempool.contains(&original.id));
+        assert!(!mempool.contains(&replacement.id));
+        assert_eq!(mempool.entries[&original.id].time, 1);
+        assert_eq!(mempool.size(), original.size().unwrap());
+        assert!(mempool.take_changes().is_empty());
+    }
+}
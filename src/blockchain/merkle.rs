@@ -12,7 +12,7 @@ impl MerkleTree {
             return Err(Error::CustomError("Datas is empty!".to_owned()));
         }
 
-        if datas.len() % 2 != 0 {
+        if !datas.len().is_multiple_of(2) {
             datas.push(datas[0].clone());
         }
 
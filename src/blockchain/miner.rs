@@ -0,0 +1,82 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::SystemTime,
+};
+
+use super::{block::Block, proof::ProofOfWork};
+
+use crate::{Error, Result};
+
+//? Splits the nonce space of a block header between worker threads
+#[derive(Clone)]
+pub struct Miner {
+    threads: u64,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        let threads = thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1);
+
+        Self::new(threads)
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1) as u64,
+        }
+    }
+
+    //? Returns false when cancelled before a valid nonce was found
+    pub fn mine(&self, block: &mut Block, cancel: &AtomicBool) -> Result<bool> {
+        loop {
+            let found = {
+                let proof = ProofOfWork::new_proof(&block.header);
+                let stop = AtomicBool::new(false);
+                let chunk = u64::MAX / self.threads;
+
+                thread::scope(|scope| {
+                    let workers: Vec<_> = (0..self.threads)
+                        .map(|worker| {
+                            let (proof, stop) = (&proof, &stop);
+                            let nonces = worker * chunk..(worker + 1) * chunk;
+                            scope.spawn(move || proof.search(nonces, stop, cancel))
+                        })
+                        .collect();
+
+                    let mut found = None;
+                    for worker in workers {
+                        match worker.join() {
+                            Ok(result) => found = found.or(result),
+                            Err(_) => {
+                                return Err(Error::CustomError("Miner thread panicked!".to_owned()))
+                            }
+                        }
+                    }
+                    Ok(found)
+                })?
+            };
+
+            if let Some((nonce, block_hash)) = found {
+                block.header.nonce = nonce;
+                block.hash = block_hash.to_vec();
+                return Ok(true);
+            }
+
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+
+            //? Every nonce failed, a new timestamp gives a whole new nonce space
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("System time is earlier than Unix epoch")
+                .as_secs();
+            block.header.timestamp = now.max(block.header.timestamp + 1);
+        }
+    }
+}
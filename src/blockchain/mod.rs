@@ -1,12 +1,28 @@
+mod address_index;
 mod block;
 mod chain;
+mod consensus;
+mod index;
+mod mempool;
 mod merkle;
+mod miner;
 mod proof;
 mod transaction;
 mod tx;
+mod txindex;
+mod undo;
 mod utxo;
+mod validation;
 
-pub use block::Block;
-pub use chain::BlockChain;
+pub use address_index::AddressTransaction;
+pub use block::{Block, BlockHeader};
+pub use chain::{BlockChain, ChainUpdate};
+pub use consensus::{block_subsidy, supply_at, COINBASE_MATURITY, MAX_MONEY};
+pub use mempool::{
+    Mempool, MempoolChange, MempoolRejection, RemovalReason, DEFAULT_MAX_MEMPOOL_SIZE,
+};
+pub use miner::Miner;
 pub use transaction::Transaction;
+pub use txindex::TransactionInfo;
 pub use utxo::UTXOSet;
+pub use validation::BlockRejection;
@@ -1,7 +1,12 @@
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
 use num_bigint::{BigInt, Sign};
 use sha2::{Digest, Sha256};
 
-use super::block::Block;
+use super::block::BlockHeader;
 use crate::Result;
 
 //? Proof Of Work (PoW):
@@ -13,59 +18,209 @@ use crate::Result;
 
 //Step 4: Check the hash to see if it meets a set of requirements
 
-//? Requirements: The first few bytes must contain 0s (more 0 mean harder)
+//? Requirements: The hash must be lower than the target encoded in the block bits
+
+//? Retargeting: Every RETARGET_INTERVAL blocks the target is scaled by the ratio
+//? between the time the last interval actually took and the time it should have taken
 
-static DIFFICULTY: u64 = 12;
+static INITIAL_DIFFICULTY: u64 = 12;
+static MINIMUM_DIFFICULTY: u64 = 8;
+static TARGET_BLOCK_TIME: u64 = 10; //? Seconds
+pub(crate) static RETARGET_INTERVAL: u64 = 10; //? Blocks
+static CANCEL_CHECK_INTERVAL: u64 = 1 << 12; //? Nonces
 
 pub(crate) struct ProofOfWork<'a> {
-    block: &'a Block,
+    header: &'a BlockHeader,
     target: BigInt,
 }
 
 impl<'a> ProofOfWork<'a> {
-    pub(crate) fn new_proof(block: &'a Block) -> Self {
-        let target = BigInt::from(1u64);
-        let target = target << (256 - DIFFICULTY);
+    pub(crate) fn new_proof(header: &'a BlockHeader) -> Self {
+        let target = target_from_bits(header.bits);
 
-        Self { block, target }
+        Self { header, target }
     }
 
-    pub(crate) fn run(&self) -> Result<(u64, [u8; 32])> {
+    //? Every field but the nonce is hashed once, each attempt only feeds the nonce to a copy
+    pub(crate) fn search(
+        &self,
+        nonces: Range<u64>,
+        stop: &AtomicBool,
+        cancel: &AtomicBool,
+    ) -> Option<(u64, [u8; 32])> {
+        let mut prefix = Sha256::new();
+        prefix.update(self.init_prefix());
+
+        let (_, target_bytes) = self.target.to_bytes_be();
+        let mut target = [0u8; 32];
+        if target_bytes.len() > 32 {
+            target = [0xff; 32];
+        } else {
+            target[32 - target_bytes.len()..].copy_from_slice(&target_bytes);
+        }
+
         let mut block_hash = [0u8; 32];
-        let mut nonce = 0u64;
-
-        loop {
-            let data = self.init_data(nonce)?;
-            block_hash.copy_from_slice(&Sha256::digest(&data));
-            if BigInt::from_bytes_be(Sign::Plus, &block_hash) < self.target {
-                println!(
-                    "PoW: Nonce {nonce} - Hash {:?}\r",
-                    hex::encode(block_hash).to_string()
-                );
-                break;
-            } else {
-                nonce += 1;
+        for nonce in nonces {
+            if nonce % CANCEL_CHECK_INTERVAL == 0
+                && (stop.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed))
+            {
+                return None;
             }
-        }
 
-        println!();
+            let mut hasher = prefix.clone();
+            hasher.update(nonce.to_be_bytes());
+            block_hash.copy_from_slice(&hasher.finalize());
 
-        Ok((nonce, block_hash))
+            if block_hash < target {
+                stop.store(true, Ordering::Relaxed);
+                return Some((nonce, block_hash));
+            }
+        }
+
+        None
     }
 
     pub(crate) fn validate(&self) -> Result<bool> {
-        let data = self.init_data(self.block.nonce)?;
+        if self.target > pow_limit() {
+            return Ok(false);
+        }
+
+        Ok(BigInt::from_bytes_be(Sign::Plus, &self.hash()?) < self.target)
+    }
+
+    pub(crate) fn hash(&self) -> Result<Vec<u8>> {
+        let data = self.init_data(self.header.nonce)?;
 
-        Ok(BigInt::from_bytes_be(Sign::Plus, &Sha256::digest(data)) < self.target)
+        Ok(Sha256::digest(data).to_vec())
     }
 
     fn init_data(&self, nonce: u64) -> Result<Vec<u8>> {
-        let mut data = vec![];
-        data.extend_from_slice(&self.block.prevhash);
-        data.extend_from_slice(&self.block.hash_transactions()?);
+        let mut data = self.init_prefix();
         data.extend_from_slice(&nonce.to_be_bytes());
-        data.extend_from_slice(&DIFFICULTY.to_be_bytes());
 
         Ok(data)
     }
+
+    fn init_prefix(&self) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&self.header.version.to_be_bytes());
+        data.extend_from_slice(&self.header.prevhash);
+        data.extend_from_slice(&self.header.merkle_root);
+        data.extend_from_slice(&self.header.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.header.bits.to_be_bytes());
+        data
+    }
+}
+
+pub(crate) fn initial_bits() -> u32 {
+    bits_from_target(&(BigInt::from(1u64) << (256 - INITIAL_DIFFICULTY)))
+}
+
+pub(crate) fn retarget(bits: u32, actual_timespan: u64) -> u32 {
+    //? The timespan is measured between the first and the last block of the interval
+    let expected_timespan = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1);
+    let actual_timespan = actual_timespan.clamp(expected_timespan / 4, expected_timespan * 4);
+
+    let target = target_from_bits(bits) * actual_timespan / expected_timespan;
+
+    bits_from_target(&target.min(pow_limit()))
+}
+
+//? Expected number of hashes to find a block: 2^256 / (target + 1)
+pub(crate) fn block_work(bits: u32) -> BigInt {
+    (BigInt::from(1u64) << 256) / (target_from_bits(bits) + 1)
+}
+
+fn pow_limit() -> BigInt {
+    BigInt::from(1u64) << (256 - MINIMUM_DIFFICULTY)
+}
+
+//? Compact target: 1 byte exponent (size in bytes) followed by a 3 bytes mantissa
+fn target_from_bits(bits: u32) -> BigInt {
+    let exponent = bits >> 24;
+    let mantissa = BigInt::from(bits & 0x007f_ffff);
+
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent))
+    } else {
+        mantissa << (8 * (exponent - 3))
+    }
+}
+
+fn bits_from_target(target: &BigInt) -> u32 {
+    let (_, bytes) = target.to_bytes_be();
+    let bytes: Vec<u8> = bytes.into_iter().skip_while(|byte| *byte == 0).collect();
+
+    let mut size = bytes.len() as u32;
+    let mut mantissa = bytes
+        .iter()
+        .take(3)
+        .fold(0u32, |mantissa, byte| (mantissa << 8) | *byte as u32);
+    if size < 3 {
+        mantissa <<= 8 * (3 - size);
+    }
+
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    mantissa | (size << 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_bits_round_trip() {
+        for bits in [initial_bits(), 0x1d00_ffff, 0x0400_ffff, 0x2100_8000] {
+            assert_eq!(bits_from_target(&target_from_bits(bits)), bits);
+        }
+
+        //? A mantissa with its top bit set would read as negative, it moves a byte up instead
+        assert_eq!(bits_from_target(&BigInt::from(0x80)), 0x0200_8000);
+        assert_eq!(bits_from_target(&BigInt::from(0xff_ffff)), 0x0400_ffff);
+        assert_eq!(target_from_bits(0x0200_8000), BigInt::from(0x80));
+        assert_eq!(target_from_bits(0x0492_3456), BigInt::from(0x1234_5600u64));
+    }
+
+    //? Targets of 3 bytes or less shift the mantissa right instead of left
+    #[test]
+    fn compact_bits_small_exponents() {
+        assert_eq!(target_from_bits(0x0112_0000), BigInt::from(0x12));
+        assert_eq!(target_from_bits(0x0212_3400), BigInt::from(0x1234));
+        assert_eq!(target_from_bits(0x0312_3456), BigInt::from(0x12_3456));
+        assert_eq!(target_from_bits(0x0000_0000), BigInt::from(0));
+
+        for bits in [0x0112_0000, 0x0212_3400, 0x0312_3456] {
+            assert_eq!(bits_from_target(&target_from_bits(bits)), bits);
+        }
+    }
+
+    #[test]
+    fn retarget_clamps_the_timespan() {
+        let bits = initial_bits();
+        let target = target_from_bits(bits);
+        let expected_timespan = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1);
+
+        assert_eq!(retarget(bits, expected_timespan), bits);
+        let shortest = expected_timespan / 4;
+        assert_eq!(
+            retarget(bits, 0),
+            bits_from_target(&(&target * shortest / expected_timespan))
+        );
+        assert_eq!(retarget(bits, 0), retarget(bits, shortest));
+        assert_eq!(target_from_bits(retarget(bits, u64::MAX)), &target * 4);
+        assert_eq!(
+            retarget(bits, u64::MAX),
+            retarget(bits, expected_timespan * 4)
+        );
+    }
+
+    #[test]
+    fn retarget_stops_at_the_pow_limit() {
+        let bits = bits_from_target(&pow_limit());
+        assert_eq!(retarget(bits, u64::MAX), bits);
+    }
 }
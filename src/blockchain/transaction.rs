@@ -9,12 +9,13 @@ use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
-    wallet::{public_key_hash_from_address, Wallets},
+    wallet::{address_from_public_key_hash, Wallets},
     Error, Result,
 };
 
 use super::{
-    tx::{TxInput, TxOutput},
+    mempool::Mempool,
+    tx::{TxInput, TxOutput, TxOutputs, SEQUENCE_REPLACEABLE},
     utxo::UTXOSet,
 };
 
@@ -30,32 +31,71 @@ impl Transaction {
         Ok(bincode::serialize(&self)?)
     }
 
-    pub fn new(from: &str, to: &str, amount: u64, utxo_set: &UTXOSet) -> Result<Transaction> {
+    //? The fee is whatever the inputs carry beyond the outputs, it goes to the miner
+    //? Coins come from the sender first, then from the other addresses of its HD account,
+    //? what is left after the amount and the fee goes to a change address of the account as the last output
+    //? A memory pool lets it skip outputs already spent there and spend unconfirmed change
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        wallets: &mut Wallets,
+        from: &str,
+        to: &str,
+        amount: u64,
+        fee: u64,
+        replaceable: bool,
+        utxo_set: &UTXOSet,
+        mempool: Option<&Mempool>,
+    ) -> Result<Transaction> {
         let mut inputs = vec![];
         let mut outputs = vec![];
 
-        let (accumulated, valid_ouputs) = utxo_set.find_address_unspent_outputs(from, amount)?;
+        let total = match amount.checked_add(fee) {
+            Some(total) => total,
+            None => return Err(Error::CustomError("Amount is too large!".to_owned())),
+        };
 
-        if accumulated < amount {
-            return Err(Error::CustomError("Address funds isn't enough!".to_owned()));
+        let addresses = wallets.account_addresses(from);
+        let mut own_keys = vec![];
+        for address in addresses.iter() {
+            own_keys.push(wallets.public_key(address)?);
         }
 
-        for (tx_id, outs) in valid_ouputs {
-            let tx_id = hex::decode(&tx_id)?;
+        let mut accumulated = 0u64;
+        for (address, public_key) in addresses.iter().zip(own_keys.iter()) {
+            if accumulated >= total {
+                break;
+            }
 
-            for out in outs {
-                inputs.push(TxInput::new(
-                    tx_id.clone(),
-                    out,
-                    vec![],
-                    public_key_hash_from_address(from)?,
-                )?);
+            let (found, valid_ouputs) = utxo_set.find_address_unspent_outputs(
+                address,
+                total - accumulated,
+                mempool,
+                &own_keys,
+            )?;
+            accumulated = accumulated.saturating_add(found);
+
+            for (tx_id, outs) in valid_ouputs {
+                let tx_id = hex::decode(&tx_id)?;
+
+                for out in outs {
+                    let mut tx_input =
+                        TxInput::new(tx_id.clone(), out, vec![], public_key.clone())?;
+                    if replaceable {
+                        tx_input.sequence = SEQUENCE_REPLACEABLE;
+                    }
+                    inputs.push(tx_input);
+                }
             }
         }
 
+        if accumulated < total {
+            return Err(Error::CustomError("Address funds isn't enough!".to_owned()));
+        }
+
         outputs.push(TxOutput::new(amount, to)?);
-        if accumulated > amount {
-            outputs.push(TxOutput::new(accumulated - amount, from)?)
+        if accumulated > total {
+            let change = wallets.change_address(from)?;
+            outputs.push(TxOutput::new(accumulated - total, &change)?)
         }
 
         let mut tx = Transaction {
@@ -65,17 +105,93 @@ impl Transaction {
         };
         tx.hash()?;
 
-        utxo_set.chain.sign_transaction(&mut tx, from)?;
+        utxo_set.chain.sign_transaction(&mut tx, wallets, mempool)?;
 
         Ok(tx)
     }
 
-    pub(crate) fn coinbase_tx(to: &str) -> Result<Self> {
+    //? Rebuilds the transaction from the same inputs, the change output pays for the higher fee
+    //? A memory pool lets the fee count inputs spending unconfirmed parents
+    pub fn bump_fee(
+        &self,
+        wallets: &Wallets,
+        fee: u64,
+        utxo_set: &UTXOSet,
+        mempool: Option<&Mempool>,
+    ) -> Result<Transaction> {
+        if !self.is_replaceable() {
+            return Err(Error::CustomError(
+                "Transaction doesn't signal replaceability!".to_owned(),
+            ));
+        }
+        let current_fee = utxo_set.chain.transaction_fee(self, mempool)?;
+        if fee <= current_fee {
+            return Err(Error::CustomError(
+                "New fee must be higher than the current fee!".to_owned(),
+            ));
+        }
+
+        let change_output = match self.change_output(wallets)? {
+            Some(change_output) => change_output,
+            None => {
+                return Err(Error::CustomError(
+                    "Change output doesn't exists!".to_owned(),
+                ))
+            }
+        };
+        let mut tx = self.clone();
+        let delta = fee - current_fee;
+        let change = &mut tx.outputs[change_output];
+        if change.value > delta {
+            change.value -= delta;
+        } else if change.value == delta {
+            tx.outputs.remove(change_output);
+        } else {
+            return Err(Error::CustomError(
+                "Change output can't cover the new fee!".to_owned(),
+            ));
+        }
+
+        for tx_input in tx.inputs.iter_mut() {
+            tx_input.signature = vec![];
+        }
+        tx.id = vec![];
+        tx.hash()?;
+
+        utxo_set.chain.sign_transaction(&mut tx, wallets, mempool)?;
+
+        Ok(tx)
+    }
+
+    //? The output paying back to the signing key, on the change chain of its HD account or to the key itself
+    pub(crate) fn change_output(&self, wallets: &Wallets) -> Result<Option<usize>> {
+        let from = match self.inputs.first() {
+            Some(tx_input) => wallets.find_address(&tx_input.public_key)?,
+            None => None,
+        };
+        let from = match from {
+            Some(from) => from,
+            None => return Ok(None),
+        };
+        let hd = wallets.key_path(&from).is_some();
+
+        Ok(self.outputs.iter().rposition(|tx_output| {
+            let address = address_from_public_key_hash(&tx_output.public_key_hash);
+            if hd {
+                wallets.is_change(&address) && wallets.account_addresses(&from).contains(&address)
+            } else {
+                address == from
+            }
+        }))
+    }
+
+    //? The value is the block subsidy plus the fees of the block transactions
+    pub(crate) fn coinbase_tx(to: &str, value: u64) -> Result<Self> {
         let mut random = [0u8; 24];
         OsRng.fill_bytes(&mut random);
 
         let tx_input = TxInput::new(vec![], -1, vec![], random.to_vec())?;
-        let tx_ouput = TxOutput::new(20, to)?; //? Reward 20
+        let tx_ouput = TxOutput::new(value, to)?;
 
         let mut tx = Transaction {
             id: vec![],
@@ -88,6 +204,16 @@ impl Transaction {
         Ok(tx)
     }
 
+    pub(crate) fn size(&self) -> Result<usize> {
+        Ok(self.serialize()?.len())
+    }
+
+    pub(crate) fn is_replaceable(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|tx_input| tx_input.sequence <= SEQUENCE_REPLACEABLE)
+    }
+
     pub(crate) fn is_coinbase(&self) -> bool {
         self.inputs.len() == 1 && self.inputs[0].id.is_empty() && self.inputs[0].out == -1
     }
@@ -101,72 +227,86 @@ impl Transaction {
         Ok(())
     }
 
+    //? The id is hashed before the inputs are signed, so signatures aren't part of it
+    pub(crate) fn compute_id(&self) -> Result<Vec<u8>> {
+        let mut tx = self.clone();
+        tx.id = vec![];
+        for tx_input in tx.inputs.iter_mut() {
+            tx_input.signature = vec![];
+        }
+        tx.hash()?;
+        Ok(tx.id)
+    }
+
+    pub(crate) fn tx_outputs(&self) -> TxOutputs {
+        let mut tx_outputs = TxOutputs::new();
+        for (out_index, tx_output) in self.outputs.iter().enumerate() {
+            tx_outputs
+                .outputs
+                .insert(out_index as i64, tx_output.clone());
+        }
+        tx_outputs
+    }
+
     pub(crate) fn sign(
         &mut self,
-        address: &str,
-        prev_txs: &HashMap<String, Transaction>,
+        wallets: &Wallets,
+        prev_outputs: &HashMap<String, TxOutputs>,
     ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        for tx_input in &self.inputs {
-            let prev_tx = prev_txs.get(&hex::encode(&tx_input.id));
-            if prev_tx.is_none() || prev_tx.unwrap().id.is_empty() {
-                return Err(Error::CustomError(
-                    "Previous transaction does not exist!".to_owned(),
-                ));
-            }
-        }
-
+        //? Each input signs the copy with the lock of the output it spends in place of its key,
+        //? using the wallet address its key belongs to
         let mut tx_copy = self.trimmed_copy()?;
         let tx_copy_inputs = tx_copy.inputs.clone();
         for (in_index, tx_input) in tx_copy_inputs.iter().enumerate() {
-            let prev_tx = prev_txs.get(&hex::encode(&tx_input.id)).unwrap();
+            let address = match wallets.find_address(&self.inputs[in_index].public_key)? {
+                Some(address) => address,
+                None => {
+                    return Err(Error::CustomError(
+                        "Wallet of the input doesn't exists!".to_owned(),
+                    ))
+                }
+            };
+            let prev_output = prev_output(prev_outputs, tx_input)?;
             tx_copy.inputs[in_index].signature = vec![];
-            tx_copy.inputs[in_index].public_key_hash = prev_tx.outputs[tx_input.out as usize]
-                .public_key_hash
-                .clone();
+            tx_copy.inputs[in_index].public_key = prev_output.public_key_hash.clone();
 
             tx_copy.hash()?;
-            tx_copy.inputs[in_index].public_key_hash = vec![];
+            tx_copy.inputs[in_index].public_key = vec![];
 
-            let mut wallets = Wallets::create_wallets()?;
-            let signature: Signature = wallets.sign_tx(&tx_copy.id, address)?;
+            let signature: Signature = wallets.sign_tx(&tx_copy.id, &address)?;
             self.inputs[in_index].signature = signature.serialize_der().to_vec();
         }
 
         Ok(())
     }
 
-    pub(crate) fn verify(&self, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+    pub(crate) fn verify(&self, prev_outputs: &HashMap<String, TxOutputs>) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        for tx_input in self.inputs.iter() {
-            let prev_tx = prev_txs.get(&hex::encode(&tx_input.id));
-            if prev_tx.is_none() || prev_tx.unwrap().id == vec![] {
+        let mut tx_copy = self.trimmed_copy()?;
+        let tx_copy_inputs = self.inputs.clone();
+        for (in_index, tx_input) in tx_copy_inputs.iter().enumerate() {
+            let prev_output = prev_output(prev_outputs, tx_input)?;
+            if !prev_output.is_locked_with(&tx_input.public_key) {
                 return Err(Error::CustomError(
-                    "Previous transaction id is empty!".to_owned(),
+                    "Input key doesn't unlock the previous output!".to_owned(),
                 ));
             }
-        }
 
-        let mut tx_copy = self.trimmed_copy()?;
-        let tx_copy_inputs = self.inputs.clone();
-        for (in_index, tx_input) in tx_copy_inputs.iter().enumerate() {
-            let prev_tx = prev_txs.get(&hex::encode(&tx_input.id)).unwrap();
             tx_copy.inputs[in_index].signature = vec![];
-            tx_copy.inputs[in_index].public_key_hash = prev_tx.outputs[tx_input.out as usize]
-                .public_key_hash
-                .clone();
+            tx_copy.inputs[in_index].public_key = prev_output.public_key_hash.clone();
 
             tx_copy.hash()?;
-            tx_copy.inputs[in_index].public_key_hash = vec![];
+            tx_copy.inputs[in_index].public_key = vec![];
 
             let signature = Signature::from_der(&self.inputs[in_index].signature)?;
-            let public_key = PublicKey::from_slice(&tx_input.public_key_hash)?;
+            let public_key = PublicKey::from_slice(&tx_input.public_key)?;
 
             let digest = Sha256::digest(&tx_copy.id);
             let message = Message::from_digest(digest.into());
@@ -182,12 +322,10 @@ impl Transaction {
         let mut outputs = vec![];
 
         for tx_input in self.inputs.iter() {
-            inputs.push(TxInput::new(
-                tx_input.id.clone(),
-                tx_input.out,
-                vec![],
-                vec![],
-            )?)
+            let mut trimmed_input =
+                TxInput::new(tx_input.id.clone(), tx_input.out, vec![], vec![])?;
+            trimmed_input.sequence = tx_input.sequence;
+            inputs.push(trimmed_input)
         }
 
         for tx_output in self.outputs.iter() {
@@ -205,6 +343,21 @@ impl Transaction {
     }
 }
 
+fn prev_output<'a>(
+    prev_outputs: &'a HashMap<String, TxOutputs>,
+    tx_input: &TxInput,
+) -> Result<&'a TxOutput> {
+    match prev_outputs
+        .get(&hex::encode(&tx_input.id))
+        .and_then(|tx_outputs| tx_outputs.outputs.get(&tx_input.out))
+    {
+        Some(prev_output) => Ok(prev_output),
+        None => Err(Error::CustomError(
+            "Previous transaction output doesn't exist!".to_owned(),
+        )),
+    }
+}
+
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut tx: String = "".to_owned();
@@ -222,9 +375,10 @@ impl fmt::Display for Transaction {
                 hex::encode(&tx_input.signature)
             ));
             tx.push_str(&format!(
-                "          - public_key_hash: {:?}\n",
-                hex::encode(&tx_input.public_key_hash)
+                "          - public_key: {:?}\n",
+                hex::encode(&tx_input.public_key)
             ));
+            tx.push_str(&format!("          - sequence: {:?}\n", tx_input.sequence));
         }
         tx.push_str(" \n");
         for (out_index, tx_output) in self.outputs.iter().enumerate() {
@@ -240,3 +394,133 @@ impl fmt::Display for Transaction {
         write!(f, "{tx}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        blockchain::{mempool::DEFAULT_MAX_MEMPOOL_SIZE, BlockChain},
+        wallet::KeyPath,
+    };
+
+    fn message(result: Result<Transaction>) -> String {
+        match result {
+            Err(Error::CustomError(message)) => message,
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("fee bump succeeded"),
+        }
+    }
+
+    fn key_path(wallets: &Wallets, tx_output: &TxOutput) -> Option<KeyPath> {
+        wallets.key_path(&address_from_public_key_hash(&tx_output.public_key_hash))
+    }
+
+    //? The genesis coinbase is spendable right away, so the account starts with its 20 coins
+    #[test]
+    fn spends_change_from_the_change_chain() {
+        let mut wallets = Wallets::in_memory();
+        wallets.create_hd_wallet(12).unwrap();
+        let from = wallets.add_wallet(None).unwrap();
+        let to = Wallets::in_memory().add_wallet(None).unwrap();
+
+        let mut chain = BlockChain::temporary(&from).unwrap();
+        let utxo_set = UTXOSet::new(chain.clone());
+        utxo_set.reindex().unwrap();
+
+        let tx = Transaction::new(&mut wallets, &from, &to, 5, 1, false, &utxo_set, None).unwrap();
+        let change = key_path(&wallets, &tx.outputs[1]).unwrap();
+        assert_eq!((change.change, change.index), (1, 0));
+        assert_eq!(tx.outputs[1].value, 14);
+        chain.mine_block(vec![tx], &to).unwrap();
+
+        //? The sender address is empty now, only the change address can pay
+        let utxo_set = UTXOSet::new(chain.clone());
+        let tx = Transaction::new(&mut wallets, &from, &to, 10, 1, false, &utxo_set, None).unwrap();
+        let spent = wallets.find_address(&tx.inputs[0].public_key).unwrap();
+        assert_eq!(wallets.key_path(&spent.unwrap()).unwrap().change, 1);
+        let change = key_path(&wallets, &tx.outputs[1]).unwrap();
+        assert_eq!((change.change, change.index), (1, 1));
+        chain.mine_block(vec![tx], &to).unwrap();
+
+        let utxo_set = UTXOSet::new(chain);
+        let change = wallets.account_addresses(&from).pop().unwrap();
+        assert_eq!(utxo_set.get_balance(&change).unwrap(), (3, 0));
+        assert_eq!(utxo_set.get_balance(&from).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn bumps_the_fee_of_a_transaction_spending_unconfirmed_change() {
+        let mut wallets = Wallets::in_memory();
+        wallets.create_hd_wallet(12).unwrap();
+        let from = wallets.add_wallet(None).unwrap();
+        let to = Wallets::in_memory().add_wallet(None).unwrap();
+
+        let chain = BlockChain::temporary(&from).unwrap();
+        let utxo_set = UTXOSet::new(chain.clone());
+        utxo_set.reindex().unwrap();
+        let mut mempool = Mempool::new(String::new(), DEFAULT_MAX_MEMPOOL_SIZE);
+
+        let parent =
+            Transaction::new(&mut wallets, &from, &to, 5, 1, true, &utxo_set, None).unwrap();
+        mempool.accept(parent.clone(), &chain).unwrap();
+        let tx = Transaction::new(
+            &mut wallets,
+            &from,
+            &to,
+            10,
+            1,
+            true,
+            &utxo_set,
+            Some(&mempool),
+        )
+        .unwrap();
+        assert_eq!(tx.inputs[0].id, parent.id);
+        mempool.accept(tx.clone(), &chain).unwrap();
+
+        //? Without the pool the unconfirmed parent output is unknown
+        assert_eq!(
+            message(tx.bump_fee(&wallets, 2, &utxo_set, None)),
+            "Transaction input doesn't exists!"
+        );
+
+        let replacement = tx.bump_fee(&wallets, 2, &utxo_set, Some(&mempool)).unwrap();
+        assert_eq!(replacement.outputs[1].value, tx.outputs[1].value - 1);
+        assert_eq!(mempool.accept(replacement, &chain).unwrap(), 2);
+    }
+
+    #[test]
+    fn bumps_the_fee_from_the_change_wherever_it_is() {
+        let mut wallets = Wallets::in_memory();
+        wallets.create_hd_wallet(12).unwrap();
+        let from = wallets.add_wallet(None).unwrap();
+        //? The payee is in the same account, only the change chain tells the outputs apart
+        let to = wallets.add_wallet(None).unwrap();
+
+        let mut chain = BlockChain::temporary(&from).unwrap();
+        let utxo_set = UTXOSet::new(chain.clone());
+        utxo_set.reindex().unwrap();
+
+        let mut tx =
+            Transaction::new(&mut wallets, &from, &to, 5, 1, true, &utxo_set, None).unwrap();
+        tx.outputs.reverse();
+        for tx_input in tx.inputs.iter_mut() {
+            tx_input.signature = vec![];
+        }
+        tx.id = vec![];
+        tx.hash().unwrap();
+        chain.sign_transaction(&mut tx, &wallets, None).unwrap();
+        assert_eq!(tx.change_output(&wallets).unwrap(), Some(0));
+
+        let replacement = tx.bump_fee(&wallets, 3, &utxo_set, None).unwrap();
+        assert_eq!(replacement.outputs[0].value, 12);
+        assert_eq!(replacement.outputs[1].value, 5);
+
+        chain.mine_block(vec![tx.clone()], &from).unwrap();
+        let utxo_set = UTXOSet::new(chain);
+        assert_eq!(
+            message(tx.bump_fee(&wallets, 3, &utxo_set, None)),
+            "Transaction is already confirmed!"
+        );
+    }
+}
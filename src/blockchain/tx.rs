@@ -1,13 +1,25 @@
+use std::collections::BTreeMap;
+
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{wallet::public_key_hash_from_address, Result};
+use crate::{
+    wallet::{hash160, public_key_hash_from_address, PUBLIC_KEY_HASH_LENGTH},
+    Result,
+};
+
+use super::consensus::is_mature;
+
+pub(crate) static SEQUENCE_FINAL: u32 = u32::MAX;
+//? Any input at or below it lets the transaction be replaced while unconfirmed
+pub(crate) static SEQUENCE_REPLACEABLE: u32 = u32::MAX - 2;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct TxInput {
     pub(crate) id: Vec<u8>,
     pub(crate) out: i64,
     pub(crate) signature: Vec<u8>,
-    pub(crate) public_key_hash: Vec<u8>,
+    pub(crate) public_key: Vec<u8>,
+    pub(crate) sequence: u32,
 }
 
 impl TxInput {
@@ -16,7 +28,8 @@ impl TxInput {
             id,
             out,
             signature,
-            public_key_hash: data,
+            public_key: data,
+            sequence: SEQUENCE_FINAL,
         })
     }
 }
@@ -37,21 +50,39 @@ impl TxOutput {
         })
     }
 
-    pub(crate) fn is_locked_with_key(&self, address: &str) -> Result<bool> {
-        let public_key_hash = public_key_hash_from_address(address)?;
+    pub(crate) fn is_locked_with(&self, public_key: &[u8]) -> bool {
+        unlocks(&self.public_key_hash, public_key)
+    }
+}
 
-        Ok(self.public_key_hash == public_key_hash)
+//? A hash lock takes any key hashing to it, a legacy one only the key it embeds
+pub(crate) fn unlocks(public_key_hash: &[u8], public_key: &[u8]) -> bool {
+    if public_key_hash.len() == PUBLIC_KEY_HASH_LENGTH {
+        hash160(public_key) == public_key_hash
+    } else {
+        public_key == public_key_hash
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+//? Unspent outputs of a transaction keyed by their index in the transaction, with the height of its block
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub(crate) struct TxOutputs {
-    pub(crate) outputs: Vec<TxOutput>,
+    pub(crate) outputs: BTreeMap<i64, TxOutput>,
+    pub(crate) height: u64,
+    pub(crate) coinbase: bool,
 }
 
 impl<'a> TxOutputs {
     pub(crate) fn new() -> Self {
-        Self { outputs: vec![] }
+        Self {
+            outputs: BTreeMap::new(),
+            height: 0,
+            coinbase: false,
+        }
+    }
+
+    pub(crate) fn is_spendable(&self, spend_height: u64) -> bool {
+        !self.coinbase || is_mature(self.height, spend_height)
     }
 
     pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
@@ -62,3 +93,29 @@ impl<'a> TxOutputs {
         Ok(bincode::deserialize(bytes)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::wallet::{address_from_public_key_hash, Wallets};
+
+    #[test]
+    fn legacy_lock_unlocks_only_with_its_full_key() {
+        let mut wallets = Wallets::in_memory();
+        let address = wallets.add_wallet(None).unwrap();
+        let other = wallets.add_wallet(None).unwrap();
+        let public_key = wallets.public_key(&address).unwrap();
+        let other_key = wallets.public_key(&other).unwrap();
+
+        let legacy = TxOutput::new(1, &address_from_public_key_hash(&public_key)).unwrap();
+        assert!(legacy.is_locked_with(&public_key));
+        assert!(!legacy.is_locked_with(&hash160(&public_key)));
+        assert!(!legacy.is_locked_with(&other_key));
+
+        let hashed = TxOutput::new(1, &address).unwrap();
+        assert!(hashed.is_locked_with(&public_key));
+        assert!(!hashed.is_locked_with(&hash160(&public_key)));
+        assert!(!hashed.is_locked_with(&other_key));
+    }
+}
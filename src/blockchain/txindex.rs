@@ -0,0 +1,83 @@
+use serde_derive::{Deserialize, Serialize};
+use sled::{Batch, Db};
+
+use super::{block::Block, transaction::Transaction};
+
+use crate::Result;
+
+pub(crate) static TX_PREFIX: &[u8] = "tx-".as_bytes();
+//? Present once the index has been built, from then on every connected block maintains it
+pub(crate) static TXINDEX_KEY: &[u8] = b"TXINDEX";
+
+//? Where a main chain transaction lives, its position in the block body
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TxLocation {
+    pub(crate) block_hash: Vec<u8>,
+    pub(crate) position: u32,
+}
+
+impl<'a> TxLocation {
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    pub(crate) fn deserialize(bytes: &'a [u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+pub struct TransactionInfo {
+    pub(crate) transaction: Transaction,
+    pub(crate) block_hash: Vec<u8>,
+    pub(crate) block_height: u64,
+    pub(crate) confirmations: u64,
+}
+
+pub(crate) fn is_enabled(database: &Db) -> Result<bool> {
+    Ok(database.contains_key(TXINDEX_KEY)?)
+}
+
+pub(crate) fn get(database: &Db, tx_id: &[u8]) -> Result<Option<TxLocation>> {
+    match database.get(tx_key(tx_id))? {
+        Some(bytes) => Ok(Some(TxLocation::deserialize(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn connect(database: &Db, batch: &mut Batch, block: &Block) -> Result<()> {
+    if !is_enabled(database)? {
+        return Ok(());
+    }
+
+    insert(batch, block)
+}
+
+pub(crate) fn disconnect(database: &Db, batch: &mut Batch, block: &Block) -> Result<()> {
+    if !is_enabled(database)? {
+        return Ok(());
+    }
+
+    for tx in block.transactions.iter() {
+        batch.remove(tx_key(&tx.id));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn insert(batch: &mut Batch, block: &Block) -> Result<()> {
+    for (position, tx) in block.transactions.iter().enumerate() {
+        let location = TxLocation {
+            block_hash: block.hash.clone(),
+            position: position as u32,
+        };
+        batch.insert(tx_key(&tx.id), location.serialize()?);
+    }
+
+    Ok(())
+}
+
+fn tx_key(tx_id: &[u8]) -> Vec<u8> {
+    let mut key = TX_PREFIX.to_vec();
+    key.extend_from_slice(tx_id);
+    key
+}
@@ -0,0 +1,30 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::tx::TxOutput;
+
+use crate::Result;
+
+//? Outputs spent by a block, enough to put them back in the UTXO set when the block is disconnected
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct BlockUndo {
+    pub(crate) spent: Vec<SpentOutput>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct SpentOutput {
+    pub(crate) id: Vec<u8>,
+    pub(crate) out: i64,
+    pub(crate) output: TxOutput,
+    pub(crate) height: u64,
+    pub(crate) coinbase: bool,
+}
+
+impl<'a> BlockUndo {
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    pub(crate) fn deserialize(bytes: &'a [u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
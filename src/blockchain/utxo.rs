@@ -1,16 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use sled::Batch;
+use sled::{Batch, Db};
 
 use super::{
+    address_index::{self, AddressTransaction, ADDRESS_PREFIX},
     block::Block,
-    tx::{TxOutput, TxOutputs},
-    BlockChain,
+    mempool::Mempool,
+    transaction::Transaction,
+    tx::TxOutputs,
+    txindex,
+    undo::{BlockUndo, SpentOutput},
+    validation::BlockRejection,
+    BlockChain, ChainUpdate,
 };
 
-use crate::Result;
+use crate::{wallet::public_key_hash_from_address, Error, Result};
 
 static UTXO_PREFIX: &[u8] = "utxo-".as_bytes();
+static UNDO_PREFIX: &[u8] = "undo-".as_bytes();
 static BATCH_SIZE: usize = 100000;
 
 pub struct UTXOSet {
@@ -24,13 +31,61 @@ impl UTXOSet {
 
     pub fn reindex(&self) -> Result<()> {
         self.delete_by_prefix(UTXO_PREFIX)?;
+        self.delete_by_prefix(ADDRESS_PREFIX)?;
 
         let mut batch = Batch::default();
         let all_utxo = self.chain.find_all_utxo()?;
         for (tx_id, tx_outputs) in all_utxo {
-            let mut key = UTXO_PREFIX.to_vec();
-            key.extend_from_slice(&hex::decode(tx_id)?);
-            batch.insert(key, tx_outputs.serialize()?)
+            let tx_id = hex::decode(tx_id)?;
+            for (out_index, tx_output) in tx_outputs.outputs.iter() {
+                batch.insert(
+                    address_index::unspent_key(&tx_output.public_key_hash, &tx_id, *out_index),
+                    address_index::encode_amount(tx_output.value),
+                );
+            }
+            batch.insert(utxo_key(&tx_id), tx_outputs.serialize()?)
+        }
+
+        //? An input is indexed under the lock of the output it spends, the block undo data has them in input order
+        //? Only a block spending nothing, like the genesis one, can go without it
+        let mut iter = self.chain.iterator();
+        while let Some(block) = iter.next()? {
+            let undo = match self.chain.database.get(undo_key(&block.hash))? {
+                Some(bytes) => BlockUndo::deserialize(&bytes)?,
+                None if block.transactions.iter().all(|tx| tx.is_coinbase()) => {
+                    BlockUndo::default()
+                }
+                None => {
+                    return Err(Error::CustomError(format!(
+                        "Undo data of block {} doesn't exists!",
+                        hex::encode(&block.hash)
+                    )))
+                }
+            };
+            let mut spent = undo.spent.iter();
+
+            for tx in block.transactions.iter() {
+                let mut keys: Vec<&Vec<u8>> = tx
+                    .outputs
+                    .iter()
+                    .map(|tx_output| &tx_output.public_key_hash)
+                    .collect();
+                if !tx.is_coinbase() {
+                    keys.extend(
+                        spent
+                            .by_ref()
+                            .take(tx.inputs.len())
+                            .map(|spent_output| &spent_output.output.public_key_hash),
+                    );
+                }
+
+                for public_key_hash in keys {
+                    batch.insert(
+                        address_index::history_key(public_key_hash, block.height, &tx.id),
+                        block.hash.clone(),
+                    );
+                }
+            }
         }
         self.chain.database.apply_batch(batch)?;
 
@@ -41,102 +96,105 @@ impl UTXOSet {
         self.chain.database.scan_prefix(UTXO_PREFIX).count()
     }
 
-    pub fn update(&self, block: &Block) -> Result<()> {
-        self.chain.database.transaction(|db| {
-            for tx in block.transactions.iter() {
-                if !tx.is_coinbase() {
-                    for tx_input in tx.inputs.iter() {
-                        let mut update_tx_outputs = TxOutputs::new();
-                        let mut tx_id = UTXO_PREFIX.to_vec();
-                        tx_id.extend_from_slice(&tx_input.id);
-
-                        let tx_outputs = TxOutputs::deserialize(&db.get(&tx_id)?.unwrap()).unwrap();
-
-                        for (out_index, tx_output) in tx_outputs.outputs.into_iter().enumerate() {
-                            if out_index != tx_input.out as usize {
-                                update_tx_outputs.outputs.push(tx_output);
-                            }
-                        }
-
-                        if update_tx_outputs.outputs.is_empty() {
-                            db.remove(tx_id)?;
-                        } else {
-                            db.insert(tx_id, update_tx_outputs.serialize().unwrap())?;
-                        }
-                    }
-                }
+    //? Returns the spendable balance and the coinbase rewards still maturing
+    pub fn get_balance(&self, address: &str) -> Result<(u64, u64)> {
+        let spend_height = self.chain.get_best_height()? + 1;
+        let prefix = address_index::unspent_prefix(&public_key_hash_from_address(address)?);
 
-                let mut new_tx_outputs = TxOutputs::new();
-                for out in tx.outputs.iter() {
-                    new_tx_outputs.outputs.push(out.clone());
-                }
+        let mut balance = 0u64;
+        let mut immature = 0u64;
+        for item in self.chain.database.scan_prefix(&prefix) {
+            let (key, bytes) = item?;
+            let (tx_id, _) = address_index::parse_unspent_key(prefix.len(), &key)?;
 
-                let mut tx_id = UTXO_PREFIX.to_vec();
-                tx_id.extend_from_slice(&tx.id);
-                db.insert(tx_id, new_tx_outputs.serialize().unwrap())?;
+            if self.is_spendable(&tx_id, spend_height)? {
+                balance += address_index::decode_amount(&bytes)?;
+            } else {
+                immature += address_index::decode_amount(&bytes)?;
             }
+        }
 
-            Ok(())
-        })?;
-
-        Ok(())
+        Ok((balance, immature))
     }
 
-    pub fn get_balance(&self, address: &str) -> Result<u64> {
-        let mut address_utxo: Vec<TxOutput> = vec![];
+    //? Transactions paying to or spending from the address, oldest first
+    pub fn history(&self, address: &str) -> Result<Vec<AddressTransaction>> {
+        let prefix = address_index::history_prefix(&public_key_hash_from_address(address)?);
 
-        for bytes in self.chain.database.scan_prefix(UTXO_PREFIX).values() {
-            let tx_outputs = TxOutputs::deserialize(&bytes?)?;
-            for tx_output in tx_outputs.outputs {
-                if tx_output.is_locked_with_key(address)? {
-                    address_utxo.push(tx_output)
-                }
-            }
-        }
+        let mut history = vec![];
+        for item in self.chain.database.scan_prefix(&prefix) {
+            let (key, block_hash) = item?;
+            let (height, tx_id) = address_index::parse_history_key(prefix.len(), &key)?;
 
-        let mut balance = 0u64;
-        for utxo in address_utxo {
-            if utxo.is_locked_with_key(address)? {
-                balance += utxo.value
-            }
+            history.push(AddressTransaction {
+                tx_id,
+                block_hash: block_hash.to_vec(),
+                height,
+            });
         }
 
-        Ok(balance)
+        Ok(history)
     }
 
+    //? Immature coinbase outputs are left out, they couldn't go in the next block
+    //? With a memory pool, outputs its transactions spend are skipped and unconfirmed change of the address is used last,
+    //? change being what pending transactions signed with the own keys pay to it
     pub(crate) fn find_address_unspent_outputs(
         &self,
         address: &str,
         amount: u64,
+        mempool: Option<&Mempool>,
+        own_keys: &[Vec<u8>],
     ) -> Result<(u64, HashMap<String, Vec<i64>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i64>> = HashMap::new();
-        let mut accumulated = 0;
+        let mut accumulated = 0u64;
+        let spend_height = self.chain.get_best_height()? + 1;
+
+        let public_key_hash = public_key_hash_from_address(address)?;
+        let prefix = address_index::unspent_prefix(&public_key_hash);
+        for item in self.chain.database.scan_prefix(&prefix) {
+            if accumulated >= amount {
+                break;
+            }
 
-        for item in self.chain.database.scan_prefix(UTXO_PREFIX) {
             let (key, bytes) = item?;
+            let (tx_id, out_index) = address_index::parse_unspent_key(prefix.len(), &key)?;
+            if !self.is_spendable(&tx_id, spend_height)?
+                || mempool.is_some_and(|mempool| mempool.is_spent(&tx_id, out_index))
+            {
+                continue;
+            }
 
-            let tx_id = hex::encode(
-                key.iter()
-                    .copied()
-                    .skip(UTXO_PREFIX.len())
-                    .collect::<Vec<u8>>(),
-            );
+            accumulated = accumulated.saturating_add(address_index::decode_amount(&bytes)?);
+            unspent_outputs
+                .entry(hex::encode(tx_id))
+                .or_default()
+                .push(out_index);
+        }
 
-            let tx_outputs = TxOutputs::deserialize(&bytes)?;
-            for (out_index, tx_output) in tx_outputs.outputs.iter().enumerate() {
-                if tx_output.is_locked_with_key(address)? && accumulated < amount {
-                    accumulated += tx_output.value;
-                    unspent_outputs
-                        .entry(tx_id.clone())
-                        .or_default()
-                        .push(out_index as i64);
+        if let Some(mempool) = mempool {
+            for (tx_id, out_index, value) in mempool.change_outputs(&public_key_hash, own_keys) {
+                if accumulated >= amount {
+                    break;
                 }
+
+                accumulated = accumulated.saturating_add(value);
+                unspent_outputs
+                    .entry(hex::encode(tx_id))
+                    .or_default()
+                    .push(out_index);
             }
         }
 
         Ok((accumulated, unspent_outputs))
     }
 
+    fn is_spendable(&self, tx_id: &[u8], spend_height: u64) -> Result<bool> {
+        Ok(UTXOView::new(&self.chain.database)
+            .get_outputs(tx_id)?
+            .is_some_and(|tx_outputs| tx_outputs.is_spendable(spend_height)))
+    }
+
     fn delete_by_prefix(&self, prefix: &[u8]) -> Result<()> {
         let mut batchs: Vec<Batch> = vec![];
         let mut batch = Batch::default();
@@ -164,3 +222,302 @@ impl UTXOSet {
         Ok(())
     }
 }
+
+//? Writes a tip change to the UTXO set, its undo data and the indexes in the caller's batch
+pub(crate) fn write_update(database: &Db, update: &ChainUpdate, batch: &mut Batch) -> Result<()> {
+    let mut view = UTXOView::new(database);
+
+    for block in update.disconnected.iter() {
+        view.disconnect(block)?;
+        batch.remove(undo_key(&block.hash));
+        txindex::disconnect(database, batch, block)?;
+    }
+
+    for block in update.connected.iter() {
+        let undo = view.connect(block)?;
+        batch.insert(undo_key(&block.hash), undo.serialize()?);
+        txindex::connect(database, batch, block)?;
+    }
+
+    view.write(batch)
+}
+
+//? Pending changes on top of the stored UTXO set and address index, a removed entry is kept as None
+pub(crate) struct UTXOView<'a> {
+    database: &'a Db,
+    changes: HashMap<Vec<u8>, Option<TxOutputs>>,
+    address_changes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> UTXOView<'a> {
+    pub(crate) fn new(database: &'a Db) -> Self {
+        Self {
+            database,
+            changes: HashMap::new(),
+            address_changes: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn connect(&mut self, block: &Block) -> Result<BlockUndo> {
+        let mut undo = BlockUndo::default();
+        for tx in block.transactions.iter() {
+            let spent_from = undo.spent.len();
+            self.connect_transaction(tx, block.height, &mut undo)?;
+            self.index_transaction(block, tx, &undo.spent[spent_from..]);
+        }
+
+        Ok(undo)
+    }
+
+    fn index_transaction(&mut self, block: &Block, tx: &Transaction, spent: &[SpentOutput]) {
+        for spent_output in spent {
+            let public_key_hash = &spent_output.output.public_key_hash;
+            self.address_changes.insert(
+                address_index::unspent_key(public_key_hash, &spent_output.id, spent_output.out),
+                None,
+            );
+            self.address_changes.insert(
+                address_index::history_key(public_key_hash, block.height, &tx.id),
+                Some(block.hash.clone()),
+            );
+        }
+
+        for (out_index, tx_output) in tx.outputs.iter().enumerate() {
+            let public_key_hash = &tx_output.public_key_hash;
+            self.address_changes.insert(
+                address_index::unspent_key(public_key_hash, &tx.id, out_index as i64),
+                Some(address_index::encode_amount(tx_output.value)),
+            );
+            self.address_changes.insert(
+                address_index::history_key(public_key_hash, block.height, &tx.id),
+                Some(block.hash.clone()),
+            );
+        }
+    }
+
+    pub(crate) fn connect_transaction(
+        &mut self,
+        tx: &Transaction,
+        height: u64,
+        undo: &mut BlockUndo,
+    ) -> Result<()> {
+        if !tx.is_coinbase() {
+            for tx_input in tx.inputs.iter() {
+                let mut tx_outputs = self.get_outputs(&tx_input.id)?.unwrap_or_default();
+                let output = match tx_outputs.outputs.remove(&tx_input.out) {
+                    Some(output) => output,
+                    None => return Err(BlockRejection::MissingInput(hex::encode(&tx.id)).into()),
+                };
+
+                undo.spent.push(SpentOutput {
+                    id: tx_input.id.clone(),
+                    out: tx_input.out,
+                    output,
+                    height: tx_outputs.height,
+                    coinbase: tx_outputs.coinbase,
+                });
+                self.set_outputs(&tx_input.id, tx_outputs);
+            }
+        }
+
+        let mut tx_outputs = tx.tx_outputs();
+        tx_outputs.height = height;
+        tx_outputs.coinbase = tx.is_coinbase();
+        self.set_outputs(&tx.id, tx_outputs);
+
+        Ok(())
+    }
+
+    pub(crate) fn disconnect(&mut self, block: &Block) -> Result<()> {
+        let undo = match self.database.get(undo_key(&block.hash))? {
+            Some(bytes) => BlockUndo::deserialize(&bytes)?,
+            None => {
+                return Err(Error::CustomError(format!(
+                    "Undo data of block {} doesn't exists!",
+                    hex::encode(&block.hash)
+                )))
+            }
+        };
+
+        let block_tx_ids: HashSet<&Vec<u8>> = block.transactions.iter().map(|tx| &tx.id).collect();
+
+        let mut spent_outputs = undo.spent.iter();
+        for tx in block.transactions.iter() {
+            self.set_outputs(&tx.id, TxOutputs::new());
+
+            for (out_index, tx_output) in tx.outputs.iter().enumerate() {
+                let public_key_hash = &tx_output.public_key_hash;
+                self.address_changes.insert(
+                    address_index::unspent_key(public_key_hash, &tx.id, out_index as i64),
+                    None,
+                );
+                self.address_changes.insert(
+                    address_index::history_key(public_key_hash, block.height, &tx.id),
+                    None,
+                );
+            }
+            if !tx.is_coinbase() {
+                for spent_output in spent_outputs.by_ref().take(tx.inputs.len()) {
+                    self.address_changes.insert(
+                        address_index::history_key(
+                            &spent_output.output.public_key_hash,
+                            block.height,
+                            &tx.id,
+                        ),
+                        None,
+                    );
+                }
+            }
+        }
+
+        //? Outputs created and spent inside the block are already gone with their transaction
+        for spent in undo.spent {
+            if block_tx_ids.contains(&spent.id) {
+                continue;
+            }
+
+            self.address_changes.insert(
+                address_index::unspent_key(&spent.output.public_key_hash, &spent.id, spent.out),
+                Some(address_index::encode_amount(spent.output.value)),
+            );
+
+            let mut tx_outputs = self.get_outputs(&spent.id)?.unwrap_or_default();
+            tx_outputs.outputs.insert(spent.out, spent.output);
+            tx_outputs.height = spent.height;
+            tx_outputs.coinbase = spent.coinbase;
+            self.set_outputs(&spent.id, tx_outputs);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn write(self, batch: &mut Batch) -> Result<()> {
+        for (tx_id, tx_outputs) in self.changes {
+            match tx_outputs {
+                Some(tx_outputs) => batch.insert(utxo_key(&tx_id), tx_outputs.serialize()?),
+                None => batch.remove(utxo_key(&tx_id)),
+            }
+        }
+
+        for (key, value) in self.address_changes {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn get_outputs(&self, tx_id: &[u8]) -> Result<Option<TxOutputs>> {
+        if let Some(tx_outputs) = self.changes.get(tx_id) {
+            return Ok(tx_outputs.clone());
+        }
+
+        match self.database.get(utxo_key(tx_id))? {
+            Some(bytes) => Ok(Some(TxOutputs::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_outputs(&mut self, tx_id: &[u8], tx_outputs: TxOutputs) {
+        if tx_outputs.outputs.is_empty() {
+            self.changes.insert(tx_id.to_vec(), None);
+        } else {
+            self.changes.insert(tx_id.to_vec(), Some(tx_outputs));
+        }
+    }
+}
+
+fn utxo_key(tx_id: &[u8]) -> Vec<u8> {
+    let mut key = UTXO_PREFIX.to_vec();
+    key.extend_from_slice(tx_id);
+    key
+}
+
+fn undo_key(block_hash: &[u8]) -> Vec<u8> {
+    let mut key = UNDO_PREFIX.to_vec();
+    key.extend_from_slice(block_hash);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    use crate::{blockchain::txindex::TX_PREFIX, wallet::Wallets};
+
+    fn snapshot(database: &Db, prefix: &[u8]) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        database
+            .scan_prefix(prefix)
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect()
+    }
+
+    fn state(database: &Db) -> Vec<BTreeMap<Vec<u8>, Vec<u8>>> {
+        [UTXO_PREFIX, ADDRESS_PREFIX, TX_PREFIX]
+            .iter()
+            .map(|prefix| snapshot(database, prefix))
+            .collect()
+    }
+
+    //? Both branches spend the genesis coinbase, the longer one has to take its place
+    #[test]
+    fn reorganizes_onto_the_heavier_branch() {
+        let mut wallets = Wallets::in_memory();
+        let from = wallets.add_wallet(None).unwrap();
+        let to = wallets.add_wallet(None).unwrap();
+        let other = wallets.add_wallet(None).unwrap();
+
+        let mut chain = BlockChain::temporary(&from).unwrap();
+        let genesis = chain.lasthash.clone();
+        let utxo_set = UTXOSet::new(chain.clone());
+        utxo_set.reindex().unwrap();
+        chain.reindex_transactions().unwrap();
+
+        let tx_a =
+            Transaction::new(&mut wallets, &from, &to, 5, 1, false, &utxo_set, None).unwrap();
+        let tx_b =
+            Transaction::new(&mut wallets, &from, &other, 7, 1, false, &utxo_set, None).unwrap();
+
+        let block_a = chain
+            .child_block(&genesis, vec![tx_a.clone()], &to)
+            .unwrap();
+        chain.add_block(&block_a).unwrap();
+        assert_eq!(utxo_set.get_balance(&to).unwrap().0, 5);
+
+        let block_b1 = chain
+            .child_block(&genesis, vec![tx_b.clone()], &other)
+            .unwrap();
+        let update = chain.add_block(&block_b1).unwrap();
+        assert!(update.connected.is_empty());
+        assert_eq!(chain.lasthash, block_a.hash);
+
+        let block_b2 = chain.child_block(&block_b1.hash, vec![], &other).unwrap();
+        let update = chain.add_block(&block_b2).unwrap();
+        assert_eq!(update.disconnected.len(), 1);
+        assert_eq!(update.connected.len(), 2);
+        assert_eq!(chain.lasthash, block_b2.hash);
+
+        let utxo_set = UTXOSet::new(chain.clone());
+        assert_eq!(utxo_set.get_balance(&to).unwrap().0, 0);
+        assert_eq!(utxo_set.get_balance(&other).unwrap().0, 7);
+        assert!(chain.get_transaction(&tx_a.id).unwrap().is_none());
+        assert!(chain.get_transaction(&tx_b.id).unwrap().is_some());
+
+        let undo: Vec<Vec<u8>> = snapshot(&chain.database, UNDO_PREFIX).into_keys().collect();
+        let mut expected = vec![undo_key(&block_b1.hash), undo_key(&block_b2.hash)];
+        expected.sort();
+        assert_eq!(undo, expected);
+
+        let reorganized = state(&chain.database);
+        utxo_set.reindex().unwrap();
+        chain.reindex_transactions().unwrap();
+        assert_eq!(reorganized, state(&chain.database));
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
+
+use thiserror::Error;
+
+use super::{
+    block::{Block, BlockHeader, BLOCK_VERSION},
+    consensus::{block_subsidy, MAX_MONEY},
+    proof::ProofOfWork,
+    transaction::Transaction,
+    tx::TxOutputs,
+    undo::BlockUndo,
+    utxo::UTXOView,
+    BlockChain, ChainUpdate,
+};
+
+use crate::Result;
+
+static MAX_FUTURE_BLOCK_TIME: u64 = 10 * 60; //? Seconds
+static MEDIAN_TIME_SPAN: usize = 11; //? Blocks
+pub(crate) static MAX_BLOCK_SIZE: usize = 1_000_000; //? Bytes of serialized transactions
+
+#[derive(Error, Debug)]
+pub enum BlockRejection {
+    #[error("parent block {0} is unknown")]
+    UnknownParent(String),
+    #[error("height {height} doesn't follow parent height {parent_height}")]
+    BadHeight { height: u64, parent_height: u64 },
+    #[error("block version {0} isn't supported")]
+    BadVersion(u32),
+    #[error("block hash doesn't match the block header")]
+    HashMismatch,
+    #[error("merkle root doesn't match the block transactions")]
+    BadMerkleRoot,
+    #[error("proof of work doesn't meet the target")]
+    InvalidProofOfWork,
+    #[error("difficulty bits {bits:#010x} don't match the expected {expected:#010x}")]
+    BadDifficulty { bits: u32, expected: u32 },
+    #[error("timestamp {0} is too far in the future")]
+    TimeTooNew(u64),
+    #[error("timestamp {0} is earlier than the median of the previous blocks")]
+    TimeTooOld(u64),
+    #[error("block has no transactions")]
+    NoTransactions,
+    #[error("block transactions take {size} bytes, more than the {limit} bytes limit")]
+    TooLarge { size: usize, limit: usize },
+    #[error("first transaction must be the only coinbase")]
+    BadCoinbase,
+    #[error("coinbase claims {claimed} but only {allowed} is allowed")]
+    CoinbaseAmount { claimed: u64, allowed: u64 },
+    #[error("transaction {0} id doesn't match its content")]
+    BadTransactionId(String),
+    #[error("transaction {0} appears twice in the block")]
+    DuplicateTransaction(String),
+    #[error("transaction {0} has no inputs")]
+    NoInputs(String),
+    #[error("transaction {0} spends an output already spent in the block")]
+    DoubleSpend(String),
+    #[error("transaction {0} spends a missing or already spent output")]
+    MissingInput(String),
+    #[error("transaction {0} spends a coinbase output before it matured")]
+    ImmatureSpend(String),
+    #[error("transaction {0} has an invalid signature")]
+    BadSignature(String),
+    #[error("transaction {0} spends more than its inputs")]
+    ValueOverflow(String),
+    #[error("transaction {0} outputs exceed the money supply")]
+    ValueOutOfRange(String),
+}
+
+impl BlockChain {
+    //? Checks a header against its indexed parent and returns the height it would take
+    pub fn validate_header(&self, header: &BlockHeader, hash: &[u8]) -> Result<u64> {
+        let parent = match self.get_header(&header.prevhash)? {
+            Some(parent) => parent,
+            None => return Err(BlockRejection::UnknownParent(hex::encode(&header.prevhash)).into()),
+        };
+        let parent_height = self.get_index(&header.prevhash)?.height;
+
+        if header.version != BLOCK_VERSION {
+            return Err(BlockRejection::BadVersion(header.version).into());
+        }
+
+        let proof = ProofOfWork::new_proof(header);
+        if proof.hash()? != hash {
+            return Err(BlockRejection::HashMismatch.into());
+        }
+        if !proof.validate()? {
+            return Err(BlockRejection::InvalidProofOfWork.into());
+        }
+
+        let expected = self.next_bits(&parent, parent_height)?;
+        if header.bits != expected {
+            return Err(BlockRejection::BadDifficulty {
+                bits: header.bits,
+                expected,
+            }
+            .into());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("System time is earlier than Unix epoch")
+            .as_secs();
+        if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockRejection::TimeTooNew(header.timestamp).into());
+        }
+        if header.timestamp < self.median_time_past(&parent)? {
+            return Err(BlockRejection::TimeTooOld(header.timestamp).into());
+        }
+
+        Ok(parent_height + 1)
+    }
+
+    //? Checks everything that doesn't depend on the UTXO set, run before a block is stored
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        if !self.has_block(&block.header.prevhash)? {
+            return Err(BlockRejection::UnknownParent(hex::encode(&block.header.prevhash)).into());
+        }
+
+        let height = self.validate_header(&block.header, &block.hash)?;
+        if block.height != height {
+            return Err(BlockRejection::BadHeight {
+                height: block.height,
+                parent_height: height - 1,
+            }
+            .into());
+        }
+
+        check_structure(block)
+    }
+
+    //? Replays a tip change on top of the UTXO set without writing it, run before the tip moves
+    pub(crate) fn validate_update(&self, update: &ChainUpdate) -> Result<()> {
+        let mut view = UTXOView::new(&self.database);
+
+        for block in update.disconnected.iter() {
+            view.disconnect(block)?;
+        }
+
+        for block in update.connected.iter() {
+            check_transactions(block, &mut view)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn median_time_past(&self, parent: &BlockHeader) -> Result<u64> {
+        let mut timestamps = vec![parent.timestamp];
+        let mut hash = parent.prevhash.clone();
+
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.get_header(&hash)? {
+                Some(header) => {
+                    timestamps.push(header.timestamp);
+                    hash = header.prevhash;
+                }
+                None => break,
+            }
+        }
+
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+}
+
+fn check_structure(block: &Block) -> Result<()> {
+    match block.transactions.first() {
+        None => return Err(BlockRejection::NoTransactions.into()),
+        Some(coinbase) if !coinbase.is_coinbase() => return Err(BlockRejection::BadCoinbase.into()),
+        _ => {}
+    }
+
+    if block.hash_transactions()? != block.header.merkle_root {
+        return Err(BlockRejection::BadMerkleRoot.into());
+    }
+
+    let mut size = 0;
+    for tx in block.transactions.iter() {
+        size += tx.size()?;
+    }
+    if size > MAX_BLOCK_SIZE {
+        return Err(BlockRejection::TooLarge {
+            size,
+            limit: MAX_BLOCK_SIZE,
+        }
+        .into());
+    }
+
+    let mut tx_ids = HashSet::new();
+    let mut spent = HashSet::new();
+
+    for tx in block.transactions.iter() {
+        let tx_id = hex::encode(&tx.id);
+
+        if tx.compute_id()? != tx.id {
+            return Err(BlockRejection::BadTransactionId(tx_id).into());
+        }
+        if output_value(tx)? > MAX_MONEY {
+            return Err(BlockRejection::ValueOutOfRange(tx_id).into());
+        }
+        if !tx_ids.insert(tx.id.clone()) {
+            return Err(BlockRejection::DuplicateTransaction(tx_id).into());
+        }
+        if tx.is_coinbase() {
+            continue;
+        }
+
+        if tx.inputs.is_empty() {
+            return Err(BlockRejection::NoInputs(tx_id).into());
+        }
+        for tx_input in tx.inputs.iter() {
+            if !spent.insert((tx_input.id.clone(), tx_input.out)) {
+                return Err(BlockRejection::DoubleSpend(tx_id).into());
+            }
+        }
+    }
+
+    if block.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+        return Err(BlockRejection::BadCoinbase.into());
+    }
+
+    Ok(())
+}
+
+fn check_transactions(block: &Block, view: &mut UTXOView<'_>) -> Result<()> {
+    let mut undo = BlockUndo::default();
+    let mut fees = 0u64;
+
+    for tx in block.transactions.iter() {
+        if !tx.is_coinbase() {
+            fees = match fees.checked_add(check_inputs(tx, view, block.height)?) {
+                Some(fees) => fees,
+                None => return Err(BlockRejection::ValueOverflow(hex::encode(&tx.id)).into()),
+            };
+        }
+        view.connect_transaction(tx, block.height, &mut undo)?;
+    }
+
+    let claimed = output_value(&block.transactions[0])?;
+    let allowed = block_subsidy(block.height).saturating_add(fees);
+    if claimed > allowed {
+        return Err(BlockRejection::CoinbaseAmount { claimed, allowed }.into());
+    }
+
+    Ok(())
+}
+
+//? Checks a transaction going in a block at this height against the UTXO view and returns its fee
+pub(crate) fn check_inputs(tx: &Transaction, view: &UTXOView<'_>, height: u64) -> Result<u64> {
+    let tx_id = hex::encode(&tx.id);
+    let mut prev_outputs: HashMap<String, TxOutputs> = HashMap::new();
+    let mut input_value = 0u64;
+
+    for tx_input in tx.inputs.iter() {
+        let tx_outputs = view.get_outputs(&tx_input.id)?.unwrap_or_default();
+        let prev_output = match tx_outputs.outputs.get(&tx_input.out) {
+            Some(prev_output) => prev_output.clone(),
+            None => return Err(BlockRejection::MissingInput(tx_id).into()),
+        };
+        if !tx_outputs.is_spendable(height) {
+            return Err(BlockRejection::ImmatureSpend(tx_id).into());
+        }
+
+        input_value = match input_value.checked_add(prev_output.value) {
+            Some(value) => value,
+            None => return Err(BlockRejection::ValueOverflow(tx_id).into()),
+        };
+        prev_outputs
+            .entry(hex::encode(&tx_input.id))
+            .or_default()
+            .outputs
+            .insert(tx_input.out, prev_output);
+    }
+
+    if tx.verify(&prev_outputs).is_err() {
+        return Err(BlockRejection::BadSignature(tx_id).into());
+    }
+
+    let output_value = output_value(tx)?;
+    if output_value > input_value {
+        return Err(BlockRejection::ValueOverflow(tx_id).into());
+    }
+
+    Ok(input_value - output_value)
+}
+
+fn output_value(tx: &Transaction) -> Result<u64> {
+    let mut value = 0u64;
+    for tx_output in tx.outputs.iter() {
+        value = match value.checked_add(tx_output.value) {
+            Some(value) => value,
+            None => return Err(BlockRejection::ValueOverflow(hex::encode(&tx.id)).into()),
+        };
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    use crate::{
+        blockchain::{
+            miner::Miner,
+            tx::{TxInput, TxOutput},
+            UTXOSet,
+        },
+        wallet::Wallets,
+        Error,
+    };
+
+    struct Fixture {
+        wallets: Wallets,
+        from: String,
+        to: String,
+        chain: BlockChain,
+    }
+
+    //? The genesis coinbase pays the sender and is spendable right away
+    fn fixture() -> Fixture {
+        let mut wallets = Wallets::in_memory();
+        let from = wallets.add_wallet(None).unwrap();
+        let to = wallets.add_wallet(None).unwrap();
+        let chain = BlockChain::temporary(&from).unwrap();
+        UTXOSet::new(chain.clone()).reindex().unwrap();
+
+        Fixture {
+            wallets,
+            from,
+            to,
+            chain,
+        }
+    }
+
+    impl Fixture {
+        fn send(&mut self, amount: u64, fee: u64) -> Transaction {
+            let utxo_set = UTXOSet::new(self.chain.clone());
+            Transaction::new(
+                &mut self.wallets,
+                &self.from,
+                &self.to,
+                amount,
+                fee,
+                false,
+                &utxo_set,
+                None,
+            )
+            .unwrap()
+        }
+
+        fn block(&self, transactions: Vec<Transaction>) -> Block {
+            self.chain
+                .child_block(&self.chain.lasthash, transactions, &self.from)
+                .unwrap()
+        }
+
+        fn rejection(&mut self, block: &Block) -> BlockRejection {
+            match self.chain.add_block(block) {
+                Err(Error::BlockRejected { source }) => source,
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("invalid block was accepted"),
+            }
+        }
+    }
+
+    //? The header changed, so the merkle root and proof of work are redone
+    fn remine(block: &mut Block, merkle_root: bool) {
+        if merkle_root {
+            block.header.merkle_root = block.hash_transactions().unwrap();
+        }
+        Miner::default()
+            .mine(block, &AtomicBool::new(false))
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_a_double_spend_inside_the_block() {
+        let mut fixture = fixture();
+        let first = fixture.send(5, 0);
+        let second = fixture.send(6, 0);
+
+        let block = fixture.block(vec![first, second]);
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::DoubleSpend(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_bad_merkle_root() {
+        let mut fixture = fixture();
+        let tx = fixture.send(5, 0);
+
+        let mut block = fixture.block(vec![tx]);
+        block.header.merkle_root = vec![0; 32];
+        remine(&mut block, false);
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::BadMerkleRoot
+        ));
+    }
+
+    #[test]
+    fn rejects_a_bad_proof_of_work() {
+        let mut fixture = fixture();
+        let mut block = fixture.block(vec![]);
+
+        loop {
+            block.header.nonce += 1;
+            let proof = ProofOfWork::new_proof(&block.header);
+            if !proof.validate().unwrap() {
+                block.hash = proof.hash().unwrap();
+                break;
+            }
+        }
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::InvalidProofOfWork
+        ));
+    }
+
+    #[test]
+    fn rejects_timestamps_out_of_range() {
+        let mut fixture = fixture();
+
+        let mut block = fixture.block(vec![]);
+        block.header.timestamp += MAX_FUTURE_BLOCK_TIME + 60;
+        remine(&mut block, false);
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::TimeTooNew(_)
+        ));
+
+        let genesis = fixture.chain.get_header(&fixture.chain.lasthash).unwrap();
+        let mut block = fixture.block(vec![]);
+        block.header.timestamp = genesis.unwrap().timestamp - 1;
+        remine(&mut block, false);
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::TimeTooOld(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_coinbase_above_subsidy_and_fees() {
+        let mut fixture = fixture();
+        let tx = fixture.send(5, 2);
+        let allowed = block_subsidy(1) + 2;
+
+        let mut block = fixture.block(vec![tx]);
+        block.transactions[0] = Transaction::coinbase_tx(&fixture.from, allowed + 1).unwrap();
+        remine(&mut block, true);
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::CoinbaseAmount { claimed, allowed: limit } if claimed == allowed + 1 && limit == allowed
+        ));
+
+        //? Claiming the fees exactly is fine
+        block.transactions[0] = Transaction::coinbase_tx(&fixture.from, allowed).unwrap();
+        remine(&mut block, true);
+        fixture.chain.add_block(&block).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_immature_coinbase_spend() {
+        let mut fixture = fixture();
+        let block = fixture.block(vec![]);
+        fixture.chain.add_block(&block).unwrap();
+
+        let coinbase = &block.transactions[0];
+        let public_key = fixture.wallets.public_key(&fixture.from).unwrap();
+        let mut tx = Transaction {
+            id: vec![],
+            inputs: vec![TxInput::new(coinbase.id.clone(), 0, vec![], public_key).unwrap()],
+            outputs: vec![TxOutput::new(coinbase.outputs[0].value, &fixture.to).unwrap()],
+        };
+        tx.hash().unwrap();
+        fixture
+            .chain
+            .sign_transaction(&mut tx, &fixture.wallets, None)
+            .unwrap();
+
+        let block = fixture.block(vec![tx]);
+        assert!(matches!(
+            fixture.rejection(&block),
+            BlockRejection::ImmatureSpend(_)
+        ));
+    }
+}
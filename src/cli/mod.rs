@@ -1,5 +1,5 @@
 use crate::{
-    blockchain::{BlockChain, Transaction, UTXOSet},
+    blockchain::{self, BlockChain, Transaction, UTXOSet},
     network,
     wallet::Wallets,
     Error, Result,
@@ -55,7 +55,42 @@ impl CommandLine {
                         return Err(Error::CustomError("MINE_NOW must be an boolean".to_owned()))
                     }
                 };
-                self.send_coin(&node_id, &self.args[1], &self.args[2], amount, mine_now)?;
+                let fee = match self.args.get(5).map(|fee| fee.parse::<u64>()) {
+                    None => 0,
+                    Some(Ok(fee)) => fee,
+                    Some(Err(_)) => {
+                        return Err(Error::CustomError("Fee must be an integer".to_owned()))
+                    }
+                };
+                let replaceable = match self.args.get(6).map(|flag| flag.parse::<bool>()) {
+                    None => true,
+                    Some(Ok(replaceable)) => replaceable,
+                    Some(Err(_)) => {
+                        return Err(Error::CustomError(
+                            "REPLACEABLE must be a boolean".to_owned(),
+                        ))
+                    }
+                };
+                self.send_coin(
+                    &node_id,
+                    &self.args[1],
+                    &self.args[2],
+                    amount,
+                    fee,
+                    mine_now,
+                    replaceable,
+                )
+                .await?;
+            }
+            Command::BumpFee => {
+                if self.args.len() < 3 {
+                    print_usage_and_exit()
+                }
+                let fee = match self.args[2].parse::<u64>() {
+                    Ok(fee) => fee,
+                    Err(_) => return Err(Error::CustomError("Fee must be an integer".to_owned())),
+                };
+                self.bump_fee(&node_id, &self.args[1], fee).await?;
             }
             Command::GetBalance => {
                 if self.args.len() < 2 {
@@ -63,16 +98,73 @@ impl CommandLine {
                 }
                 self.get_balance(&node_id, &self.args[1])?;
             }
+            Command::GetHistory => {
+                if self.args.len() < 2 {
+                    print_usage_and_exit()
+                }
+                self.get_history(&node_id, &self.args[1])?;
+            }
             Command::PrintBlockchain => self.print_blockchain(&node_id)?,
-            Command::CreateWallet => self.create_wallet()?,
+            Command::GetSupply => {
+                let height = match self.args.get(1).map(|height| height.parse::<u64>()) {
+                    None => None,
+                    Some(Ok(height)) => Some(height),
+                    Some(Err(_)) => {
+                        return Err(Error::CustomError("Height must be an integer".to_owned()))
+                    }
+                };
+                self.get_supply(&node_id, height)?;
+            }
+            Command::CreateWallet => {
+                let account = match self.args.get(1).map(|account| account.parse::<u32>()) {
+                    None => None,
+                    Some(Ok(account)) => Some(account),
+                    Some(Err(_)) => {
+                        return Err(Error::CustomError("Account must be an integer".to_owned()))
+                    }
+                };
+                self.create_wallet(account)?
+            }
+            Command::CreateHdWallet => {
+                let words = match self.args.get(1).map(|words| words.parse::<usize>()) {
+                    None => 12,
+                    Some(Ok(words)) => words,
+                    Some(Err(_)) => {
+                        return Err(Error::CustomError("Words must be an integer".to_owned()))
+                    }
+                };
+                self.create_hd_wallet(words)?
+            }
+            Command::RestoreWallet => {
+                if self.args.len() < 2 {
+                    print_usage_and_exit()
+                }
+                self.restore_wallet(&node_id, &self.args[1..].join(" "))?
+            }
+            Command::RescanWallet => self.rescan_wallet(&node_id)?,
+            Command::EncryptWallet => self.encrypt_wallet()?,
+            Command::ChangePassphrase => self.change_passphrase()?,
             Command::ListAddresses => self.list_addresses()?,
             Command::ReindexUTXO => self.reindex_utxo(&node_id)?,
+            Command::ReindexTransactions => self.reindex_transactions(&node_id)?,
+            Command::GetTransaction => {
+                if self.args.len() < 2 {
+                    print_usage_and_exit()
+                }
+                self.get_transaction(&node_id, &self.args[1])?;
+            }
             Command::StartNode => {
                 if self.args.len() < 2 {
                     print_usage_and_exit()
                 }
                 self.start_node(&node_id, &self.args[1]).await?
             }
+            Command::Rpc => {
+                if self.args.len() < 2 {
+                    print_usage_and_exit()
+                }
+                self.rpc(&node_id, &self.args[1], &self.args[2..]).await?
+            }
         }
 
         println!();
@@ -80,8 +172,8 @@ impl CommandLine {
     }
 
     fn create_blockchain(&self, node_id: &str, address: &str) -> Result<()> {
-        let mut wallets = Wallets::create_wallets()?;
-        wallets.get_wallet(address)?;
+        let wallets = Wallets::create_wallets()?;
+        wallets.check_address(address)?;
 
         let chain = BlockChain::init_blockchain(node_id, address)?;
         println!("Blockchain created");
@@ -93,46 +185,122 @@ impl CommandLine {
         Ok(())
     }
 
-    fn send_coin(
+    #[allow(clippy::too_many_arguments)]
+    async fn send_coin(
         &self,
         node_id: &str,
         from: &str,
         to: &str,
         amount: u64,
+        fee: u64,
         mine_now: bool,
+        replaceable: bool,
     ) -> Result<()> {
-        let mut wallets = Wallets::create_wallets()?;
-        wallets.get_wallet(from)?;
-        wallets.get_wallet(to)?;
+        let mut wallets = open_wallets()?;
+        wallets.check_address(from)?;
+        wallets.check_address(to)?;
 
         let mut chain = BlockChain::continue_blockchain(node_id)?;
         let utxo_set = UTXOSet::new(chain.clone());
 
-        let tx = Transaction::new(from, to, amount, &utxo_set)?;
+        let tx = Transaction::new(
+            &mut wallets,
+            from,
+            to,
+            amount,
+            fee,
+            replaceable,
+            &utxo_set,
+            None,
+        )?;
         if mine_now {
-            let coinbase_tx = Transaction::coinbase_tx(from)?;
-
-            let block = chain.mine_block(vec![coinbase_tx, tx])?;
-            utxo_set.update(&block)?;
+            chain.mine_block(vec![tx], from)?;
         } else {
-            network::send_transaction_central(node_id, &tx)?;
-            println!("Send transaction");
+            network::submit_transaction(node_id, &tx, &network_config()?).await?;
+            chain.save_wallet_transaction(&tx)?;
+            println!("Send transaction {:?}", hex::encode(&tx.id));
         }
-        println!("Send {amount} coin | {from} -> {to}");
+        //? Saved once the transaction went out, a failed send doesn't use up a change address
+        wallets.save_file()?;
+        println!("Send {amount} coin with {fee} fee | {from} -> {to}");
+
+        println!();
+        Ok(())
+    }
+
+    async fn bump_fee(&self, node_id: &str, tx_id: &str, fee: u64) -> Result<()> {
+        let chain = BlockChain::continue_blockchain(node_id)?;
+        let tx = match chain.get_wallet_transaction(&hex::decode(tx_id)?)? {
+            Some(tx) => tx,
+            None => {
+                return Err(Error::CustomError(
+                    "Transaction wasn't sent from this node!".to_owned(),
+                ))
+            }
+        };
+
+        let utxo_set = UTXOSet::new(chain.clone());
+        let replacement = tx.bump_fee(&open_wallets()?, fee, &utxo_set, None)?;
+        network::submit_transaction(node_id, &replacement, &network_config()?).await?;
+        chain.save_wallet_transaction(&replacement)?;
+
+        println!(
+            "Replace transaction {tx_id} with {:?} paying {fee} fee",
+            hex::encode(&replacement.id)
+        );
 
         println!();
         Ok(())
     }
 
     fn get_balance(&self, node_id: &str, address: &str) -> Result<()> {
-        let mut wallets = Wallets::create_wallets()?;
-        wallets.get_wallet(address)?;
+        let wallets = Wallets::create_wallets()?;
+        wallets.check_address(address)?;
 
         let chain = BlockChain::continue_blockchain(node_id)?;
         let utxo_set = UTXOSet::new(chain);
-        let balance = utxo_set.get_balance(address)?;
+        let (balance, immature) = utxo_set.get_balance(address)?;
 
         println!("Balance of {address}: {balance}");
+        if immature > 0 {
+            println!("Immature rewards: {immature}");
+        }
+
+        println!();
+        Ok(())
+    }
+
+    fn get_history(&self, node_id: &str, address: &str) -> Result<()> {
+        let wallets = Wallets::create_wallets()?;
+        wallets.check_address(address)?;
+
+        let chain = BlockChain::continue_blockchain(node_id)?;
+        let utxo_set = UTXOSet::new(chain);
+
+        println!("History of {address}");
+        for entry in utxo_set.history(address)? {
+            println!(
+                "{} | tx {:?} | block {:?}",
+                entry.height,
+                hex::encode(&entry.tx_id),
+                hex::encode(&entry.block_hash)
+            );
+        }
+
+        println!();
+        Ok(())
+    }
+
+    fn get_supply(&self, node_id: &str, height: Option<u64>) -> Result<()> {
+        let height = match height {
+            Some(height) => height,
+            None => BlockChain::continue_blockchain(node_id)?.get_best_height()?,
+        };
+
+        println!("Height: {height}");
+        println!("Block subsidy: {}", blockchain::block_subsidy(height));
+        println!("Circulating supply: {}", blockchain::supply_at(height));
+        println!("Max supply: {}", blockchain::MAX_MONEY);
 
         println!();
         Ok(())
@@ -149,12 +317,84 @@ impl CommandLine {
         Ok(())
     }
 
-    fn create_wallet(&self) -> Result<()> {
-        let mut wallets = Wallets::create_wallets()?;
-        let address = wallets.add_wallet()?;
+    fn create_wallet(&self, account: Option<u32>) -> Result<()> {
+        let mut wallets = open_wallets()?;
+        let address = wallets.add_wallet(account)?;
         wallets.save_file()?;
 
         println!("Wallet: {}", address);
+        if let Some(path) = wallets.key_path(&address) {
+            println!("Path: {path}");
+        }
+
+        println!();
+        Ok(())
+    }
+
+    fn create_hd_wallet(&self, words: usize) -> Result<()> {
+        let mut wallets = open_wallets()?;
+        let mnemonic = wallets.create_hd_wallet(words)?;
+        let address = wallets.add_wallet(None)?;
+        wallets.save_file()?;
+
+        println!("Mnemonic: {mnemonic}");
+        println!("Write these words down, they restore every address derived from now on");
+        println!("Wallet: {address}");
+
+        println!();
+        Ok(())
+    }
+
+    fn restore_wallet(&self, node_id: &str, mnemonic: &str) -> Result<()> {
+        let mut wallets = open_wallets()?;
+        wallets.restore_hd_wallet(mnemonic)?;
+        println!("HD seed restored");
+
+        self.rescan(node_id, wallets)
+    }
+
+    fn rescan_wallet(&self, node_id: &str) -> Result<()> {
+        self.rescan(node_id, open_wallets()?)
+    }
+
+    //? An address is used once the chain has any transaction paying to or spending from it
+    fn rescan(&self, node_id: &str, mut wallets: Wallets) -> Result<()> {
+        let chain = BlockChain::continue_blockchain(node_id)?;
+        let utxo_set = UTXOSet::new(chain);
+
+        let found = wallets.rescan(|address| Ok(!utxo_set.history(address)?.is_empty()))?;
+        wallets.save_file()?;
+
+        println!("Rescan found {} new addresses", found.len());
+        for address in found {
+            println!("{address}");
+        }
+
+        println!();
+        Ok(())
+    }
+
+    fn encrypt_wallet(&self) -> Result<()> {
+        let mut wallets = Wallets::create_wallets()?;
+        let passphrase = new_passphrase("WALLET_PASSPHRASE")?;
+        wallets.encrypt(&passphrase)?;
+
+        println!("Wallet encrypted, older backups of it are still in the clear");
+
+        println!();
+        Ok(())
+    }
+
+    fn change_passphrase(&self) -> Result<()> {
+        let mut wallets = Wallets::create_wallets()?;
+        let old_passphrase = match env::var("WALLET_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => rpassword::prompt_password("Current passphrase: ")?,
+        };
+        let passphrase = new_passphrase("NEW_WALLET_PASSPHRASE")?;
+        wallets.change_passphrase(&old_passphrase, &passphrase)?;
+
+        println!("Passphrase changed");
 
         println!();
         Ok(())
@@ -166,7 +406,14 @@ impl CommandLine {
 
         println!("List addresses");
         for address in addresses {
-            println!("{address}");
+            match wallets.key_path(&address) {
+                Some(path) => println!("{address} {path}"),
+                None => println!("{address}"),
+            }
+            //? Legacy keys also receive on their hash address
+            if let Some(hash_address) = wallets.hash_address(&address) {
+                println!("  hash address: {hash_address}");
+            }
         }
 
         println!();
@@ -185,35 +432,183 @@ impl CommandLine {
         Ok(())
     }
 
+    fn reindex_transactions(&self, node_id: &str) -> Result<()> {
+        let chain = BlockChain::continue_blockchain(node_id)?;
+        let count = chain.reindex_transactions()?;
+        println!("Index {count} transactions");
+
+        println!();
+        Ok(())
+    }
+
+    fn get_transaction(&self, node_id: &str, tx_id: &str) -> Result<()> {
+        let chain = BlockChain::continue_blockchain(node_id)?;
+
+        match chain.get_transaction(&hex::decode(tx_id)?)? {
+            Some(info) => {
+                println!("Block: {:?}", hex::encode(&info.block_hash));
+                println!("Height: {}", info.block_height);
+                println!("Confirmations: {}", info.confirmations);
+                println!("Transaction:");
+                println!("{}", info.transaction);
+            }
+            None => println!("Transaction {tx_id} not found"),
+        }
+
+        println!();
+        Ok(())
+    }
+
     async fn start_node(&self, node_id: &str, miner_address: &str) -> Result<()> {
         println!("Starting node {node_id}");
 
-        let mut wallets = Wallets::create_wallets()?;
+        let wallets = Wallets::create_wallets()?;
 
         if !miner_address.is_empty() {
-            if wallets.get_wallet(miner_address)?.is_some() {
+            if wallets.check_address(miner_address).is_ok() {
                 println!("Mining is on. Address to receive rewards: {miner_address}");
             } else {
                 println!("Wrong miner address!");
             }
         }
 
-        network::start_server(node_id, miner_address).await?;
+        network::start_server(node_id, miner_address, network_config()?).await?;
+
+        println!();
+        Ok(())
+    }
+
+    //? Each param is read as JSON, anything else is passed as a string
+    async fn rpc(&self, node_id: &str, method: &str, params: &[String]) -> Result<()> {
+        let params = params
+            .iter()
+            .map(|param| {
+                serde_json::from_str(param)
+                    .unwrap_or_else(|_| serde_json::Value::String(param.clone()))
+            })
+            .collect();
+
+        let result = network::rpc_call(node_id, &network_config()?, method, params).await?;
+        println!("{}", serde_json::to_string_pretty(&result)?);
 
         println!();
         Ok(())
     }
 }
 
+//? SEEDS is a comma separated list of peers, empty to start without any
+//? Commands that sign or add keys unlock an encrypted wallet with WALLET_PASSPHRASE
+fn open_wallets() -> Result<Wallets> {
+    let mut wallets = Wallets::create_wallets()?;
+    if wallets.is_locked() {
+        if let Ok(passphrase) = env::var("WALLET_PASSPHRASE") {
+            wallets.unlock(&passphrase)?;
+        }
+    }
+    Ok(wallets)
+}
+
+//? Passphrases never come from the arguments, other users and the shell history would see them
+//? The env variable is taken as is, a prompt asks twice without echoing
+fn new_passphrase(variable: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(variable) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if rpassword::prompt_password("Repeat the new passphrase: ")? != passphrase {
+        return Err(Error::CustomError("Passphrases don't match!".to_owned()));
+    }
+    Ok(passphrase)
+}
+
+fn network_config() -> Result<network::NetworkConfig> {
+    let mut config = network::NetworkConfig::default();
+
+    if let Ok(max_message_size) = env::var("MAX_MESSAGE_SIZE") {
+        config.max_message_size = match max_message_size.parse::<usize>() {
+            Ok(max_message_size) => max_message_size,
+            Err(_) => {
+                return Err(Error::CustomError(
+                    "MAX_MESSAGE_SIZE must be an integer".to_owned(),
+                ))
+            }
+        };
+    }
+
+    if let Ok(max_mempool_size) = env::var("MAX_MEMPOOL_SIZE") {
+        config.max_mempool_size = match max_mempool_size.parse::<usize>() {
+            Ok(max_mempool_size) => max_mempool_size,
+            Err(_) => {
+                return Err(Error::CustomError(
+                    "MAX_MEMPOOL_SIZE must be an integer".to_owned(),
+                ))
+            }
+        };
+    }
+
+    if let Ok(rpc_port) = env::var("RPC_PORT") {
+        config.rpc_port = match rpc_port.parse::<u16>() {
+            Ok(rpc_port) => Some(rpc_port),
+            Err(_) => return Err(Error::CustomError("RPC_PORT must be a port".to_owned())),
+        };
+    }
+
+    config.rpc_credentials = match (env::var("RPC_USER"), env::var("RPC_PASSWORD")) {
+        (Ok(user), Ok(password)) => Some((user, password)),
+        (Err(_), Err(_)) => None,
+        _ => {
+            return Err(Error::CustomError(
+                "RPC_USER and RPC_PASSWORD must be set together".to_owned(),
+            ))
+        }
+    };
+
+    if let Ok(events_port) = env::var("EVENTS_PORT") {
+        config.events_port = match events_port.parse::<u16>() {
+            Ok(events_port) => Some(events_port),
+            Err(_) => return Err(Error::CustomError("EVENTS_PORT must be a port".to_owned())),
+        };
+    }
+
+    if let Ok(rest) = env::var("REST") {
+        config.rest = match rest.parse::<bool>() {
+            Ok(rest) => rest,
+            Err(_) => return Err(Error::CustomError("REST must be a boolean".to_owned())),
+        };
+    }
+
+    if let Ok(seeds) = env::var("SEEDS") {
+        config.seeds = seeds
+            .split(',')
+            .map(|seed| seed.trim().to_owned())
+            .filter(|seed| !seed.is_empty())
+            .collect();
+    }
+
+    Ok(config)
+}
+
 enum Command {
     CreateBlockchain,
     SendCoin,
+    BumpFee,
     GetBalance,
+    GetHistory,
     PrintBlockchain,
+    GetSupply,
     CreateWallet,
+    CreateHdWallet,
+    RestoreWallet,
+    RescanWallet,
+    EncryptWallet,
+    ChangePassphrase,
     ListAddresses,
     ReindexUTXO,
+    ReindexTransactions,
+    GetTransaction,
     StartNode,
+    Rpc,
 }
 
 impl FromStr for Command {
@@ -222,12 +617,23 @@ impl FromStr for Command {
         match s {
             "create_blockchain" => Ok(Command::CreateBlockchain),
             "send_coin" => Ok(Command::SendCoin),
+            "bump_fee" => Ok(Command::BumpFee),
             "get_balance" => Ok(Command::GetBalance),
+            "get_history" => Ok(Command::GetHistory),
             "print_blockchain" => Ok(Command::PrintBlockchain),
+            "get_supply" => Ok(Command::GetSupply),
             "create_wallet" => Ok(Command::CreateWallet),
+            "create_hd_wallet" => Ok(Command::CreateHdWallet),
+            "restore_wallet" => Ok(Command::RestoreWallet),
+            "rescan_wallet" => Ok(Command::RescanWallet),
+            "encrypt_wallet" => Ok(Command::EncryptWallet),
+            "change_passphrase" => Ok(Command::ChangePassphrase),
             "list_addresses" => Ok(Command::ListAddresses),
             "reindex_utxo" => Ok(Command::ReindexUTXO),
+            "reindex_transactions" => Ok(Command::ReindexTransactions),
+            "get_transaction" => Ok(Command::GetTransaction),
             "start_node" => Ok(Command::StartNode),
+            "rpc" => Ok(Command::Rpc),
             _ => {
                 println!("Invalid command!\n");
                 print_usage_and_exit();
@@ -242,12 +648,23 @@ impl fmt::Display for Command {
         match self {
             Command::CreateBlockchain => write!(f, "create_blockchain"),
             Command::SendCoin => write!(f, "send_coin"),
+            Command::BumpFee => write!(f, "bump_fee"),
             Command::GetBalance => write!(f, "get_balance"),
+            Command::GetHistory => write!(f, "get_history"),
             Command::PrintBlockchain => write!(f, "print_blockchain"),
+            Command::GetSupply => write!(f, "get_supply"),
             Command::CreateWallet => write!(f, "create_wallet"),
+            Command::CreateHdWallet => write!(f, "create_hd_wallet"),
+            Command::RestoreWallet => write!(f, "restore_wallet"),
+            Command::RescanWallet => write!(f, "rescan_wallet"),
+            Command::EncryptWallet => write!(f, "encrypt_wallet"),
+            Command::ChangePassphrase => write!(f, "change_passphrase"),
             Command::ListAddresses => write!(f, "list_addresses"),
             Command::ReindexUTXO => write!(f, "reindex_utxo"),
+            Command::ReindexTransactions => write!(f, "reindex_transactions"),
+            Command::GetTransaction => write!(f, "get_transaction"),
             Command::StartNode => write!(f, "start_node"),
+            Command::Rpc => write!(f, "rpc"),
         }
     }
 }
@@ -259,23 +676,70 @@ fn print_usage_and_exit() {
         Command::CreateBlockchain
     );
     println!(
-        " {} FROM (str) - TO (str) - AMOUNT (int) - MINE_NOW (bool) | send amount of coins",
+        " {} FROM (str) - TO (str) - AMOUNT (int) - MINE_NOW (bool) - FEE (int, default 0) - REPLACEABLE (bool, default true) | send amount of coins",
         Command::SendCoin
     );
+    println!(
+        " {} TXID (hex) - NEW_FEE (int) | replace an unconfirmed transaction sent from this node with a higher fee",
+        Command::BumpFee
+    );
     println!(
         " {} ADDRESS | get balance for the ADDRESS",
         Command::GetBalance
     );
+    println!(
+        " {} ADDRESS | list the transactions touching the ADDRESS",
+        Command::GetHistory
+    );
     println!(
         " {} | show all the blocks in the blockchain",
         Command::PrintBlockchain
     );
-    println!(" {} | create a new wallet", Command::CreateWallet);
+    println!(
+        " {} HEIGHT (int, default tip) | show the block subsidy and the coins issued up to HEIGHT",
+        Command::GetSupply
+    );
+    println!(
+        " {} ACCOUNT (int, default 0) | create a new wallet, derived from the HD seed when there is one",
+        Command::CreateWallet
+    );
+    println!(
+        " {} WORDS (12 or 24, default 12) | create the HD seed and show its mnemonic",
+        Command::CreateHdWallet
+    );
+    println!(
+        " {} MNEMONIC (words) | restore the HD seed and rediscover its used addresses",
+        Command::RestoreWallet
+    );
+    println!(
+        " {} | rediscover the used addresses of the HD seed from the chain",
+        Command::RescanWallet
+    );
+    println!(
+        " {} | encrypt the wallet with a prompted passphrase or WALLET_PASSPHRASE env, which then unlocks it for a command, the walletpassphrase RPC for a running node",
+        Command::EncryptWallet
+    );
+    println!(
+        " {} | change the passphrase of an encrypted wallet, prompted or taken from WALLET_PASSPHRASE and NEW_WALLET_PASSPHRASE env",
+        Command::ChangePassphrase
+    );
     println!(" {} | list all the addresses", Command::ListAddresses);
     println!(" {} | rebuild the UTXO set", Command::ReindexUTXO);
+    println!(
+        " {} | build the transaction index, kept up to date afterwards",
+        Command::ReindexTransactions
+    );
+    println!(
+        " {} TXID (hex) | show a transaction with its block and confirmations",
+        Command::GetTransaction
+    );
     println!(
         " {} - MINER (str) | start anode with id specified in NODE_ID env and enable miner option",
         Command::StartNode
     );
+    println!(
+        " {} METHOD - PARAMS... (json or str) | call the running node, RPC_PORT, RPC_USER and RPC_PASSWORD env override the defaults",
+        Command::Rpc
+    );
     process::exit(0);
 }
@@ -48,6 +48,26 @@ pub enum Error {
         #[from]
         source: std::io::Error,
     },
+    #[error("Json error")]
+    JsonError {
+        #[from]
+        source: serde_json::Error,
+    },
+    #[error("Mnemonic error: {source}")]
+    MnemonicError {
+        #[from]
+        source: bip39::Error,
+    },
+    #[error("Block rejected: {source}")]
+    BlockRejected {
+        #[from]
+        source: blockchain::BlockRejection,
+    },
+    #[error("Transaction rejected: {source}")]
+    TransactionRejected {
+        #[from]
+        source: blockchain::MempoolRejection,
+    },
     #[error("Custom error")]
     CustomError(String),
 }
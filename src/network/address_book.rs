@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, rename, File, OpenOptions},
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::SystemTime,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::Result;
+
+static PEERS_PATH: &str = "./tmp/peers";
+//? Addresses not heard of for a week are forgotten
+static ADDRESS_HORIZON: u64 = 7 * 24 * 60 * 60;
+//? Past it the address seen the longest ago makes room for the new one
+static MAX_ADDRESSES: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct NetAddress {
+    pub(crate) address: String,
+    pub(crate) last_seen: u64,
+}
+
+pub(crate) struct AddressBook {
+    file: String,
+    addresses: HashMap<String, u64>,
+}
+
+impl AddressBook {
+    pub(crate) fn load(node_id: &str) -> Result<Self> {
+        let mut book = AddressBook {
+            file: format!("{}/peers_{}.data", PEERS_PATH, node_id),
+            addresses: HashMap::new(),
+        };
+
+        if Path::new(&book.file).exists() {
+            let mut file = File::open(&book.file)?;
+            let mut buffer = vec![];
+            file.read_to_end(&mut buffer)?;
+            //? A damaged file costs the known peers, not the node start
+            match bincode::deserialize(&buffer) {
+                Ok(addresses) => book.addresses = addresses,
+                Err(err) => println!("Address book file is unreadable, starting empty: {:?}", err),
+            }
+        } else {
+            create_dir_all(PEERS_PATH)?;
+        }
+
+        let horizon = now().saturating_sub(ADDRESS_HORIZON);
+        book.addresses.retain(|_, last_seen| *last_seen >= horizon);
+
+        Ok(book)
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        //? Written aside then renamed over, a crash mid-write leaves the previous file whole
+        let temporary = format!("{}.tmp", self.file);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary)?;
+
+        let encoded = bincode::serialize(&self.addresses)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        rename(&temporary, &self.file)?;
+        Ok(())
+    }
+
+    //? Returns whether the address was unknown, gossiped times from the future are capped to now
+    //? Addresses nobody can dial are dropped, a remote peer's loopback would point back at us
+    pub(crate) fn add(&mut self, net_address: &NetAddress, source: IpAddr) -> bool {
+        let address = match net_address.address.parse::<SocketAddr>() {
+            Ok(address) => address,
+            Err(_) => return false,
+        };
+        if address.ip().is_unspecified()
+            || address.port() == 0
+            || (address.ip().is_loopback() && !source.is_loopback())
+        {
+            return false;
+        }
+
+        let last_seen = net_address.last_seen.min(now());
+        if last_seen < now().saturating_sub(ADDRESS_HORIZON) {
+            return false;
+        }
+
+        match self.addresses.get_mut(&net_address.address) {
+            Some(known) => {
+                *known = (*known).max(last_seen);
+                false
+            }
+            None => self.insert(net_address.address.clone(), last_seen),
+        }
+    }
+
+    pub(crate) fn mark_seen(&mut self, address: &str) {
+        match self.addresses.get_mut(address) {
+            Some(known) => *known = now(),
+            None => {
+                self.insert(address.to_owned(), now());
+            }
+        }
+    }
+
+    //? A full book only takes an address seen more recently than its oldest one
+    fn insert(&mut self, address: String, last_seen: u64) -> bool {
+        if self.addresses.len() >= MAX_ADDRESSES {
+            let oldest = self
+                .addresses
+                .iter()
+                .min_by_key(|(_, last_seen)| **last_seen)
+                .map(|(address, last_seen)| (address.clone(), *last_seen));
+            match oldest {
+                Some((oldest, oldest_seen)) if oldest_seen < last_seen => {
+                    self.addresses.remove(&oldest);
+                }
+                _ => return false,
+            }
+        }
+
+        self.addresses.insert(address, last_seen);
+        true
+    }
+
+    //? Most recently seen first
+    pub(crate) fn addresses(&self, limit: usize) -> Vec<NetAddress> {
+        let mut addresses: Vec<NetAddress> = self
+            .addresses
+            .iter()
+            .map(|(address, last_seen)| NetAddress {
+                address: address.clone(),
+                last_seen: *last_seen,
+            })
+            .collect();
+        addresses.sort_by_key(|net_address| std::cmp::Reverse(net_address.last_seen));
+        addresses.truncate(limit);
+        addresses
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time is earlier than Unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn book() -> AddressBook {
+        AddressBook {
+            file: String::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    fn net_address(address: &str, last_seen: u64) -> NetAddress {
+        NetAddress {
+            address: address.to_owned(),
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn rejects_addresses_nobody_can_dial() {
+        let mut book = book();
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(!book.add(&net_address("not an address", now()), remote));
+        assert!(!book.add(&net_address("0.0.0.0:3000", now()), remote));
+        assert!(!book.add(&net_address("10.0.0.2:0", now()), remote));
+        assert!(!book.add(&net_address("127.0.0.1:3000", now()), remote));
+        assert!(book.add(
+            &net_address("127.0.0.1:3000", now()),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        ));
+        assert!(book.add(&net_address("10.0.0.2:3000", now()), remote));
+        assert_eq!(book.addresses.len(), 2);
+    }
+
+    #[test]
+    fn full_book_evicts_the_oldest_address() {
+        let mut book = book();
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = now() - MAX_ADDRESSES as u64 - 1;
+        for i in 0..MAX_ADDRESSES {
+            let address = format!("10.1.{}.{}:3000", i / 256, i % 256);
+            assert!(book.add(&net_address(&address, start + i as u64 + 1), remote));
+        }
+
+        assert!(!book.add(&net_address("10.2.0.0:3000", start), remote));
+        assert!(book.add(&net_address("10.2.0.1:3000", now()), remote));
+        assert_eq!(book.addresses.len(), MAX_ADDRESSES);
+        assert!(!book.addresses.contains_key("10.1.0.0:3000"));
+        assert!(book.addresses.contains_key("10.2.0.1:3000"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::{Arc, RwLock};
+
+use serde_derive::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{blockchain, wallet, Result};
+
+use super::Network;
+
+//? Events a slow subscriber may fall behind by before it is told it missed some
+pub(crate) static EVENT_BUFFER: usize = 1000;
+
+//? One JSON object per line, unconfirmed payments have no height
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    BlockConnected {
+        hash: String,
+        height: u64,
+        transactions: usize,
+    },
+    BlockDisconnected {
+        hash: String,
+        height: u64,
+    },
+    TransactionAdded {
+        txid: String,
+        fee: u64,
+    },
+    TransactionRemoved {
+        txid: String,
+        reason: blockchain::RemovalReason,
+    },
+    AddressReceived {
+        address: String,
+        txid: String,
+        vout: usize,
+        value: u64,
+        height: Option<u64>,
+    },
+    Lagged {
+        missed: u64,
+    },
+}
+
+//? Blocks taken off the tip come first, then the new ones and what they paid, then the memory pool changes
+pub(crate) fn publish_update(network: &mut Network, update: &blockchain::ChainUpdate) {
+    for block in update.disconnected.iter() {
+        publish(
+            network,
+            Event::BlockDisconnected {
+                hash: hex::encode(&block.hash),
+                height: block.height,
+            },
+        );
+    }
+    for block in update.connected.iter() {
+        publish(
+            network,
+            Event::BlockConnected {
+                hash: hex::encode(&block.hash),
+                height: block.height,
+                transactions: block.transactions.len(),
+            },
+        );
+        for tx in block.transactions.iter() {
+            publish_payments(network, tx, Some(block.height));
+        }
+    }
+
+    publish_mempool(network);
+}
+
+pub(crate) fn publish_mempool(network: &mut Network) {
+    for change in network.memory_pool.take_changes() {
+        match change {
+            blockchain::MempoolChange::Added { transaction, fee } => {
+                publish(
+                    network,
+                    Event::TransactionAdded {
+                        txid: hex::encode(&transaction.id),
+                        fee,
+                    },
+                );
+                publish_payments(network, &transaction, None);
+            }
+            blockchain::MempoolChange::Removed { tx_id, reason } => publish(
+                network,
+                Event::TransactionRemoved {
+                    txid: hex::encode(&tx_id),
+                    reason,
+                },
+            ),
+        }
+    }
+}
+
+fn publish_payments(network: &Network, tx: &blockchain::Transaction, height: Option<u64>) {
+    for (vout, output) in tx.outputs.iter().enumerate() {
+        publish(
+            network,
+            Event::AddressReceived {
+                address: wallet::address_from_public_key_hash(&output.public_key_hash),
+                txid: hex::encode(&tx.id),
+                vout,
+                value: output.value,
+                height,
+            },
+        );
+    }
+}
+
+//? Nobody listening isn't an error
+fn publish(network: &Network, event: Event) {
+    let _ = network.events.send(event);
+}
+
+pub(crate) async fn start_events(address: &str, network: Arc<RwLock<Network>>) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    println!("Event stream on {}", address);
+
+    tokio::spawn(async move {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    println!("Event subscriber accept failed: {:?}", err);
+                    continue;
+                }
+            };
+
+            let receiver = { network.read().unwrap().events.subscribe() };
+            tokio::spawn(async move {
+                if let Err(err) = stream_events(socket, receiver).await {
+                    println!("Event subscriber dropped: {:?}", err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+//? Runs until the subscriber disconnects, a lagging one is told how many events it missed and carries on
+async fn stream_events(
+    mut socket: TcpStream,
+    mut receiver: broadcast::Receiver<Event>,
+) -> Result<()> {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => Event::Lagged { missed },
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        socket.write_all(&line).await?;
+    }
+}
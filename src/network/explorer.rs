@@ -0,0 +1,536 @@
+use std::sync::{Arc, RwLock};
+
+use serde_json::{json, Value};
+
+use crate::{blockchain, wallet, Error, Result};
+
+use super::{
+    http::{HttpRequest, HttpResponse},
+    rpc::{block_json, error_message, find_transaction_json},
+    Network,
+};
+
+static PAGE_SIZE: u64 = 20;
+
+//? JSON under /block, /tx, /address and /mempool, the same pages in HTML under /explorer
+pub(crate) fn respond(
+    request: &HttpRequest,
+    network: &Arc<RwLock<Network>>,
+    chain: &Arc<RwLock<blockchain::BlockChain>>,
+) -> HttpResponse {
+    let chain = { chain.read().unwrap().clone() };
+
+    let (path, query) = match request.path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.path.as_str(), ""),
+    };
+    let page = query_param(query, "page")
+        .and_then(|page| page.parse::<u64>().ok())
+        .unwrap_or(0);
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let response = match segments.as_slice() {
+        ["block", "height", height] => match height.parse::<u64>() {
+            Ok(height) => block_at_height(&chain, height),
+            Err(_) => Ok(bad_request("Height must be an integer")),
+        },
+        ["block", hash] => hex::decode(hash)
+            .map_err(Error::from)
+            .and_then(|hash| block_response(&chain, &hash, false)),
+        ["tx", tx_id] => hex::decode(tx_id)
+            .map_err(Error::from)
+            .and_then(|tx_id| transaction_response(&chain, network, &tx_id, false)),
+        ["address", address] => address_response(&chain, network, address, page, false),
+        ["mempool"] => mempool_json(network).map(|mempool| HttpResponse::json(200, &mempool)),
+        [] | ["explorer"] => index_page(&chain, network, page),
+        ["explorer", "block", hash] => hex::decode(hash)
+            .map_err(Error::from)
+            .and_then(|hash| block_response(&chain, &hash, true)),
+        ["explorer", "tx", tx_id] => hex::decode(tx_id)
+            .map_err(Error::from)
+            .and_then(|tx_id| transaction_response(&chain, network, &tx_id, true)),
+        ["explorer", "address", address] => address_response(&chain, network, address, page, true),
+        ["explorer", "mempool"] => mempool_page(network),
+        ["explorer", "search"] => search(
+            &chain,
+            network,
+            &query_param(query, "q").unwrap_or_default(),
+        ),
+        _ => Ok(not_found(false)),
+    };
+
+    match response {
+        Ok(response) => response,
+        Err(err @ (Error::HexError { .. } | Error::Base58Error { .. })) => {
+            bad_request(&error_message(&err))
+        }
+        Err(err) => HttpResponse::text(500, &error_message(&err)),
+    }
+}
+
+fn block_response(chain: &blockchain::BlockChain, hash: &[u8], html: bool) -> Result<HttpResponse> {
+    let block = match chain.get_block(hash)? {
+        Some(block) => block_json(&block, chain)?,
+        None => return Ok(not_found(html)),
+    };
+
+    if !html {
+        return Ok(HttpResponse::json(200, &block));
+    }
+
+    let mut body = format!(
+        "<h1>Block {}</h1><table>{}{}{}{}{}{}{}{}</table><h2>Transactions</h2><table><tr><th>Id</th><th>Outputs</th><th>Value</th></tr>",
+        block["height"],
+        row("Hash", &text(&block["hash"])),
+        row("Confirmations", &block["confirmations"].to_string()),
+        row("Previous", &block_link(&text(&block["previousblockhash"]))),
+        row("Merkle root", &text(&block["merkleroot"])),
+        row("Time", &block["time"].to_string()),
+        row("Bits", &block["bits"].to_string()),
+        row("Nonce", &block["nonce"].to_string()),
+        row("Version", &block["version"].to_string()),
+    );
+    for tx in values(&block["tx"]) {
+        let outputs = values(&tx["outputs"]);
+        let value: u64 = outputs
+            .iter()
+            .filter_map(|output| output["value"].as_u64())
+            .sum();
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            tx_link(&text(&tx["txid"])),
+            outputs.len(),
+            value
+        ));
+    }
+    body.push_str("</table>");
+
+    Ok(page("Block", &body))
+}
+
+fn transaction_response(
+    chain: &blockchain::BlockChain,
+    network: &Arc<RwLock<Network>>,
+    tx_id: &[u8],
+    html: bool,
+) -> Result<HttpResponse> {
+    let tx = match find_transaction_json(tx_id, chain, network)? {
+        Some(tx) => tx,
+        None => return Ok(not_found(html)),
+    };
+
+    if !html {
+        return Ok(HttpResponse::json(200, &tx));
+    }
+
+    let status = match tx.get("blockhash") {
+        Some(block_hash) => format!(
+            "{} confirmations in block {}",
+            tx["confirmations"],
+            block_link(&text(block_hash))
+        ),
+        None => format!("In the memory pool, paying {} fee", tx["fee"]),
+    };
+    let mut body = format!(
+        "<h1>Transaction</h1><table>{}{}{}{}{}</table><h2>Inputs</h2><table><tr><th>Spends</th><th>Public key</th><th>Sequence</th></tr>",
+        row("Id", &text(&tx["txid"])),
+        row("Status", &status),
+        row("Size", &tx["size"].to_string()),
+        row("Coinbase", &tx["coinbase"].to_string()),
+        row("Replaceable", &tx["replaceable"].to_string()),
+    );
+    for input in values(&tx["inputs"]) {
+        let spends = if tx["coinbase"] == json!(true) {
+            "New coins".to_owned()
+        } else {
+            format!("{}:{}", tx_link(&text(&input["txid"])), input["vout"])
+        };
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            spends,
+            escape(&text(&input["public_key"])),
+            input["sequence"]
+        ));
+    }
+    body.push_str(
+        "</table><h2>Outputs</h2><table><tr><th>Index</th><th>Address</th><th>Value</th></tr>",
+    );
+    for output in values(&tx["outputs"]) {
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            output["n"],
+            address_link(&text(&output["address"])),
+            output["value"]
+        ));
+    }
+    body.push_str("</table>");
+
+    Ok(page("Transaction", &body))
+}
+
+//? The history is paged newest first, the unconfirmed transactions aren't part of it
+fn address_response(
+    chain: &blockchain::BlockChain,
+    network: &Arc<RwLock<Network>>,
+    address: &str,
+    page_number: u64,
+    html: bool,
+) -> Result<HttpResponse> {
+    if !wallet::validate_address(address)? {
+        return Ok(bad_request("Address is invalid"));
+    }
+
+    let utxo_set = blockchain::UTXOSet::new(chain.clone());
+    let (balance, immature) = utxo_set.get_balance(address)?;
+    let history = utxo_set.history(address)?;
+    let count = history.len() as u64;
+    let transactions: Vec<Value> = history
+        .iter()
+        .rev()
+        .skip(page_number.saturating_mul(PAGE_SIZE) as usize)
+        .take(PAGE_SIZE as usize)
+        .map(|entry| {
+            json!({
+                "txid": hex::encode(&entry.tx_id),
+                "blockhash": hex::encode(&entry.block_hash),
+                "height": entry.height,
+            })
+        })
+        .collect();
+    let pending = pending_for(network, address)?;
+
+    if !html {
+        return Ok(HttpResponse::json(
+            200,
+            &json!({
+                "address": address,
+                "balance": balance,
+                "immature": immature,
+                "transactions_count": count,
+                "page": page_number,
+                "transactions": transactions,
+                "pending": pending,
+            }),
+        ));
+    }
+
+    let mut body = format!(
+        "<h1>Address</h1><table>{}{}{}{}{}</table><h2>Transactions</h2><table><tr><th>Height</th><th>Id</th><th>Block</th></tr>",
+        row("Address", &escape(address)),
+        row("Balance", &balance.to_string()),
+        row("Immature", &immature.to_string()),
+        row("Transactions", &count.to_string()),
+        row(
+            "Unconfirmed",
+            &pending
+                .iter()
+                .map(|tx_id| tx_link(&text(tx_id)))
+                .collect::<Vec<String>>()
+                .join(" "),
+        ),
+    );
+    for tx in transactions.iter() {
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            tx["height"],
+            tx_link(&text(&tx["txid"])),
+            block_link(&text(&tx["blockhash"]))
+        ));
+    }
+    body.push_str("</table>");
+    body.push_str(&pagination(
+        &format!("/explorer/address/{}", escape(address)),
+        page_number,
+        count,
+    ));
+
+    Ok(page("Address", &body))
+}
+
+fn mempool_json(network: &Arc<RwLock<Network>>) -> Result<Value> {
+    let network = network.read().unwrap();
+
+    let mut transactions = vec![];
+    for tx in network.memory_pool.transactions() {
+        transactions.push(json!({
+            "txid": hex::encode(&tx.id),
+            "fee": network.memory_pool.fee(&tx.id),
+            "size": tx.size()?,
+        }));
+    }
+
+    Ok(json!({
+        "size": network.memory_pool.len(),
+        "bytes": network.memory_pool.size(),
+        "maxmempool": network.memory_pool.max_size(),
+        "transactions": transactions,
+    }))
+}
+
+//? Memory pool transactions paying to the address
+fn pending_for(network: &Arc<RwLock<Network>>, address: &str) -> Result<Vec<Value>> {
+    let public_key_hash = wallet::public_key_hash_from_address(address)?;
+    let network = network.read().unwrap();
+
+    Ok(network
+        .memory_pool
+        .transactions()
+        .iter()
+        .filter(|tx| {
+            tx.outputs
+                .iter()
+                .any(|output| output.public_key_hash == public_key_hash)
+        })
+        .map(|tx| json!(hex::encode(&tx.id)))
+        .collect())
+}
+
+fn index_page(
+    chain: &blockchain::BlockChain,
+    network: &Arc<RwLock<Network>>,
+    page_number: u64,
+) -> Result<HttpResponse> {
+    let best_height = chain.get_best_height()?;
+    let mempool_size = { network.read().unwrap().memory_pool.len() };
+
+    let mut body = format!(
+        "<h1>Blocks</h1><table>{}{}{}</table><table><tr><th>Height</th><th>Hash</th><th>Transactions</th><th>Time</th></tr>",
+        row("Height", &best_height.to_string()),
+        row("Supply", &blockchain::supply_at(best_height).to_string()),
+        row(
+            "Memory pool",
+            &format!("<a href=\"/explorer/mempool\">{} transactions</a>", mempool_size),
+        ),
+    );
+
+    //? Heights are listed from the tip down
+    let first = best_height.saturating_sub(page_number.saturating_mul(PAGE_SIZE));
+    if page_number.saturating_mul(PAGE_SIZE) <= best_height {
+        for height in (first.saturating_sub(PAGE_SIZE - 1)..=first).rev() {
+            let hash = chain.get_main_hash(height)?;
+            let block = match chain.get_block(&hash)? {
+                Some(block) => block,
+                None => continue,
+            };
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                height,
+                block_link(&hex::encode(&block.hash)),
+                block.transactions.len(),
+                block.header.timestamp
+            ));
+        }
+    }
+    body.push_str("</table>");
+    body.push_str(&pagination("/explorer", page_number, best_height + 1));
+
+    Ok(page("Blocks", &body))
+}
+
+fn mempool_page(network: &Arc<RwLock<Network>>) -> Result<HttpResponse> {
+    let mempool = mempool_json(network)?;
+
+    let mut body = format!(
+        "<h1>Memory pool</h1><table>{}{}</table><table><tr><th>Id</th><th>Fee</th><th>Size</th></tr>",
+        row("Transactions", &mempool["size"].to_string()),
+        row(
+            "Bytes",
+            &format!("{} of {}", mempool["bytes"], mempool["maxmempool"]),
+        ),
+    );
+    for tx in values(&mempool["transactions"]) {
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            tx_link(&text(&tx["txid"])),
+            tx["fee"],
+            tx["size"]
+        ));
+    }
+    body.push_str("</table>");
+
+    Ok(page("Memory pool", &body))
+}
+
+//? A height, a block hash, a transaction id or an address
+fn search(
+    chain: &blockchain::BlockChain,
+    network: &Arc<RwLock<Network>>,
+    query: &str,
+) -> Result<HttpResponse> {
+    let query = query.trim();
+
+    let location = if let Ok(height) = query.parse::<u64>() {
+        match chain.get_main_hash(height) {
+            Ok(hash) => Some(format!("/explorer/block/{}", hex::encode(hash))),
+            Err(_) => None,
+        }
+    } else if let Ok(id) = hex::decode(query) {
+        if chain.has_block(&id)? {
+            Some(format!("/explorer/block/{}", query))
+        } else if find_transaction_json(&id, chain, network)?.is_some() {
+            Some(format!("/explorer/tx/{}", query))
+        } else {
+            None
+        }
+    } else if !query.is_empty() && wallet::validate_address(query).unwrap_or(false) {
+        Some(format!("/explorer/address/{}", query))
+    } else {
+        None
+    };
+
+    match location {
+        Some(location) => {
+            let mut response = HttpResponse::text(302, "Found");
+            response.headers.push(("Location", location));
+            Ok(response)
+        }
+        None => Ok(not_found(true)),
+    }
+}
+
+fn block_at_height(chain: &blockchain::BlockChain, height: u64) -> Result<HttpResponse> {
+    if height > chain.get_best_height()? {
+        return Ok(not_found(false));
+    }
+    block_response(chain, &chain.get_main_hash(height)?, false)
+}
+
+fn page(title: &str, body: &str) -> HttpResponse {
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>body{{font-family:monospace;margin:2em}}table{{border-collapse:collapse;margin-bottom:1em}}td,th{{border:1px solid #ccc;padding:.2em .6em;text-align:left}}</style></head><body><nav><a href=\"/explorer\">Blocks</a> | <a href=\"/explorer/mempool\">Memory pool</a> | <form action=\"/explorer/search\" style=\"display:inline\"><input name=\"q\" size=\"70\" placeholder=\"Height, block hash, transaction id or address\"></form></nav>{}</body></html>",
+        escape(title),
+        body
+    );
+    HttpResponse::new(200, "text/html; charset=utf-8", html.into_bytes())
+}
+
+fn pagination(path: &str, page_number: u64, count: u64) -> String {
+    let pages = count.div_ceil(PAGE_SIZE).max(1);
+
+    //? The page comes from the query string, past the end it links back to the last one
+    let mut links = format!("Page {} of {}", page_number.saturating_add(1), pages);
+    if page_number > 0 {
+        links.push_str(&format!(
+            " | <a href=\"{}?page={}\">Newer</a>",
+            path,
+            (page_number - 1).min(pages - 1)
+        ));
+    }
+    if page_number.saturating_add(1) < pages {
+        links.push_str(&format!(
+            " | <a href=\"{}?page={}\">Older</a>",
+            path,
+            page_number + 1
+        ));
+    }
+    format!("<p>{}</p>", links)
+}
+
+fn row(name: &str, value: &str) -> String {
+    format!("<tr><th>{}</th><td>{}</td></tr>", name, value)
+}
+
+fn block_link(hash: &str) -> String {
+    if hash.is_empty() {
+        return "None".to_owned();
+    }
+    format!("<a href=\"/explorer/block/{0}\">{0}</a>", escape(hash))
+}
+
+fn tx_link(tx_id: &str) -> String {
+    format!("<a href=\"/explorer/tx/{0}\">{0}</a>", escape(tx_id))
+}
+
+fn address_link(address: &str) -> String {
+    format!("<a href=\"/explorer/address/{0}\">{0}</a>", escape(address))
+}
+
+fn not_found(html: bool) -> HttpResponse {
+    let mut response = if html {
+        page("Not found", "<h1>Not found</h1>")
+    } else {
+        HttpResponse::json(404, &json!({ "error": "Not found" }))
+    };
+    response.status = 404;
+    response
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::json(400, &json!({ "error": message }))
+}
+
+//? Keys and values are form encoded, a browser sends the search box with + for spaces and %XX escapes
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| decode_component(key) == name)
+        .map(|(_, value)| decode_component(value))
+}
+
+//? A malformed escape is kept as is rather than failing the request
+fn decode_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn text(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_owned()
+}
+
+fn values(value: &Value) -> Vec<Value> {
+    value.as_array().cloned().unwrap_or_default()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params_are_percent_decoded() {
+        let query = "page=2&q=+abc%20def%2B%3d&%71uoted=1";
+        assert_eq!(query_param(query, "page").as_deref(), Some("2"));
+        assert_eq!(query_param(query, "q").as_deref(), Some(" abc def+="));
+        assert_eq!(query_param(query, "quoted").as_deref(), Some("1"));
+        assert_eq!(query_param(query, "missing"), None);
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(decode_component("100%"), "100%");
+        assert_eq!(decode_component("%zz%4"), "%zz%4");
+        assert_eq!(decode_component("%e2%82%ac"), "\u{20ac}");
+        assert_eq!(decode_component("%ff"), "\u{fffd}");
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{Error, Result};
+
+static MAX_HEADER_LINES: usize = 100;
+static MAX_LINE_LENGTH: usize = 8 * 1024;
+static MAX_BODY_SIZE: usize = 1024 * 1024;
+
+//? Just enough HTTP/1.1 for local tools, one request per connection
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Vec<u8>,
+}
+
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) content_type: &'static str,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpRequest {
+    //? Header names are kept lowercase
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+}
+
+impl HttpResponse {
+    pub(crate) fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type,
+            headers: vec![],
+            body,
+        }
+    }
+
+    pub(crate) fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self::new(status, "application/json", body.to_string().into_bytes())
+    }
+
+    pub(crate) fn text(status: u16, body: &str) -> Self {
+        Self::new(
+            status,
+            "text/plain; charset=utf-8",
+            body.as_bytes().to_vec(),
+        )
+    }
+}
+
+pub(crate) async fn read_request<R: AsyncRead + Unpin>(reader: R) -> Result<HttpRequest> {
+    let mut reader = BufReader::new(reader);
+
+    let request_line = read_line(&mut reader).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => {
+            return Err(Error::CustomError(
+                "Malformed HTTP request line!".to_owned(),
+            ))
+        }
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line(&mut reader).await?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADER_LINES {
+            return Err(Error::CustomError("Too many HTTP headers!".to_owned()));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let length = match headers.get("content-length") {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) => length,
+            Err(_) => return Err(Error::CustomError("Invalid Content-Length!".to_owned())),
+        },
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Error::CustomError("HTTP body is too large!".to_owned()));
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub(crate) async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: HttpResponse,
+) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+//? Status code and body of a response read until the server closes the connection
+pub(crate) async fn read_response<R: AsyncRead + Unpin>(mut reader: R) -> Result<(u16, Vec<u8>)> {
+    let mut buffer = vec![];
+    reader.read_to_end(&mut buffer).await?;
+
+    let split = match buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(split) => split,
+        None => return Err(Error::CustomError("Malformed HTTP response!".to_owned())),
+    };
+    let head = String::from_utf8_lossy(&buffer[..split]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok());
+
+    match status {
+        Some(status) => Ok((status, buffer[split + 4..].to_vec())),
+        None => Err(Error::CustomError("Malformed HTTP status line!".to_owned())),
+    }
+}
+
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<String> {
+    let mut line = vec![];
+    let read = (&mut *reader)
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 || line.last() != Some(&b'\n') {
+        return Err(Error::CustomError(
+            "HTTP line is too long or cut!".to_owned(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        302 => "Found",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
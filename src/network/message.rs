@@ -0,0 +1,129 @@
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{Addr, Block, Command, GetData, GetHeaders, Headers, Inv, Transaction, Version};
+
+use crate::{Error, Result};
+
+//? Frame: magic (4) | command (12, zero padded) | payload length (4) | checksum (4) | payload
+
+static MAGIC: [u8; 4] = *b"RBLC";
+static COMMAND_LENGTH: usize = 12;
+static CHECKSUM_LENGTH: usize = 4;
+static HEADER_LENGTH: usize = 4 + COMMAND_LENGTH + 4 + CHECKSUM_LENGTH;
+pub(crate) static DEFAULT_MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+impl Command {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Addr(_) => "addr",
+            Command::Block(_) => "block",
+            Command::GetAddr => "getaddr",
+            Command::GetData(_) => "getdata",
+            Command::GetHeaders(_) => "getheaders",
+            Command::Headers(_) => "headers",
+            Command::Inv(_) => "inv",
+            Command::Transaction(_) => "tx",
+            Command::Verack => "verack",
+            Command::Version(_) => "version",
+        }
+    }
+
+    fn payload(&self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Command::Addr(addr) => bincode::serialize(addr)?,
+            Command::Block(block) => bincode::serialize(block)?,
+            Command::GetAddr | Command::Verack => vec![],
+            Command::GetData(get_data) => bincode::serialize(get_data)?,
+            Command::GetHeaders(get_headers) => bincode::serialize(get_headers)?,
+            Command::Headers(headers) => bincode::serialize(headers)?,
+            Command::Inv(inv) => bincode::serialize(inv)?,
+            Command::Transaction(transaction) => bincode::serialize(transaction)?,
+            Command::Version(version) => bincode::serialize(version)?,
+        })
+    }
+
+    fn from_payload(name: &str, payload: &[u8]) -> Result<Self> {
+        Ok(match name {
+            "addr" => Command::Addr(bincode::deserialize::<Addr>(payload)?),
+            "block" => Command::Block(bincode::deserialize::<Block>(payload)?),
+            "getaddr" => Command::GetAddr,
+            "getdata" => Command::GetData(bincode::deserialize::<GetData>(payload)?),
+            "getheaders" => Command::GetHeaders(bincode::deserialize::<GetHeaders>(payload)?),
+            "headers" => Command::Headers(bincode::deserialize::<Headers>(payload)?),
+            "inv" => Command::Inv(bincode::deserialize::<Inv>(payload)?),
+            "tx" => Command::Transaction(bincode::deserialize::<Transaction>(payload)?),
+            "verack" => Command::Verack,
+            "version" => Command::Version(bincode::deserialize::<Version>(payload)?),
+            _ => return Err(Error::CustomError(format!("Unknown command {:?}!", name))),
+        })
+    }
+}
+
+pub(crate) fn encode(command: &Command) -> Result<Vec<u8>> {
+    let payload = command.payload()?;
+
+    let mut name = [0u8; COMMAND_LENGTH];
+    name[..command.name().len()].copy_from_slice(command.name().as_bytes());
+
+    let mut frame = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&name);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+
+    Ok(frame)
+}
+
+//? Returns None when the peer closed the connection between two messages
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_message_size: usize,
+) -> Result<Option<Command>> {
+    let mut header = [0u8; HEADER_LENGTH];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    if header[..4] != MAGIC {
+        return Err(Error::CustomError("Message magic is invalid!".to_owned()));
+    }
+
+    let name: Vec<u8> = header[4..4 + COMMAND_LENGTH]
+        .iter()
+        .copied()
+        .take_while(|byte| *byte != 0)
+        .collect();
+    let name = String::from_utf8_lossy(&name).to_string();
+
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&header[4 + COMMAND_LENGTH..8 + COMMAND_LENGTH]);
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_message_size {
+        return Err(Error::CustomError(format!(
+            "Message {} of {} bytes exceeds the {} bytes limit!",
+            name, length, max_message_size
+        )));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+
+    if header[8 + COMMAND_LENGTH..] != checksum(&payload)[..] {
+        return Err(Error::CustomError(format!(
+            "Message {} checksum is invalid!",
+            name
+        )));
+    }
+
+    Ok(Some(Command::from_payload(&name, &payload)?))
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let first_hash = Sha256::digest(payload);
+    let second_hash = Sha256::digest(first_hash);
+    second_hash.into_iter().take(CHECKSUM_LENGTH).collect()
+}
@@ -1,271 +1,522 @@
 use std::{
     cmp::Ordering,
-    collections::HashMap,
-    io::Write,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
+use secp256k1::rand::{rngs::OsRng, seq::SliceRandom, RngCore};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
 
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{blockchain, Error, Result};
+use crate::{blockchain, wallet, Error, Result};
 
-const CENTRAL: &str = "127.0.0.1:3000";
-const VERSION: u32 = 1;
+use address_book::{AddressBook, NetAddress};
+use orphans::{
+    OrphanPool, MAX_ORPHAN_BLOCKS, MAX_ORPHAN_TRANSACTIONS, MAX_ORPHAN_TRANSACTION_SIZE,
+};
+use peer::{Peer, PeerVersion, NODE_NETWORK};
+use sync::BlockSync;
+
+mod address_book;
+mod events;
+mod explorer;
+mod http;
+mod message;
+mod orphans;
+mod peer;
+mod rpc;
+mod sync;
+
+pub use rpc::rpc_call;
+
+const DEFAULT_SEED: &str = "127.0.0.1:3000";
+const VERSION: u32 = 2;
+//? Version 2 introduced framing and the verack handshake
+const MIN_PEER_VERSION: u32 = 2;
+const MAX_OUTBOUND: usize = 8;
+const MAX_ADDR: usize = 1000;
+//? Only small announcements are relayed, answers to getaddr stop at the receiver
+const ADDR_RELAY_LIMIT: usize = 10;
+const ADDR_RELAY_FANOUT: usize = 2;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_HEADERS: usize = 2000;
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
 
 struct Network {
     node_address: String,
+    max_message_size: usize,
     mine_address: String,
-    known_nodes: Vec<String>,
-    blocks_in_transit: Vec<Vec<u8>>,
-    memory_pool: HashMap<String, blockchain::Transaction>,
+    nonce: u64,
+    seeds: Vec<String>,
+    address_book: AddressBook,
+    peers: HashMap<u64, Peer>,
+    next_peer_id: u64,
+    sync: BlockSync,
+    memory_pool: blockchain::Mempool,
+    orphan_blocks: OrphanPool<blockchain::Block>,
+    orphan_transactions: OrphanPool<blockchain::Transaction>,
+    mining: Option<Arc<AtomicBool>>,
+    events: tokio::sync::broadcast::Sender<events::Event>,
+    //? Set by the walletpassphrase RPC until its timeout
+    wallet_key: Option<(wallet::WalletKey, Instant)>,
+}
+
+impl Network {
+    fn ready_peers(&self) -> Vec<u64> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.is_ready())
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    fn is_connected(&self, address: &str) -> bool {
+        self.peers.values().any(|peer| {
+            peer.address.to_string() == address
+                || peer
+                    .version
+                    .as_ref()
+                    .is_some_and(|version| version.listen_address == address)
+        })
+    }
+
+    fn outbound_count(&self) -> usize {
+        self.peers.values().filter(|peer| !peer.inbound).count()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 enum Command {
+    Addr(Addr),
     Block(Block),
-    GetBlocks(GetBlocks),
+    GetAddr,
     GetData(GetData),
+    GetHeaders(GetHeaders),
+    Headers(Headers),
     Inv(Inv),
     Transaction(Transaction),
+    Verack,
     Version(Version),
 }
 
+#[derive(Serialize, Deserialize)]
+struct Addr {
+    addresses: Vec<NetAddress>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Block {
-    addr_from: String,
     block: Vec<u8>,
 }
 
+//? An empty stop hash asks for as many headers as allowed
 #[derive(Serialize, Deserialize)]
-struct GetBlocks {
-    addr_from: String,
+struct GetHeaders {
+    locator: Vec<Vec<u8>>,
+    stop: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Headers {
+    headers: Vec<blockchain::BlockHeader>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct GetData {
-    addr_from: String,
     data_type: String,
     id: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Inv {
-    addr_from: String,
     inv_type: String,
     items: Vec<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Transaction {
-    addr_from: String,
     tx: Vec<u8>,
 }
 
+//? addr_from is the address the sender listens on, empty when it doesn't accept connections
 #[derive(Serialize, Deserialize)]
 struct Version {
     version: u32,
+    services: u64,
+    nonce: u64,
     best_height: u64,
     addr_from: String,
 }
 
+async fn send_addr(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    addresses: Vec<NetAddress>,
+) -> Result<()> {
+    println!("send_addr");
+    let request = message::encode(&Command::Addr(Addr { addresses }))?;
+
+    send_data(network, peer_id, request)
+}
+
 async fn send_block(
     network: Arc<RwLock<Network>>,
-    address: &str,
+    peer_id: u64,
     block: &blockchain::Block,
 ) -> Result<()> {
     println!("send_block");
-    let node_address = { network.read().unwrap().node_address.clone() };
-
     let block = Block {
-        addr_from: node_address,
         block: bincode::serialize(block)?,
     };
-    let request = bincode::serialize(&Command::Block(block))?;
+    let request = message::encode(&Command::Block(block))?;
 
-    send_data(network, address, &request).await?;
-    Ok(())
+    send_data(network, peer_id, request)
 }
 
-async fn send_get_blocks(network: Arc<RwLock<Network>>, address: &str) -> Result<()> {
-    println!("send_get_blocks");
-    let node_address = { network.read().unwrap().node_address.clone() };
+async fn send_get_addr(network: Arc<RwLock<Network>>, peer_id: u64) -> Result<()> {
+    println!("send_get_addr");
+    let request = message::encode(&Command::GetAddr)?;
 
-    let get_blocks = GetBlocks {
-        addr_from: node_address,
-    };
+    send_data(network, peer_id, request)
+}
 
-    let request = bincode::serialize(&Command::GetBlocks(get_blocks))?;
+async fn send_get_headers(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    locator: Vec<Vec<u8>>,
+    stop: Vec<u8>,
+) -> Result<()> {
+    println!("send_get_headers");
+    let get_headers = GetHeaders { locator, stop };
+    let request = message::encode(&Command::GetHeaders(get_headers))?;
 
-    send_data(network, address, &request).await?;
-    Ok(())
+    send_data(network, peer_id, request)
+}
+
+async fn send_headers(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    headers: Vec<blockchain::BlockHeader>,
+) -> Result<()> {
+    println!("send_headers");
+    let request = message::encode(&Command::Headers(Headers { headers }))?;
+
+    send_data(network, peer_id, request)
 }
 
 async fn send_get_data(
     network: Arc<RwLock<Network>>,
-    address: &str,
+    peer_id: u64,
     data_type: String,
     id: Vec<u8>,
 ) -> Result<()> {
     println!("send_get_data");
-    let node_address = { network.read().unwrap().node_address.clone() };
-
-    let get_data = GetData {
-        addr_from: node_address,
-        data_type,
-        id,
-    };
+    let get_data = GetData { data_type, id };
+    let request = message::encode(&Command::GetData(get_data))?;
 
-    let request = bincode::serialize(&Command::GetData(get_data))?;
-
-    send_data(network, address, &request).await?;
-    Ok(())
+    send_data(network, peer_id, request)
 }
 
 async fn send_inv(
     network: Arc<RwLock<Network>>,
-    address: &str,
+    peer_id: u64,
     inv_type: String,
     items: Vec<Vec<u8>>,
 ) -> Result<()> {
     println!("send_inv");
-    let node_address = { network.read().unwrap().node_address.clone() };
-    let inv = Inv {
-        addr_from: node_address,
-        inv_type,
-        items,
-    };
+    let inv = Inv { inv_type, items };
+    let request = message::encode(&Command::Inv(inv))?;
 
-    let request = bincode::serialize(&Command::Inv(inv))?;
-    send_data(network, address, &request).await?;
-    Ok(())
+    send_data(network, peer_id, request)
 }
 
 async fn send_transaction(
     network: Arc<RwLock<Network>>,
-    address: &str,
+    peer_id: u64,
     tx: &blockchain::Transaction,
 ) -> Result<()> {
     println!("send_transaction");
-    let node_address = { network.read().unwrap().node_address.clone() };
-
     let transaction = Transaction {
-        addr_from: node_address,
         tx: bincode::serialize(tx)?,
     };
-    let request = bincode::serialize(&Command::Transaction(transaction))?;
+    let request = message::encode(&Command::Transaction(transaction))?;
 
-    send_data(network, address, &request).await?;
-    Ok(())
+    send_data(network, peer_id, request)
+}
+
+async fn send_verack(network: Arc<RwLock<Network>>, peer_id: u64) -> Result<()> {
+    println!("send_verack");
+    let request = message::encode(&Command::Verack)?;
+
+    send_data(network, peer_id, request)
 }
 
 async fn send_version(
     network: Arc<RwLock<Network>>,
-    address: &str,
+    peer_id: u64,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
     println!("send_version");
-    let node_address = { network.read().unwrap().node_address.clone() };
+    let (node_address, nonce) = {
+        let network = network.read().unwrap();
+        (network.node_address.clone(), network.nonce)
+    };
     let best_height = { chain.read().unwrap().get_best_height()? };
 
     let version = Version {
         version: VERSION,
-        addr_from: node_address,
+        services: NODE_NETWORK,
+        nonce,
         best_height,
+        addr_from: node_address,
     };
-    let request = bincode::serialize(&Command::Version(version))?;
+    let request = message::encode(&Command::Version(version))?;
+
+    send_data(network, peer_id, request)
+}
+
+fn send_data(network: Arc<RwLock<Network>>, peer_id: u64, request: Vec<u8>) -> Result<()> {
+    let network = network.read().unwrap();
+    match network.peers.get(&peer_id) {
+        Some(peer) if peer.send(request) => Ok(()),
+        _ => Err(Error::CustomError(format!(
+            "Peer {} is not connected!",
+            peer_id
+        ))),
+    }
+}
+
+async fn handle_block(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    block: Block,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
+    println!("handle_block");
+    let b: blockchain::Block = bincode::deserialize(&block.block)?;
 
-    send_data(network, address, &request).await?;
+    let solicited = { network.write().unwrap().sync.received(b.clone()) };
+    if !solicited {
+        return connect_block(network, Some(peer_id), b, chain).await;
+    }
+
+    loop {
+        let next = { network.write().unwrap().sync.next_ready() };
+        let next = match next {
+            Some(next) => next,
+            None => break,
+        };
+
+        //? Everything queued after an invalid body builds on it or can't be trusted, headers are fetched again later
+        if let Err(err) = connect_block(network.clone(), None, next, chain.clone()).await {
+            network.write().unwrap().sync.clear();
+            return Err(err);
+        }
+    }
+
+    request_blocks(network).await;
 
     Ok(())
 }
 
-async fn send_data(network: Arc<RwLock<Network>>, addr: &str, request: &[u8]) -> Result<()> {
-    let mut socket = match TcpStream::connect(addr).await {
-        Ok(socket) => socket,
-        Err(err) => {
-            {
-                network
-                    .write()
-                    .unwrap()
-                    .known_nodes
-                    .retain(|node| *node != addr);
+//? Orphans waiting on the block connect after it, each of them may free orphans of its own
+async fn connect_block(
+    network: Arc<RwLock<Network>>,
+    source: Option<u64>,
+    b: blockchain::Block,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
+    let mut parents = vec![b.hash.clone()];
+    process_block(network.clone(), source, b, chain.clone()).await?;
+
+    while let Some(parent) = parents.pop() {
+        let children = {
+            network
+                .write()
+                .unwrap()
+                .orphan_blocks
+                .take_children(&parent)
+        };
+        for (child, peer_id) in children {
+            let child_hash = child.hash.clone();
+            match process_block(network.clone(), Some(peer_id), child, chain.clone()).await {
+                Ok(()) => parents.push(child_hash),
+                Err(err) => println!(
+                    "Orphan block {} rejected: {:?}",
+                    hex::encode(&child_hash),
+                    err
+                ),
             }
-            return Err(Error::CustomError(format!(
-                "Node {} is not available: {}",
-                addr, err
-            )));
         }
-    };
-
-    socket.write_all(request).await?;
+    }
 
     Ok(())
 }
 
-async fn handle_block(
+//? A block sent by a peer without its parent waits as an orphan while the missing headers are requested
+async fn process_block(
     network: Arc<RwLock<Network>>,
-    block: Block,
+    source: Option<u64>,
+    b: blockchain::Block,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
-    println!("handle_block");
-    let b: blockchain::Block = bincode::deserialize(&block.block)?;
+    let parent_known = { chain.read().unwrap().has_block(&b.header.prevhash)? };
+    if let (false, Some(peer_id)) = (parent_known, source) {
+        let stop = b.header.prevhash.clone();
+        let added = {
+            network.write().unwrap().orphan_blocks.insert(
+                b.hash.clone(),
+                b.clone(),
+                vec![b.header.prevhash.clone()],
+                peer_id,
+            )
+        };
+        if added {
+            println!(
+                "Orphan block {:?}, requesting its ancestors",
+                hex::encode(&b.hash)
+            );
+            let locator = { chain.read().unwrap().block_locator()? };
+            send_get_headers(network, peer_id, locator, stop).await?;
+        }
+        return Ok(());
+    }
 
-    {
-        chain.write().unwrap().add_block(&b)?;
+    let known = { chain.read().unwrap().has_block(&b.hash)? };
+    let update = commit_block(&network, &chain, &b)?;
+    if !update.disconnected.is_empty() {
+        println!(
+            "Reorganize: {} blocks disconnected",
+            update.disconnected.len()
+        );
+    }
+
+    println!("Added block {:?}", hex::encode(&b.hash));
+
+    //? Orphan transactions may have been waiting on what the block confirmed
+    for connected in update.connected.iter() {
+        for tx in connected.transactions.iter() {
+            let children = {
+                network
+                    .write()
+                    .unwrap()
+                    .orphan_transactions
+                    .take_children(&tx.id)
+            };
+            for (child, peer_id) in children {
+                accept_transaction(network.clone(), peer_id, child, chain.clone()).await?;
+            }
+        }
     }
 
-    println!("Added block {:?}", hex::encode(b.hash));
+    //? Blocks fetched while catching up are old news for the other peers
+    let syncing = { !network.read().unwrap().sync.is_idle() };
+    if !known && !syncing {
+        relay_inv(network.clone(), source, "block", vec![b.hash.clone()]).await;
+    }
 
-    let blocks_in_transit = { network.read().unwrap().blocks_in_transit.clone() };
+    Ok(())
+}
 
-    if !blocks_in_transit.is_empty() {
-        let block_hash = blocks_in_transit[0].clone();
-        send_get_data(
-            network.clone(),
-            &block.addr_from,
-            "block".to_owned(),
-            block_hash,
-        )
-        .await?;
+//? Hands out pending bodies to the ready full nodes, a failing request is retried after its timeout
+async fn request_blocks(network: Arc<RwLock<Network>>) {
+    let requests = {
+        let mut network = network.write().unwrap();
+        let peers: Vec<u64> = network
+            .ready_peers()
+            .into_iter()
+            .filter(|peer_id| network.peers[peer_id].has_service(NODE_NETWORK))
+            .collect();
+        network.sync.schedule(&peers)
+    };
 
+    for (peer_id, block_hash) in requests {
+        if let Err(err) =
+            send_get_data(network.clone(), peer_id, "block".to_owned(), block_hash).await
         {
-            network.write().unwrap().blocks_in_transit.pop();
+            println!("Block request to peer {} failed: {:?}", peer_id, err);
         }
-    } else {
-        let chain_clone = { chain.read().unwrap().clone() };
-        let utxo_set = blockchain::UTXOSet::new(chain_clone);
-        utxo_set.reindex()?;
     }
+}
 
-    Ok(())
+async fn sync_blocks(network: Arc<RwLock<Network>>) {
+    loop {
+        tokio::time::sleep(SYNC_INTERVAL).await;
+
+        let expired = { network.write().unwrap().sync.expire() };
+        if expired > 0 {
+            println!("{} block requests timed out, retrying", expired);
+        }
+        request_blocks(network.clone()).await;
+    }
 }
 
-async fn handle_get_blocks(
+async fn handle_get_headers(
     network: Arc<RwLock<Network>>,
-    get_block: GetBlocks,
+    peer_id: u64,
+    get_headers: GetHeaders,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
-    println!("handle_get_blocks");
-    let block_hashes = { chain.read().unwrap().get_block_hashes()? };
-
-    send_inv(
-        network,
-        &get_block.addr_from,
-        "block".to_owned(),
-        block_hashes,
-    )
-    .await?;
+    println!("handle_get_headers");
+    let headers = {
+        chain.read().unwrap().locate_headers(
+            &get_headers.locator,
+            &get_headers.stop,
+            MAX_HEADERS,
+        )?
+    };
+
+    send_headers(network, peer_id, headers).await
+}
+
+async fn handle_headers(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    headers: Headers,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
+    println!("handle_headers: Receive {} headers", headers.headers.len());
+
+    if headers.headers.len() > MAX_HEADERS {
+        return Err(Error::CustomError(format!(
+            "Peer sent {} headers!",
+            headers.headers.len()
+        )));
+    }
+
+    let mut last_hash = None;
+    for header in headers.headers.iter() {
+        let hash = { chain.write().unwrap().add_header(header)? };
+        let downloaded = { chain.read().unwrap().has_block(&hash)? };
+        if !downloaded {
+            network.write().unwrap().sync.push(hash.clone());
+        }
+        last_hash = Some(hash);
+    }
+
+    //? A full batch means the peer has more, continue from where it stopped
+    if let Some(last_hash) = last_hash {
+        if headers.headers.len() == MAX_HEADERS {
+            send_get_headers(network.clone(), peer_id, vec![last_hash], vec![]).await?;
+        }
+    }
+
+    request_blocks(network).await;
 
     Ok(())
 }
 
 async fn handle_get_data(
     network: Arc<RwLock<Network>>,
+    peer_id: u64,
     get_data: GetData,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
@@ -274,264 +525,900 @@ async fn handle_get_data(
     if get_data.data_type == *"block" {
         let b = { chain.read().unwrap().get_block(&get_data.id)? };
 
-        if b.is_none() {
+        if let Some(b) = b {
+            send_block(network.clone(), peer_id, &b).await?;
+        } else {
             return Err(Error::CustomError(format!(
                 "Block {:?} doesn't existed!",
                 &get_data.id
             )));
-        } else {
-            send_block(network.clone(), &get_data.addr_from, &b.unwrap()).await?;
         }
     }
 
     if get_data.data_type == *"tx" {
-        let tx_id = hex::encode(get_data.id);
         let tx = {
             network
                 .read()
                 .unwrap()
                 .memory_pool
-                .get(&tx_id)
-                .unwrap()
-                .clone()
+                .get(&get_data.id)
+                .cloned()
         };
 
-        send_transaction(network, &get_data.addr_from, &tx).await?;
+        if let Some(tx) = tx {
+            send_transaction(network, peer_id, &tx).await?;
+        } else {
+            return Err(Error::CustomError(format!(
+                "Transaction {:?} isn't in the memory pool!",
+                hex::encode(&get_data.id)
+            )));
+        }
     }
 
     Ok(())
 }
 
-async fn handle_inv(network: Arc<RwLock<Network>>, inv: Inv) -> Result<()> {
+async fn handle_inv(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    inv: Inv,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
     println!(
         "handle_inv: Receive inventory with {} of  {}",
         inv.items.len(),
         inv.inv_type
     );
 
-    let first_item = inv.items[0].clone();
     if inv.inv_type == *"block" {
-        {
-            network.write().unwrap().blocks_in_transit = inv.items;
+        //? Announced blocks are fetched headers first, from our locator up to the last unknown one
+        let mut last_unknown = None;
+        for block_hash in inv.items {
+            let known = { chain.read().unwrap().has_block(&block_hash)? } || {
+                network.read().unwrap().sync.is_pending(&block_hash)
+            };
+            if !known {
+                last_unknown = Some(block_hash);
+            }
         }
 
-        send_get_data(
-            network.clone(),
-            &inv.addr_from,
-            "block".to_owned(),
-            first_item.clone(),
-        )
-        .await?;
-
-        let mut new_in_transit = vec![];
-        let blocks_in_transit = { network.read().unwrap().blocks_in_transit.clone() };
-
-        for block_hash in blocks_in_transit {
-            if block_hash == first_item {
-                new_in_transit.push(block_hash);
+        if let Some(stop) = last_unknown {
+            let locator = { chain.read().unwrap().block_locator()? };
+            send_get_headers(network.clone(), peer_id, locator, stop).await?;
+        }
+    } else if inv.inv_type == *"tx" {
+        for tx_id in inv.items {
+            let known = {
+                let network = network.read().unwrap();
+                network.memory_pool.contains(&tx_id) || network.orphan_transactions.contains(&tx_id)
+            };
+            if !known {
+                send_get_data(network.clone(), peer_id, "tx".to_owned(), tx_id).await?;
             }
         }
     }
 
-    if inv.inv_type == *"tx" && {
-        network
-            .read()
-            .unwrap()
-            .memory_pool
-            .get(&hex::encode(first_item.clone()))
-            .is_none()
-    } {
-        send_get_data(network, &inv.addr_from, "tx".to_owned(), first_item).await?;
-    }
-
     Ok(())
 }
 
 async fn handle_transaction(
     network: Arc<RwLock<Network>>,
+    peer_id: u64,
     transaction: Transaction,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
     println!("handle_transaction");
 
     let tx: blockchain::Transaction = bincode::deserialize(&transaction.tx)?;
-    let tx_id = tx.id.clone();
 
-    {
-        network
-            .write()
-            .unwrap()
-            .memory_pool
-            .insert(hex::encode(tx_id.clone()), tx);
+    let known = {
+        let network = network.read().unwrap();
+        network.memory_pool.contains(&tx.id) || network.orphan_transactions.contains(&tx.id)
+    };
+    if known {
+        return Ok(());
+    }
+
+    if accept_transaction(network.clone(), peer_id, tx, chain.clone()).await? == 0 {
+        return Ok(());
     }
 
     let node_address = { network.read().unwrap().node_address.clone() };
-    let known_nodes = { network.read().unwrap().known_nodes.clone() };
     let memory_pool_size = { network.read().unwrap().memory_pool.len() };
     let mine_address = { network.read().unwrap().mine_address.clone() };
 
     println!("Network {} - pool size {}", node_address, memory_pool_size);
 
-    if node_address == CENTRAL {
-        for node in known_nodes {
-            if node != node_address && node != transaction.addr_from {
-                send_inv(network.clone(), &node, "tx".to_owned(), vec![tx_id.clone()]).await?;
+    if memory_pool_size >= 1 && !mine_address.is_empty() {
+        spawn_mining(network, chain);
+    }
+
+    Ok(())
+}
+
+//? Mining runs apart from the caller so the peers' next messages, like a new tip, are still read
+fn spawn_mining(network: Arc<RwLock<Network>>, chain: Arc<RwLock<blockchain::BlockChain>>) {
+    tokio::spawn(async move {
+        if let Err(err) = mine_tx(network, chain).await {
+            println!("Mining failed: {:?}", err);
+        }
+    });
+}
+
+//? Accepts the transaction and the orphans waiting on it, returns how many entered the memory pool
+//? A transaction with unknown parents waits as an orphan while they are requested from its peer
+async fn accept_transaction(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    tx: blockchain::Transaction,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<usize> {
+    let mut accepted = 0;
+    let mut queue = vec![(tx, peer_id)];
+
+    while let Some((tx, source)) = queue.pop() {
+        let tx_id = tx.id.clone();
+        let chain_clone = { chain.read().unwrap().clone() };
+        let result = {
+            let mut network = network.write().unwrap();
+            let result = network.memory_pool.accept(tx.clone(), &chain_clone);
+            events::publish_mempool(&mut network);
+            result
+        };
+
+        let missing = match result {
+            Ok(_) => {
+                accepted += 1;
+                relay_inv(network.clone(), Some(source), "tx", vec![tx_id.clone()]).await;
+
+                let children = {
+                    network
+                        .write()
+                        .unwrap()
+                        .orphan_transactions
+                        .take_children(&tx_id)
+                };
+                queue.extend(children);
+                continue;
+            }
+            Err(Error::BlockRejected {
+                source: blockchain::BlockRejection::MissingInput(_),
+            }) => {
+                let network = network.read().unwrap();
+                network.memory_pool.missing_parents(&tx, &chain_clone)?
+            }
+            Err(err) => {
+                println!("Transaction {} rejected: {:?}", hex::encode(&tx_id), err);
+                continue;
+            }
+        };
+
+        if missing.is_empty() || tx.size()? > MAX_ORPHAN_TRANSACTION_SIZE {
+            println!(
+                "Transaction {} rejected: spends missing or spent outputs",
+                hex::encode(&tx_id)
+            );
+            continue;
+        }
+
+        let added = {
+            network.write().unwrap().orphan_transactions.insert(
+                tx_id.clone(),
+                tx,
+                missing.clone(),
+                source,
+            )
+        };
+        if added {
+            println!(
+                "Orphan transaction {:?}, requesting {} parents",
+                hex::encode(&tx_id),
+                missing.len()
+            );
+            for parent in missing {
+                send_get_data(network.clone(), source, "tx".to_owned(), parent).await?;
             }
         }
-    } else if memory_pool_size >= 1 && !mine_address.is_empty() {
-        mine_tx(network, chain).await?
     }
 
-    Ok(())
+    Ok(accepted)
 }
 
+//? The mining slot is taken before the template is built, so a tip committed meanwhile cancels it too
 async fn mine_tx(
     network: Arc<RwLock<Network>>,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
-    let mut txs = vec![];
+    let cancel = {
+        let mut network = network.write().unwrap();
+        if network.mining.is_some() {
+            println!("Already mining");
+            return Ok(());
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        network.mining = Some(cancel.clone());
+        cancel
+    };
 
-    let node_address = { network.read().unwrap().node_address.clone() };
-    let known_nodes = { network.read().unwrap().known_nodes.clone() };
-    let memory_pool = { network.read().unwrap().memory_pool.clone() };
-    let mine_address = { network.read().unwrap().mine_address.clone() };
+    //? None when there was nothing to mine, Some(None) when a new tip cancelled it
+    let mined = match build_template(&network, &chain) {
+        Ok(Some(block)) => mine_template(block, cancel.clone()).await.map(Some),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err),
+    };
+    {
+        //? A new tip may have already handed the slot to another miner
+        let mut network = network.write().unwrap();
+        if network
+            .mining
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, &cancel))
+        {
+            network.mining = None;
+        }
+    }
 
-    for (id, tx) in memory_pool.iter() {
-        println!("Tx: {:?}", id);
-        let verified_tx = { chain.read().unwrap().verify_transaction(tx) };
-        if verified_tx.is_ok() {
-            txs.push(tx.clone());
+    let block = match mined? {
+        Some(Some(block)) => block,
+        Some(None) => {
+            println!("Mining cancelled by a new tip");
+            return Ok(());
         }
+        None => return Ok(()),
+    };
+
+    commit_block(&network, &chain, &block)?;
+
+    println!("New block mined");
+
+    relay_inv(network, None, "block", vec![block.hash.clone()]).await;
+
+    Ok(())
+}
+
+fn build_template(
+    network: &Arc<RwLock<Network>>,
+    chain: &Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<Option<blockchain::Block>> {
+    let candidates = { network.read().unwrap().memory_pool.transactions() };
+    let mine_address = { network.read().unwrap().mine_address.clone() };
+
+    let block = {
+        chain
+            .read()
+            .unwrap()
+            .block_template(candidates, &mine_address)?
+    };
+    for tx in block.transactions.iter().skip(1) {
+        println!("Tx: {:?}", hex::encode(&tx.id));
     }
 
-    if txs.is_empty() {
+    if block.transactions.len() == 1 {
         println!("All transactions are invalid");
-        return Ok(());
+        return Ok(None);
     }
 
-    let coinbase_tx = blockchain::Transaction::coinbase_tx(&mine_address)?;
-    txs.push(coinbase_tx);
+    Ok(Some(block))
+}
 
-    let block = { chain.write().unwrap().mine_block(txs.clone())? };
-    let chain_clone = { chain.read().unwrap().clone() };
-    let utxo_set = blockchain::UTXOSet::new(chain_clone);
-    utxo_set.reindex()?;
+//? Peer and mined blocks alike, the tip, the memory pool and the miner change under the chain lock
+//? A miner working on the old tip is cancelled and a new one started on top of the new tip
+fn commit_block(
+    network: &Arc<RwLock<Network>>,
+    chain: &Arc<RwLock<blockchain::BlockChain>>,
+    block: &blockchain::Block,
+) -> Result<blockchain::ChainUpdate> {
+    let mut chain_guard = chain.write().unwrap();
+    let update = chain_guard.add_block(block)?;
+    if update.connected.is_empty() {
+        return Ok(update);
+    }
 
-    println!("New block mined");
+    let mut network_guard = network.write().unwrap();
+    network_guard.memory_pool.update(&update, &chain_guard);
+    events::publish_update(&mut network_guard, &update);
 
-    for tx in txs {
-        let tx_id = hex::encode(tx.id);
-        {
-            network.write().unwrap().memory_pool.remove(&tx_id);
-        }
+    if let Some(cancel) = network_guard.mining.take() {
+        cancel.store(true, atomic::Ordering::Relaxed);
+    }
+    if !network_guard.mine_address.is_empty() && !network_guard.memory_pool.is_empty() {
+        spawn_mining(network.clone(), chain.clone());
     }
 
-    for node in known_nodes {
-        if node != node_address {
-            send_inv(
-                network.clone(),
-                &node,
-                "block".to_owned(),
-                vec![block.hash.clone()],
-            )
-            .await?;
+    Ok(update)
+}
+
+//? Announces to every ready peer but the one the items came from, a failing peer doesn't stop the others
+async fn relay_inv(
+    network: Arc<RwLock<Network>>,
+    source: Option<u64>,
+    inv_type: &str,
+    items: Vec<Vec<u8>>,
+) {
+    let ready_peers = { network.read().unwrap().ready_peers() };
+    for ready_peer in ready_peers {
+        if Some(ready_peer) == source {
+            continue;
+        }
+        if let Err(err) = send_inv(
+            network.clone(),
+            ready_peer,
+            inv_type.to_owned(),
+            items.clone(),
+        )
+        .await
+        {
+            println!("Relay to peer {} failed: {:?}", ready_peer, err);
         }
     }
+}
 
-    Ok(())
+//? Mining runs on blocking threads so the node keeps serving peers meanwhile
+async fn mine_template(
+    mut block: blockchain::Block,
+    cancel: Arc<AtomicBool>,
+) -> Result<Option<blockchain::Block>> {
+    let task = tokio::task::spawn_blocking(move || {
+        let found = blockchain::Miner::default().mine(&mut block, &cancel)?;
+        Ok(if found { Some(block) } else { None })
+    });
+
+    match task.await {
+        Ok(mined) => mined,
+        Err(err) => Err(Error::CustomError(format!("Miner task failed: {}", err))),
+    }
 }
 
 async fn handle_version(
     network: Arc<RwLock<Network>>,
+    peer_id: u64,
     version: Version,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
     println!("handle_version");
 
-    let best_height = { chain.read().unwrap().get_best_height()? };
-    let other_height = version.best_height;
-
-    match best_height.cmp(&other_height) {
-        Ordering::Less => send_get_blocks(network.clone(), &version.addr_from).await?,
-        Ordering::Greater => send_version(network.clone(), &version.addr_from, chain).await?,
-        _ => {}
+    if version.version < MIN_PEER_VERSION {
+        return Err(Error::CustomError(format!(
+            "Peer version {} is too old!",
+            version.version
+        )));
     }
 
-    let known_nodes = { network.read().unwrap().known_nodes.clone() };
-    if !known_nodes.contains(&version.addr_from) {
-        {
-            network.write().unwrap().known_nodes.push(version.addr_from);
+    let inbound = {
+        let mut network = network.write().unwrap();
+        if version.nonce == network.nonce {
+            return Err(Error::CustomError("Connected to itself!".to_owned()));
+        }
+
+        let peer = match network.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => {
+                return Err(Error::CustomError(format!(
+                    "Peer {} doesn't exists!",
+                    peer_id
+                )))
+            }
+        };
+        if peer.version.is_some() {
+            return Err(Error::CustomError("Peer sent version twice!".to_owned()));
         }
+
+        peer.version = Some(PeerVersion {
+            version: version.version.min(VERSION),
+            services: version.services,
+            best_height: version.best_height,
+            listen_address: version.addr_from,
+        });
+        peer.inbound
+    };
+
+    //? The connecting side speaks first, the other one answers with its own version
+    if inbound {
+        send_version(network.clone(), peer_id, chain).await?;
     }
+    send_verack(network, peer_id).await?;
 
     Ok(())
 }
 
-async fn handle_connection(
+async fn handle_verack(
     network: Arc<RwLock<Network>>,
-    mut socket: TcpStream,
+    peer_id: u64,
     chain: Arc<RwLock<blockchain::BlockChain>>,
 ) -> Result<()> {
-    let mut buffer = [0; 8192];
-    let bytes_read = socket.read(&mut buffer).await?;
+    println!("handle_verack");
+
+    let (other_height, full_node, inbound) = {
+        let mut network = network.write().unwrap();
+        let network = &mut *network;
+        let peer = match network.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => {
+                return Err(Error::CustomError(format!(
+                    "Peer {} doesn't exists!",
+                    peer_id
+                )))
+            }
+        };
+        if peer.verack {
+            return Err(Error::CustomError("Peer sent verack twice!".to_owned()));
+        }
+        let version = match peer.version.as_ref() {
+            Some(version) => version,
+            None => {
+                return Err(Error::CustomError(
+                    "Peer sent verack before version!".to_owned(),
+                ))
+            }
+        };
+
+        println!(
+            "Connected to {} ({}, version {}, listening on {:?})",
+            peer.address,
+            if peer.inbound { "inbound" } else { "outbound" },
+            version.version,
+            version.listen_address
+        );
+        let handshake = (
+            version.best_height,
+            peer.has_service(NODE_NETWORK),
+            peer.inbound,
+        );
+        peer.verack = true;
+
+        //? A peer we dialed is known to be reachable
+        if !peer.inbound {
+            let address = peer.address.to_string();
+            network.address_book.mark_seen(&address);
+        }
+        handshake
+    };
+
+    let node_address = { network.read().unwrap().node_address.clone() };
+    send_addr(
+        network.clone(),
+        peer_id,
+        vec![NetAddress {
+            address: node_address,
+            last_seen: address_book::now(),
+        }],
+    )
+    .await?;
+    if !inbound {
+        send_get_addr(network.clone(), peer_id).await?;
+    }
+
+    let best_height = { chain.read().unwrap().get_best_height()? };
+    if full_node && best_height.cmp(&other_height) == Ordering::Less {
+        let locator = { chain.read().unwrap().block_locator()? };
+        send_get_headers(network, peer_id, locator, vec![]).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_addr(network: Arc<RwLock<Network>>, peer_id: u64, addr: Addr) -> Result<()> {
+    println!("handle_addr: Receive {} addresses", addr.addresses.len());
+
+    if addr.addresses.len() > MAX_ADDR {
+        return Err(Error::CustomError(format!(
+            "Peer sent {} addresses!",
+            addr.addresses.len()
+        )));
+    }
 
-    let payload = &buffer[..bytes_read];
-    let command: Command = bincode::deserialize(payload)?;
+    let relay = addr.addresses.len() <= ADDR_RELAY_LIMIT;
+    let (fresh, relay_peers) = {
+        let mut network = network.write().unwrap();
+        let node_address = network.node_address.clone();
+        let source = match network.peers.get(&peer_id) {
+            Some(peer) => peer.address.ip(),
+            None => return Ok(()),
+        };
+
+        let mut fresh = vec![];
+        for net_address in addr.addresses {
+            if net_address.address != node_address && network.address_book.add(&net_address, source)
+            {
+                fresh.push(net_address);
+            }
+        }
+
+        let mut relay_peers = network.ready_peers();
+        relay_peers.retain(|ready_peer| *ready_peer != peer_id);
+        relay_peers.shuffle(&mut OsRng);
+        relay_peers.truncate(ADDR_RELAY_FANOUT);
+        (fresh, relay_peers)
+    };
+
+    if relay && !fresh.is_empty() {
+        for relay_peer in relay_peers {
+            send_addr(network.clone(), relay_peer, fresh.clone()).await?;
+        }
+    }
+
+    Ok(())
+}
 
+async fn handle_get_addr(network: Arc<RwLock<Network>>, peer_id: u64) -> Result<()> {
+    println!("handle_get_addr");
+    let addresses = { network.read().unwrap().address_book.addresses(MAX_ADDR) };
+
+    send_addr(network, peer_id, addresses).await
+}
+
+async fn handle_command(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    command: Command,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
     match command {
-        Command::Block(block) => handle_block(network, block, chain).await?,
-        Command::Inv(inv) => handle_inv(network, inv).await?,
-        Command::GetBlocks(get_blocks) => handle_get_blocks(network, get_blocks, chain).await?,
-        Command::GetData(get_data) => handle_get_data(network, get_data, chain).await?,
+        Command::Addr(addr) => handle_addr(network, peer_id, addr).await,
+        Command::Block(block) => handle_block(network, peer_id, block, chain).await,
+        Command::Inv(inv) => handle_inv(network, peer_id, inv, chain).await,
+        Command::GetAddr => handle_get_addr(network, peer_id).await,
+        Command::GetData(get_data) => handle_get_data(network, peer_id, get_data, chain).await,
+        Command::GetHeaders(get_headers) => {
+            handle_get_headers(network, peer_id, get_headers, chain).await
+        }
+        Command::Headers(headers) => handle_headers(network, peer_id, headers, chain).await,
         Command::Transaction(transaction) => {
-            handle_transaction(network, transaction, chain).await?
+            handle_transaction(network, peer_id, transaction, chain).await
         }
-        Command::Version(version) => handle_version(network, version, chain).await?,
+        Command::Verack => handle_verack(network, peer_id, chain).await,
+        Command::Version(version) => handle_version(network, peer_id, version, chain).await,
     }
+}
+
+//? Handshake violations drop the peer, a failing request only gets logged
+async fn handle_connection<R: AsyncRead + Unpin>(
+    network: Arc<RwLock<Network>>,
+    peer_id: u64,
+    mut reader: R,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
+    let (max_message_size, disconnect) = {
+        let network = network.read().unwrap();
+        let disconnect = match network.peers.get(&peer_id) {
+            Some(peer) => peer.disconnect(),
+            None => return Ok(()),
+        };
+        (network.max_message_size, disconnect)
+    };
+
+    loop {
+        let command = tokio::select! {
+            command = message::read_message(&mut reader, max_message_size) => command?,
+            _ = disconnect.notified() => {
+                return Err(Error::CustomError(format!(
+                    "Peer {} doesn't read its messages!",
+                    peer_id
+                )))
+            }
+        };
+        let command = match command {
+            Some(command) => command,
+            None => break,
+        };
+        let handshake = matches!(command, Command::Version(_) | Command::Verack);
+        let ready = {
+            network
+                .read()
+                .unwrap()
+                .peers
+                .get(&peer_id)
+                .is_some_and(|peer| peer.is_ready())
+        };
+
+        if handshake {
+            handle_command(network.clone(), peer_id, command, chain.clone()).await?;
+        } else if !ready {
+            return Err(Error::CustomError(format!(
+                "Peer sent {} before the handshake!",
+                command.name()
+            )));
+        } else if let Err(err) =
+            handle_command(network.clone(), peer_id, command, chain.clone()).await
+        {
+            println!("Peer {} request failed: {:?}", peer_id, err);
+        }
+    }
+
+    Ok(())
+}
+
+fn start_peer(
+    network: Arc<RwLock<Network>>,
+    socket: TcpStream,
+    inbound: bool,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<u64> {
+    let address = socket.peer_addr()?;
+    let (reader, writer) = socket.into_split();
+
+    let peer_id = {
+        let mut network = network.write().unwrap();
+        let peer_id = network.next_peer_id;
+        network.next_peer_id += 1;
+        network
+            .peers
+            .insert(peer_id, Peer::new(address, inbound, writer));
+        peer_id
+    };
+
+    tokio::spawn(async move {
+        let result = handle_connection(network.clone(), peer_id, reader, chain).await;
+        {
+            let mut network = network.write().unwrap();
+            network.peers.remove(&peer_id);
+            network.sync.remove_peer(peer_id);
+        }
+
+        match result {
+            Ok(()) => println!("Disconnected from {}", address),
+            Err(err) => println!("Disconnected from {}: {:?}", address, err),
+        }
+    });
+
+    Ok(peer_id)
+}
+
+async fn connect_peer(
+    network: Arc<RwLock<Network>>,
+    address: &str,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<()> {
+    let socket = match TcpStream::connect(address).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            return Err(Error::CustomError(format!(
+                "Node {} is not available: {}",
+                address, err
+            )))
+        }
+    };
+
+    let peer_id = start_peer(network.clone(), socket, false, chain.clone())?;
+    send_version(network, peer_id, chain).await?;
 
     Ok(())
 }
 
-pub async fn start_server(node_id: &str, miner_address: &str) -> Result<()> {
+//? Keeps the outbound slots filled from the seeds and the address book, and saves the book and the memory pool
+async fn maintain_peers(network: Arc<RwLock<Network>>, chain: Arc<RwLock<blockchain::BlockChain>>) {
+    loop {
+        let candidates = {
+            let mut network = network.write().unwrap();
+            let network = &mut *network;
+
+            for peer in network.peers.values() {
+                if peer.is_ready() && !peer.inbound {
+                    network.address_book.mark_seen(&peer.address.to_string());
+                }
+            }
+            if let Err(err) = network.address_book.save() {
+                println!("Saving peers failed: {:?}", err);
+            }
+
+            let expired = network.memory_pool.expire();
+            if expired > 0 {
+                println!("Expired {} transactions from the memory pool", expired);
+            }
+            events::publish_mempool(network);
+            let expired = network.orphan_blocks.expire() + network.orphan_transactions.expire();
+            if expired > 0 {
+                println!("Expired {} orphans", expired);
+            }
+            if let Err(err) = network.memory_pool.save() {
+                println!("Saving the memory pool failed: {:?}", err);
+            }
+
+            //? Seeds can be in the address book too, each address takes one slot
+            let mut dialed = HashSet::new();
+            let mut candidates: Vec<String> = network
+                .address_book
+                .addresses(MAX_ADDR)
+                .into_iter()
+                .map(|net_address| net_address.address)
+                .chain(network.seeds.iter().cloned())
+                .filter(|address| {
+                    *address != network.node_address && !network.is_connected(address)
+                })
+                .filter(|address| dialed.insert(address.clone()))
+                .collect();
+            candidates.truncate(MAX_OUTBOUND.saturating_sub(network.outbound_count()));
+            candidates
+        };
+
+        for address in candidates {
+            if let Err(err) = connect_peer(network.clone(), &address, chain.clone()).await {
+                println!("Connecting to {} failed: {:?}", address, err);
+            }
+        }
+
+        tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+    }
+}
+
+pub struct NetworkConfig {
+    pub max_message_size: usize,
+    pub max_mempool_size: usize,
+    pub seeds: Vec<String>,
+    //? The RPC server also accepts this user and password next to the cookie
+    pub rpc_port: Option<u16>,
+    pub rpc_credentials: Option<(String, String)>,
+    //? Serves the REST endpoints and the explorer pages on the RPC port
+    pub rest: bool,
+    //? Streams the node events as JSON lines on this localhost port when set
+    pub events_port: Option<u16>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: message::DEFAULT_MAX_MESSAGE_SIZE,
+            max_mempool_size: blockchain::DEFAULT_MAX_MEMPOOL_SIZE,
+            seeds: vec![DEFAULT_SEED.to_owned()],
+            rpc_port: None,
+            rpc_credentials: None,
+            rest: false,
+            events_port: None,
+        }
+    }
+}
+
+pub async fn start_server(node_id: &str, miner_address: &str, config: NetworkConfig) -> Result<()> {
     let node_address = format!("127.0.0.1:{}", node_id);
+    let chain = blockchain::BlockChain::continue_blockchain(node_id)?;
+    let memory_pool = blockchain::Mempool::load(node_id, config.max_mempool_size, &chain)?;
+    if !memory_pool.is_empty() {
+        println!(
+            "Loaded {} transactions into the memory pool",
+            memory_pool.len()
+        );
+    }
 
     let network = Arc::new(RwLock::new(Network {
         node_address: node_address.clone(),
+        max_message_size: config.max_message_size,
         mine_address: miner_address.to_owned(),
-        known_nodes: vec![CENTRAL.to_owned()],
-        blocks_in_transit: vec![],
-        memory_pool: HashMap::new(),
+        nonce: OsRng.next_u64(),
+        seeds: config.seeds.clone(),
+        address_book: AddressBook::load(node_id)?,
+        peers: HashMap::new(),
+        next_peer_id: 0,
+        sync: BlockSync::default(),
+        memory_pool,
+        orphan_blocks: OrphanPool::new(MAX_ORPHAN_BLOCKS),
+        orphan_transactions: OrphanPool::new(MAX_ORPHAN_TRANSACTIONS),
+        mining: None,
+        events: tokio::sync::broadcast::channel(events::EVENT_BUFFER).0,
+        wallet_key: None,
     }));
 
-    let chain = Arc::new(RwLock::new(blockchain::BlockChain::continue_blockchain(
-        node_id,
-    )?));
+    let chain = Arc::new(RwLock::new(chain));
 
     let listener = TcpListener::bind(node_address.clone()).await?;
 
-    if node_address != CENTRAL {
-        send_version(network.clone(), CENTRAL, chain.clone()).await?;
+    let mut stop = rpc::start_rpc(node_id, &config, network.clone(), chain.clone()).await?;
+    if let Some(events_port) = config.events_port {
+        events::start_events(&format!("127.0.0.1:{}", events_port), network.clone()).await?;
     }
 
+    tokio::spawn(maintain_peers(network.clone(), chain.clone()));
+    tokio::spawn(sync_blocks(network.clone()));
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        let network_clone = network.clone();
-        let chain_clone = chain.clone();
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, _) = accepted?;
+                start_peer(network.clone(), socket, true, chain.clone())?;
+            }
+            signal = &mut shutdown => {
+                signal?;
+                break;
+            }
+            _ = stop.recv() => break,
+        }
+    }
 
-        tokio::spawn(handle_connection(network_clone, socket, chain_clone));
+    println!("Shutting down, saving the memory pool and peers");
+    rpc::remove_cookie(node_id)?;
+
+    let network = network.read().unwrap();
+    network.memory_pool.save()?;
+    network.address_book.save()?;
+    Ok(())
+}
+
+//? Ctrl-C, or the terminate signal a service manager sends on restart
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            signal = tokio::signal::ctrl_c() => signal?,
+            _ = terminate.recv() => {}
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
 }
 
-pub fn send_transaction_central(node_id: &str, tx: &blockchain::Transaction) -> Result<()> {
-    println!("send_transaction_central");
-    let node_address = format!("127.0.0.1:{}", node_id);
+//? Hands the transaction to the first known peer or seed that accepts it
+pub async fn submit_transaction(
+    node_id: &str,
+    tx: &blockchain::Transaction,
+    config: &NetworkConfig,
+) -> Result<()> {
+    println!("submit_transaction");
+    let candidates = AddressBook::load(node_id)?
+        .addresses(MAX_ADDR)
+        .into_iter()
+        .map(|net_address| net_address.address)
+        .chain(config.seeds.iter().cloned());
+
+    for address in candidates {
+        match submit_to(&address, tx).await {
+            Ok(()) => {
+                println!("Transaction sent to {}", address);
+                return Ok(());
+            }
+            Err(err) => println!("Sending to {} failed: {:?}", address, err),
+        }
+    }
+
+    Err(Error::CustomError(
+        "No peer accepted the transaction!".to_owned(),
+    ))
+}
+
+//? Handshakes as a client without services or listening address, then hands over the transaction
+async fn submit_to(address: &str, tx: &blockchain::Transaction) -> Result<()> {
+    let mut socket = TcpStream::connect(address).await?;
+
+    let version = Version {
+        version: VERSION,
+        services: 0,
+        nonce: OsRng.next_u64(),
+        best_height: 0,
+        addr_from: "".to_owned(),
+    };
+    socket
+        .write_all(&message::encode(&Command::Version(version))?)
+        .await?;
+
+    loop {
+        match message::read_message(&mut socket, message::DEFAULT_MAX_MESSAGE_SIZE).await? {
+            Some(Command::Version(_)) => {
+                socket
+                    .write_all(&message::encode(&Command::Verack)?)
+                    .await?
+            }
+            Some(Command::Verack) => break,
+            Some(_) => {}
+            None => {
+                return Err(Error::CustomError(
+                    "Node closed the connection during the handshake!".to_owned(),
+                ))
+            }
+        }
+    }
 
     let transaction = Transaction {
-        addr_from: node_address,
         tx: bincode::serialize(tx)?,
     };
-    let request = bincode::serialize(&Command::Transaction(transaction))?;
-    let mut socket = std::net::TcpStream::connect(CENTRAL)?;
-    socket.write_all(&request)?;
+    socket
+        .write_all(&message::encode(&Command::Transaction(transaction))?)
+        .await?;
+    socket.shutdown().await?;
+
     Ok(())
 }
@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use secp256k1::rand::{rngs::OsRng, seq::IteratorRandom};
+
+pub(crate) static MAX_ORPHAN_BLOCKS: usize = 100;
+pub(crate) static MAX_ORPHAN_TRANSACTIONS: usize = 100;
+//? Bigger orphans could fill the memory with transactions that never connect
+pub(crate) static MAX_ORPHAN_TRANSACTION_SIZE: usize = 100_000; //? Bytes
+static ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan<T> {
+    item: T,
+    parents: Vec<Vec<u8>>,
+    peer_id: u64,
+    received: Instant,
+}
+
+//? Blocks or transactions waiting for missing parents, with the peer that sent them
+pub(crate) struct OrphanPool<T> {
+    orphans: HashMap<Vec<u8>, Orphan<T>>,
+    by_parent: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+    limit: usize,
+}
+
+impl<T> OrphanPool<T> {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            limit,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub(crate) fn contains(&self, id: &[u8]) -> bool {
+        self.orphans.contains_key(id)
+    }
+
+    //? Returns false for a known orphan, a random one makes room once the pool is full so a peer can't pin the others
+    pub(crate) fn insert(
+        &mut self,
+        id: Vec<u8>,
+        item: T,
+        parents: Vec<Vec<u8>>,
+        peer_id: u64,
+    ) -> bool {
+        if self.contains(&id) {
+            return false;
+        }
+
+        while self.orphans.len() >= self.limit {
+            let evicted = match self.orphans.keys().choose(&mut OsRng) {
+                Some(evicted) => evicted.clone(),
+                None => break,
+            };
+            self.remove(&evicted);
+        }
+
+        for parent in parents.iter() {
+            self.by_parent
+                .entry(parent.clone())
+                .or_default()
+                .insert(id.clone());
+        }
+        self.orphans.insert(
+            id,
+            Orphan {
+                item,
+                parents,
+                peer_id,
+                received: Instant::now(),
+            },
+        );
+
+        true
+    }
+
+    //? Takes out the orphans waiting on the parent, those still missing another parent come back through insert
+    pub(crate) fn take_children(&mut self, parent: &[u8]) -> Vec<(T, u64)> {
+        let children = match self.by_parent.get(parent) {
+            Some(children) => children.clone(),
+            None => return vec![],
+        };
+
+        children
+            .iter()
+            .filter_map(|child| self.remove(child))
+            .map(|orphan| (orphan.item, orphan.peer_id))
+            .collect()
+    }
+
+    pub(crate) fn expire(&mut self) -> usize {
+        let expired: Vec<Vec<u8>> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.received.elapsed() > ORPHAN_EXPIRY)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired.iter() {
+            self.remove(id);
+        }
+
+        expired.len()
+    }
+
+    fn remove(&mut self, id: &[u8]) -> Option<Orphan<T>> {
+        let orphan = self.orphans.remove(id)?;
+
+        for parent in orphan.parents.iter() {
+            if let Some(children) = self.by_parent.get_mut(parent) {
+                children.remove(id);
+                if children.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+
+        Some(orphan)
+    }
+}
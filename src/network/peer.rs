@@ -0,0 +1,85 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::tcp::OwnedWriteHalf,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+};
+
+//? Service bits announced in the version message
+pub(crate) static NODE_NETWORK: u64 = 1;
+//? Frames waiting for the socket, a peer leaving more unread is dropped
+static MAX_QUEUED_FRAMES: usize = 1000;
+
+pub(crate) struct Peer {
+    pub(crate) address: SocketAddr,
+    pub(crate) inbound: bool,
+    pub(crate) version: Option<PeerVersion>,
+    pub(crate) verack: bool,
+    sender: mpsc::Sender<Vec<u8>>,
+    disconnect: Arc<Notify>,
+}
+
+//? What the peer announced about itself, the version is the one negotiated with it
+pub(crate) struct PeerVersion {
+    pub(crate) version: u32,
+    pub(crate) services: u64,
+    pub(crate) best_height: u64,
+    pub(crate) listen_address: String,
+}
+
+impl Peer {
+    pub(crate) fn new(address: SocketAddr, inbound: bool, writer: OwnedWriteHalf) -> Self {
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_FRAMES);
+        tokio::spawn(write_frames(writer, receiver));
+
+        Self {
+            address,
+            inbound,
+            version: None,
+            verack: false,
+            sender,
+            disconnect: Arc::new(Notify::new()),
+        }
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.version.is_some() && self.verack
+    }
+
+    pub(crate) fn has_service(&self, service: u64) -> bool {
+        self.version
+            .as_ref()
+            .is_some_and(|version| version.services & service != 0)
+    }
+
+    //? Frames are queued up to a bound so a slow peer never blocks the caller,
+    //? one that doesn't keep up gets disconnected instead of growing the queue
+    pub(crate) fn send(&self, frame: Vec<u8>) -> bool {
+        match self.sender.try_send(frame) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.disconnect.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    //? Notified once the peer has to be dropped
+    pub(crate) fn disconnect(&self) -> Arc<Notify> {
+        self.disconnect.clone()
+    }
+}
+
+//? Ends once the peer is dropped from the network or the socket fails
+async fn write_frames(mut writer: OwnedWriteHalf, mut receiver: mpsc::Receiver<Vec<u8>>) {
+    while let Some(frame) = receiver.recv().await {
+        if writer.write_all(&frame).await.is_err() {
+            break;
+        }
+    }
+}
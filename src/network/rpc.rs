@@ -0,0 +1,770 @@
+use std::{
+    fs::{create_dir_all, remove_file, File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use secp256k1::rand::{rngs::OsRng, RngCore};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{blockchain, wallet, Error, Result};
+
+use super::{
+    events, explorer,
+    http::{self, HttpRequest, HttpResponse},
+    relay_inv, spawn_mining, Network, NetworkConfig,
+};
+
+//? The RPC port defaults to the node port plus the offset, it only listens on localhost
+static RPC_PORT_OFFSET: u16 = 10000;
+static RPC_PATH: &str = "./tmp/rpc";
+static COOKIE_USER: &str = "__cookie__";
+
+static PARSE_ERROR: i64 = -32700;
+static INVALID_REQUEST: i64 = -32600;
+static METHOD_NOT_FOUND: i64 = -32601;
+static INVALID_PARAMS: i64 = -32602;
+static INTERNAL_ERROR: i64 = -32603;
+//? Implementation defined, a request the node refused like an unknown block or a rejected transaction
+static SERVER_ERROR: i64 = -32000;
+
+//? Longest a wallet stays unlocked in the node
+static MAX_UNLOCK_SECONDS: u64 = 100_000_000;
+
+static METHODS: [&str; 15] = [
+    "getbalance",
+    "getbestblockhash",
+    "getblock",
+    "getblockchaininfo",
+    "getblockcount",
+    "getblockhash",
+    "getmempoolinfo",
+    "getpeerinfo",
+    "getrawmempool",
+    "gettransaction",
+    "help",
+    "sendtoaddress",
+    "stop",
+    "walletlock",
+    "walletpassphrase",
+];
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+type RpcResult<T> = std::result::Result<T, RpcError>;
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        let code = match err {
+            Error::SledError { .. }
+            | Error::SledTransaction { .. }
+            | Error::BincodeError { .. }
+            | Error::IoError { .. }
+            | Error::JsonError { .. } => INTERNAL_ERROR,
+            _ => SERVER_ERROR,
+        };
+
+        Self {
+            code,
+            message: error_message(&err),
+        }
+    }
+}
+
+//? Everything the RPC handlers share, the accepted user:password pairs are the cookie and the configured one
+#[derive(Clone)]
+struct RpcContext {
+    credentials: Arc<Vec<String>>,
+    network: Arc<RwLock<Network>>,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+    shutdown: tokio::sync::mpsc::UnboundedSender<()>,
+    rest: bool,
+}
+
+//? Binds the RPC listener and writes a fresh cookie, the receiver gets a message when stop is called
+pub(crate) async fn start_rpc(
+    node_id: &str,
+    config: &NetworkConfig,
+    network: Arc<RwLock<Network>>,
+    chain: Arc<RwLock<blockchain::BlockChain>>,
+) -> Result<tokio::sync::mpsc::UnboundedReceiver<()>> {
+    let address = rpc_address(node_id, config)?;
+    let listener = TcpListener::bind(&address).await?;
+
+    let cookie = write_cookie(node_id)?;
+    let mut credentials = vec![cookie];
+    if let Some((user, password)) = &config.rpc_credentials {
+        credentials.push(format!("{}:{}", user, password));
+    }
+
+    let (shutdown, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let context = RpcContext {
+        credentials: Arc::new(credentials),
+        network,
+        chain,
+        shutdown,
+        rest: config.rest,
+    };
+
+    println!("RPC server listening on {}", address);
+    if config.rest {
+        println!("REST and explorer on http://{}/", address);
+    }
+    tokio::spawn(async move {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    println!("RPC accept failed: {:?}", err);
+                    continue;
+                }
+            };
+
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_rpc_connection(socket, context).await {
+                    println!("RPC connection failed: {:?}", err);
+                }
+            });
+        }
+    });
+
+    Ok(receiver)
+}
+
+pub(crate) fn remove_cookie(node_id: &str) -> Result<()> {
+    let file = cookie_file(node_id);
+    if Path::new(&file).exists() {
+        remove_file(file)?;
+    }
+    Ok(())
+}
+
+//? Calls a method on the running node, with RPC_USER and RPC_PASSWORD or else the node's cookie
+pub async fn rpc_call(
+    node_id: &str,
+    config: &NetworkConfig,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value> {
+    let credentials = match &config.rpc_credentials {
+        Some((user, password)) => format!("{}:{}", user, password),
+        None => read_cookie(node_id)?,
+    };
+
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    })
+    .to_string();
+
+    let address = rpc_address(node_id, config)?;
+    let mut socket = TcpStream::connect(&address).await?;
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        address,
+        STANDARD.encode(credentials),
+        body.len(),
+        body
+    );
+    socket.write_all(request.as_bytes()).await?;
+
+    let (status, body) = http::read_response(socket).await?;
+    if status == 401 {
+        return Err(Error::CustomError("RPC authorization failed!".to_owned()));
+    }
+
+    let mut response: Value = serde_json::from_slice(&body)?;
+    match response.get("error") {
+        Some(error) if !error.is_null() => Err(Error::CustomError(format!(
+            "RPC error {}: {}",
+            error["code"],
+            error["message"].as_str().unwrap_or_default()
+        ))),
+        _ => Ok(response["result"].take()),
+    }
+}
+
+fn rpc_address(node_id: &str, config: &NetworkConfig) -> Result<String> {
+    let port = match config.rpc_port {
+        Some(port) => port,
+        None => match node_id
+            .parse::<u16>()
+            .ok()
+            .and_then(|port| port.checked_add(RPC_PORT_OFFSET))
+        {
+            Some(port) => port,
+            None => {
+                return Err(Error::CustomError(
+                    "RPC_PORT must be set when NODE_ID isn't a port below 55536".to_owned(),
+                ))
+            }
+        },
+    };
+
+    Ok(format!("127.0.0.1:{}", port))
+}
+
+fn cookie_file(node_id: &str) -> String {
+    format!("{}/cookie_{}", RPC_PATH, node_id)
+}
+
+//? Only the user running the node can read the cookie, so anyone who can is allowed to call it
+fn write_cookie(node_id: &str) -> Result<String> {
+    create_dir_all(RPC_PATH)?;
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let cookie = format!("{}:{}", COOKIE_USER, hex::encode(secret));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(cookie_file(node_id))?;
+    file.write_all(cookie.as_bytes())?;
+
+    Ok(cookie)
+}
+
+fn read_cookie(node_id: &str) -> Result<String> {
+    let file = cookie_file(node_id);
+    if !Path::new(&file).exists() {
+        return Err(Error::CustomError(
+            "RPC cookie doesn't exists, is the node running?".to_owned(),
+        ));
+    }
+
+    let mut cookie = String::new();
+    File::open(file)?.read_to_string(&mut cookie)?;
+    Ok(cookie.trim().to_owned())
+}
+
+async fn handle_rpc_connection(mut socket: TcpStream, context: RpcContext) -> Result<()> {
+    let (reader, mut writer) = socket.split();
+
+    let response = match http::read_request(reader).await {
+        Ok(request) => respond(request, context).await,
+        Err(err) => HttpResponse::text(400, &error_message(&err)),
+    };
+
+    http::write_response(&mut writer, response).await
+}
+
+//? POST / is JSON-RPC, with REST on the other requests are read only and need no credentials
+async fn respond(request: HttpRequest, context: RpcContext) -> HttpResponse {
+    if request.method == "GET" && context.rest {
+        return explorer::respond(&request, &context.network, &context.chain);
+    }
+    if request.path != "/" {
+        return HttpResponse::text(404, "Not found");
+    }
+    if request.method != "POST" {
+        return HttpResponse::text(405, "JSON-RPC requests must be POSTed");
+    }
+    if !is_authorized(&request, &context.credentials) {
+        let mut response = HttpResponse::text(401, "Unauthorized");
+        response
+            .headers
+            .push(("WWW-Authenticate", "Basic realm=\"jsonrpc\"".to_owned()));
+        return response;
+    }
+
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(_) => {
+            return HttpResponse::json(
+                200,
+                &error_response(Value::Null, RpcError::new(PARSE_ERROR, "Parse error")),
+            )
+        }
+    };
+
+    //? Notifications get no answer, a batch of only notifications gets an empty response
+    let response = match body {
+        Value::Array(calls) if calls.is_empty() => Some(error_response(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, "Empty batch"),
+        )),
+        Value::Array(calls) => {
+            let mut responses = vec![];
+            for call in calls {
+                if let Some(response) = handle_call(call, &context).await {
+                    responses.push(response);
+                }
+            }
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        call => handle_call(call, &context).await,
+    };
+
+    match response {
+        Some(response) => HttpResponse::json(200, &response),
+        None => HttpResponse::new(204, "application/json", vec![]),
+    }
+}
+
+fn is_authorized(request: &HttpRequest, credentials: &[String]) -> bool {
+    let decoded = match request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+    {
+        Some(decoded) => decoded,
+        None => return false,
+    };
+
+    credentials
+        .iter()
+        .any(|expected| constant_time_eq(expected.as_bytes(), &decoded))
+}
+
+//? Compares every byte so the time taken doesn't tell how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn handle_call(call: Value, context: &RpcContext) -> Option<Value> {
+    let object = match call.as_object() {
+        Some(object) => object,
+        None => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, "Request must be an object"),
+            ))
+        }
+    };
+    let id = object.get("id").cloned();
+
+    let method = match (object.get("jsonrpc"), object.get("method")) {
+        (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => method,
+        _ => {
+            return Some(error_response(
+                id.unwrap_or(Value::Null),
+                RpcError::new(INVALID_REQUEST, "Invalid request"),
+            ))
+        }
+    };
+    let params = match object.get("params") {
+        None => Ok(vec![]),
+        Some(Value::Array(params)) => Ok(params.clone()),
+        Some(_) => Err(RpcError::new(
+            INVALID_PARAMS,
+            "Params must be given by position",
+        )),
+    };
+
+    let result = match params {
+        Ok(params) => dispatch(method, &params, context).await,
+        Err(err) => Err(err),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(err) => error_response(id, err),
+    })
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": err.code, "message": err.message },
+        "id": id,
+    })
+}
+
+async fn dispatch(method: &str, params: &[Value], context: &RpcContext) -> RpcResult<Value> {
+    let chain = { context.chain.read().unwrap().clone() };
+
+    match method {
+        "getbalance" => {
+            let address = address_param(params, 0, "address")?;
+            let utxo_set = blockchain::UTXOSet::new(chain);
+            let (balance, immature) = utxo_set.get_balance(&address)?;
+            Ok(json!({ "balance": balance, "immature": immature }))
+        }
+        "getbestblockhash" => Ok(json!(hex::encode(&chain.lasthash))),
+        "getblock" => {
+            let hash = hex_param(params, 0, "hash")?;
+            match chain.get_block(&hash)? {
+                Some(block) => Ok(block_json(&block, &chain)?),
+                None => Err(RpcError::new(SERVER_ERROR, "Block doesn't exists!")),
+            }
+        }
+        "getblockchaininfo" => {
+            let height = chain.get_best_height()?;
+            let syncing = { !context.network.read().unwrap().sync.is_idle() };
+            Ok(json!({
+                "blocks": height,
+                "bestblockhash": hex::encode(&chain.lasthash),
+                "subsidy": blockchain::block_subsidy(height),
+                "supply": blockchain::supply_at(height),
+                "max_money": blockchain::MAX_MONEY,
+                "syncing": syncing,
+            }))
+        }
+        "getblockcount" => Ok(json!(chain.get_best_height()?)),
+        "getblockhash" => {
+            let height = u64_param(params, 0, "height")?;
+            Ok(json!(hex::encode(chain.get_main_hash(height)?)))
+        }
+        "getmempoolinfo" => {
+            let network = context.network.read().unwrap();
+            Ok(json!({
+                "size": network.memory_pool.len(),
+                "bytes": network.memory_pool.size(),
+                "maxmempool": network.memory_pool.max_size(),
+                "orphans": network.orphan_transactions.len(),
+            }))
+        }
+        "getpeerinfo" => {
+            let network = context.network.read().unwrap();
+            let mut peer_ids: Vec<&u64> = network.peers.keys().collect();
+            peer_ids.sort();
+            Ok(Value::Array(
+                peer_ids
+                    .into_iter()
+                    .map(|peer_id| {
+                        let peer = &network.peers[peer_id];
+                        let mut info = json!({
+                            "id": peer_id,
+                            "address": peer.address.to_string(),
+                            "inbound": peer.inbound,
+                            "ready": peer.is_ready(),
+                        });
+                        if let Some(version) = &peer.version {
+                            info["version"] = json!(version.version);
+                            info["services"] = json!(version.services);
+                            info["best_height"] = json!(version.best_height);
+                            info["listen_address"] = json!(version.listen_address);
+                        }
+                        info
+                    })
+                    .collect(),
+            ))
+        }
+        "getrawmempool" => {
+            let network = context.network.read().unwrap();
+            Ok(Value::Array(
+                network
+                    .memory_pool
+                    .transactions()
+                    .iter()
+                    .map(|tx| json!(hex::encode(&tx.id)))
+                    .collect(),
+            ))
+        }
+        "gettransaction" => {
+            let tx_id = hex_param(params, 0, "txid")?;
+            match find_transaction_json(&tx_id, &chain, &context.network)? {
+                Some(tx) => Ok(tx),
+                None => Err(RpcError::new(SERVER_ERROR, "Transaction doesn't exists!")),
+            }
+        }
+        "help" => Ok(json!(METHODS)),
+        "sendtoaddress" => send_to_address(params, chain, context).await,
+        "stop" => {
+            let _ = context.shutdown.send(());
+            Ok(json!("Node stopping"))
+        }
+        "walletlock" => {
+            context.network.write().unwrap().wallet_key = None;
+            Ok(Value::Null)
+        }
+        "walletpassphrase" => wallet_passphrase(params, context).await,
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+//? Params: from, to, amount, fee (default 0), replaceable (default true), the node's wallet signs
+async fn send_to_address(
+    params: &[Value],
+    chain: blockchain::BlockChain,
+    context: &RpcContext,
+) -> RpcResult<Value> {
+    let from = string_param(params, 0, "from")?;
+    let to = address_param(params, 1, "to")?;
+    let amount = u64_param(params, 2, "amount")?;
+    let fee = match params.get(3) {
+        None | Some(Value::Null) => 0,
+        Some(_) => u64_param(params, 3, "fee")?,
+    };
+    let replaceable = match params.get(4) {
+        None | Some(Value::Null) => true,
+        Some(Value::Bool(replaceable)) => *replaceable,
+        Some(_) => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "replaceable must be a boolean",
+            ))
+        }
+    };
+
+    let mut wallets = wallet::Wallets::create_wallets()?;
+    if wallets.check_address(&from).is_err() {
+        return Err(RpcError::new(
+            SERVER_ERROR,
+            "Wallet of the sender doesn't exists!",
+        ));
+    }
+    if wallets.is_locked() {
+        let key = { context.network.read().unwrap().wallet_key.clone() };
+        match key {
+            Some((key, until)) if Instant::now() < until => wallets.unlock_with(&key)?,
+            _ => {
+                return Err(RpcError::new(
+                    SERVER_ERROR,
+                    "Wallet is locked, unlock it with walletpassphrase first!",
+                ))
+            }
+        }
+    }
+
+    //? Built and accepted under one lock, so concurrent sends see each other's spent outputs
+    let utxo_set = blockchain::UTXOSet::new(chain);
+    let tx = {
+        let mut network = context.network.write().unwrap();
+        let tx = blockchain::Transaction::new(
+            &mut wallets,
+            &from,
+            &to,
+            amount,
+            fee,
+            replaceable,
+            &utxo_set,
+            Some(&network.memory_pool),
+        )?;
+        let accepted = network.memory_pool.accept(tx.clone(), &utxo_set.chain);
+        events::publish_mempool(&mut network);
+        accepted?;
+        tx
+    };
+    wallets.save_file()?;
+    utxo_set.chain.save_wallet_transaction(&tx)?;
+    println!("RPC transaction {:?}", hex::encode(&tx.id));
+
+    relay_inv(context.network.clone(), None, "tx", vec![tx.id.clone()]).await;
+
+    let mining = { !context.network.read().unwrap().mine_address.is_empty() };
+    if mining {
+        spawn_mining(context.network.clone(), context.chain.clone());
+    }
+
+    Ok(json!(hex::encode(&tx.id)))
+}
+
+//? Keeps the derived key rather than the passphrase, and drops it once the timeout passes
+async fn wallet_passphrase(params: &[Value], context: &RpcContext) -> RpcResult<Value> {
+    let passphrase = string_param(params, 0, "passphrase")?;
+    let timeout = u64_param(params, 1, "timeout")?;
+    if timeout == 0 || timeout > MAX_UNLOCK_SECONDS {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            &format!(
+                "timeout must be between 1 and {} seconds",
+                MAX_UNLOCK_SECONDS
+            ),
+        ));
+    }
+
+    //? The key derivation is made to be slow
+    let task = tokio::task::spawn_blocking(move || {
+        let mut wallets = wallet::Wallets::create_wallets()?;
+        wallets.unlock(&passphrase)
+    });
+    let key = match task.await {
+        Ok(key) => key?,
+        Err(err) => {
+            return Err(RpcError::from(Error::CustomError(format!(
+                "Unlock task failed: {}",
+                err
+            ))))
+        }
+    };
+
+    let until = Instant::now() + Duration::from_secs(timeout);
+    context.network.write().unwrap().wallet_key = Some((key, until));
+
+    let network = context.network.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(until.into()).await;
+        let mut network = network.write().unwrap();
+        //? A later unlock extended the timeout
+        if matches!(network.wallet_key, Some((_, current)) if current == until) {
+            network.wallet_key = None;
+        }
+    });
+
+    Ok(Value::Null)
+}
+
+//? A confirmed transaction with its block, else one from the memory pool with its fee
+pub(crate) fn find_transaction_json(
+    tx_id: &[u8],
+    chain: &blockchain::BlockChain,
+    network: &Arc<RwLock<Network>>,
+) -> Result<Option<Value>> {
+    if let Some(info) = chain.get_transaction(tx_id)? {
+        let mut tx = transaction_json(&info.transaction)?;
+        tx["blockhash"] = json!(hex::encode(&info.block_hash));
+        tx["height"] = json!(info.block_height);
+        tx["confirmations"] = json!(info.confirmations);
+        return Ok(Some(tx));
+    }
+
+    let network = network.read().unwrap();
+    match network.memory_pool.get(tx_id) {
+        Some(transaction) => {
+            let mut tx = transaction_json(transaction)?;
+            tx["confirmations"] = json!(0);
+            tx["fee"] = json!(network.memory_pool.fee(tx_id));
+            Ok(Some(tx))
+        }
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn block_json(
+    block: &blockchain::Block,
+    chain: &blockchain::BlockChain,
+) -> Result<Value> {
+    //? A block off the main chain has no confirmations
+    let confirmations = if chain.get_main_hash(block.height).ok().as_ref() == Some(&block.hash) {
+        chain.get_best_height()? - block.height + 1
+    } else {
+        0
+    };
+
+    let mut transactions = vec![];
+    for tx in block.transactions.iter() {
+        transactions.push(transaction_json(tx)?);
+    }
+
+    Ok(json!({
+        "hash": hex::encode(&block.hash),
+        "height": block.height,
+        "confirmations": confirmations,
+        "version": block.header.version,
+        "previousblockhash": hex::encode(&block.header.prevhash),
+        "merkleroot": hex::encode(&block.header.merkle_root),
+        "time": block.header.timestamp,
+        "bits": block.header.bits,
+        "nonce": block.header.nonce,
+        "tx": transactions,
+    }))
+}
+
+pub(crate) fn transaction_json(tx: &blockchain::Transaction) -> Result<Value> {
+    let inputs: Vec<Value> = tx
+        .inputs
+        .iter()
+        .map(|input| {
+            json!({
+                "txid": hex::encode(&input.id),
+                "vout": input.out,
+                "public_key": hex::encode(&input.public_key),
+                "sequence": input.sequence,
+            })
+        })
+        .collect();
+    let outputs: Vec<Value> = tx
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(index, output)| {
+            json!({
+                "n": index,
+                "value": output.value,
+                "address": wallet::address_from_public_key_hash(&output.public_key_hash),
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "txid": hex::encode(&tx.id),
+        "size": tx.size()?,
+        "coinbase": tx.is_coinbase(),
+        "replaceable": tx.is_replaceable(),
+        "inputs": inputs,
+        "outputs": outputs,
+    }))
+}
+
+fn string_param(params: &[Value], index: usize, name: &str) -> RpcResult<String> {
+    match params.get(index) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        _ => Err(RpcError {
+            code: INVALID_PARAMS,
+            message: format!("{} must be a string", name),
+        }),
+    }
+}
+
+fn address_param(params: &[Value], index: usize, name: &str) -> RpcResult<String> {
+    let address = string_param(params, index, name)?;
+    match wallet::validate_address(&address) {
+        Ok(true) => Ok(address),
+        _ => Err(RpcError {
+            code: INVALID_PARAMS,
+            message: format!("{} must be a valid address", name),
+        }),
+    }
+}
+
+fn u64_param(params: &[Value], index: usize, name: &str) -> RpcResult<u64> {
+    match params.get(index).and_then(|value| value.as_u64()) {
+        Some(value) => Ok(value),
+        None => Err(RpcError {
+            code: INVALID_PARAMS,
+            message: format!("{} must be a positive integer", name),
+        }),
+    }
+}
+
+fn hex_param(params: &[Value], index: usize, name: &str) -> RpcResult<Vec<u8>> {
+    match hex::decode(string_param(params, index, name)?) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(RpcError {
+            code: INVALID_PARAMS,
+            message: format!("{} must be hex", name),
+        }),
+    }
+}
+
+//? The custom errors carry their message, the others name the failing part
+pub(crate) fn error_message(err: &Error) -> String {
+    match err {
+        Error::CustomError(message) => message.clone(),
+        err => err.to_string(),
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::blockchain;
+
+static MAX_IN_FLIGHT_PER_PEER: usize = 16;
+//? Only the first pending bodies are requested so out of order downloads stay bounded in memory
+static DOWNLOAD_WINDOW: usize = 1024;
+static DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
+
+//? Bodies of validated headers, requested from several peers at once but connected in header order
+#[derive(Default)]
+pub(crate) struct BlockSync {
+    pending: VecDeque<Vec<u8>>,
+    //? Same hashes as pending, for lookups during long header syncs
+    queued: HashSet<Vec<u8>>,
+    in_flight: HashMap<Vec<u8>, (u64, Instant)>,
+    downloaded: HashMap<Vec<u8>, blockchain::Block>,
+    stalled: HashMap<Vec<u8>, HashSet<u64>>,
+}
+
+impl BlockSync {
+    pub(crate) fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub(crate) fn is_pending(&self, block_hash: &[u8]) -> bool {
+        self.queued.contains(block_hash)
+    }
+
+    pub(crate) fn push(&mut self, block_hash: Vec<u8>) {
+        if self.queued.insert(block_hash.clone()) {
+            self.pending.push_back(block_hash);
+        }
+    }
+
+    //? Spreads requests over the least loaded peers, a peer that stalled on a body is only asked again as a last resort
+    pub(crate) fn schedule(&mut self, peers: &[u64]) -> Vec<(u64, Vec<u8>)> {
+        let mut load: HashMap<u64, usize> = peers.iter().map(|peer| (*peer, 0)).collect();
+        for (peer, _) in self.in_flight.values() {
+            if let Some(count) = load.get_mut(peer) {
+                *count += 1;
+            }
+        }
+
+        let mut requests = vec![];
+        for block_hash in self.pending.iter().take(DOWNLOAD_WINDOW) {
+            if self.in_flight.contains_key(block_hash) || self.downloaded.contains_key(block_hash) {
+                continue;
+            }
+
+            let stalled = self.stalled.get(block_hash);
+            let free = |peer: &&u64| load[*peer] < MAX_IN_FLIGHT_PER_PEER;
+            let peer = peers
+                .iter()
+                .filter(free)
+                .filter(|peer| !stalled.is_some_and(|stalled| stalled.contains(*peer)))
+                .min_by_key(|peer| load[*peer])
+                .or_else(|| peers.iter().filter(free).min_by_key(|peer| load[*peer]));
+
+            match peer {
+                Some(peer) => {
+                    *load.entry(*peer).or_default() += 1;
+                    requests.push((*peer, block_hash.clone()));
+                }
+                None => break,
+            }
+        }
+
+        let now = Instant::now();
+        for (peer, block_hash) in requests.iter() {
+            self.in_flight.insert(block_hash.clone(), (*peer, now));
+        }
+
+        requests
+    }
+
+    //? Timed out requests go back to the schedule, returns how many expired
+    pub(crate) fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<(Vec<u8>, u64)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested))| now.duration_since(*requested) > DOWNLOAD_TIMEOUT)
+            .map(|(block_hash, (peer, _))| (block_hash.clone(), *peer))
+            .collect();
+
+        for (block_hash, peer) in expired.iter() {
+            self.in_flight.remove(block_hash);
+            self.stalled
+                .entry(block_hash.clone())
+                .or_default()
+                .insert(*peer);
+        }
+
+        expired.len()
+    }
+
+    //? Returns false for a block nobody asked for
+    pub(crate) fn received(&mut self, block: blockchain::Block) -> bool {
+        if !self.is_pending(&block.hash) {
+            return false;
+        }
+
+        self.in_flight.remove(&block.hash);
+        self.downloaded.insert(block.hash.clone(), block);
+        true
+    }
+
+    pub(crate) fn next_ready(&mut self) -> Option<blockchain::Block> {
+        let block = self.downloaded.remove(self.pending.front()?)?;
+        self.pending.pop_front();
+        self.queued.remove(&block.hash);
+        self.stalled.remove(&block.hash);
+        Some(block)
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer_id: u64) {
+        self.in_flight.retain(|_, (peer, _)| *peer != peer_id);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use secp256k1::{rand::rngs::OsRng, rand::RngCore, PublicKey, Scalar, Secp256k1, SecretKey};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha512;
+
+use crate::{Error, Result};
+
+use super::wallet::Wallet;
+
+type HmacSha512 = Hmac<Sha512>;
+
+static HARDENED: u32 = 0x8000_0000;
+static MASTER_KEY: &[u8] = b"Bitcoin seed";
+//? m / purpose' / coin_type' / account' / change / index
+static PURPOSE: u32 = 44;
+static COIN_TYPE: u32 = 0;
+pub(crate) static RECEIVE: u32 = 0;
+pub(crate) static CHANGE: u32 = 1;
+//? Unused addresses in a row after which a rescan stops looking further on a chain
+pub(crate) static GAP_LIMIT: u32 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPath {
+    pub(crate) account: u32,
+    pub(crate) change: u32,
+    pub(crate) index: u32,
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "m/{}'/{}'/{}'/{}/{}",
+            PURPOSE, COIN_TYPE, self.account, self.change, self.index
+        )
+    }
+}
+
+//? A private key with the chain code its children are derived with
+struct ExtendedKey {
+    secret_key: SecretKey,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    fn master(seed: &[u8]) -> Result<Self> {
+        Self::from_hmac(MASTER_KEY, &[seed])
+    }
+
+    //? Hardened children commit to the private key, so a leaked child and chain code can't reveal the parent
+    fn child(&self, index: u32) -> Result<Self> {
+        let mut data = vec![];
+        if index >= HARDENED {
+            data.push(0);
+            data.extend_from_slice(&self.secret_key.secret_bytes());
+        } else {
+            let secp = Secp256k1::new();
+            data.extend_from_slice(
+                &PublicKey::from_secret_key(&secp, &self.secret_key).serialize(),
+            );
+        }
+
+        let child = Self::from_hmac(&self.chain_code, &[&data, &index.to_be_bytes()])?;
+        let tweak = Scalar::from(child.secret_key);
+
+        Ok(Self {
+            secret_key: self.secret_key.add_tweak(&tweak)?,
+            chain_code: child.chain_code,
+        })
+    }
+
+    //? The left half is the key, the right half the chain code
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> Result<Self> {
+        let mut mac = match HmacSha512::new_from_slice(key) {
+            Ok(mac) => mac,
+            Err(_) => return Err(Error::CustomError("HMAC key is invalid!".to_owned())),
+        };
+        for data in data {
+            mac.update(data);
+        }
+        let output = mac.finalize().into_bytes();
+
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&output[32..]);
+
+        Ok(Self {
+            secret_key: SecretKey::from_slice(&output[..32])?,
+            chain_code,
+        })
+    }
+}
+
+//? The seed every key derives from, with the next index of each account chain and the path of each derived address
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HdWallet {
+    seed: Vec<u8>,
+    next_index: BTreeMap<(u32, u32), u32>,
+    paths: HashMap<String, KeyPath>,
+}
+
+impl HdWallet {
+    //? 12 words carry 128 bits of entropy, 24 words 256 bits
+    pub(crate) fn generate(words: usize) -> Result<(Self, String)> {
+        let mut entropy = match words {
+            12 => vec![0u8; 16],
+            24 => vec![0u8; 32],
+            _ => {
+                return Err(Error::CustomError(
+                    "Mnemonic must have 12 or 24 words!".to_owned(),
+                ))
+            }
+        };
+        OsRng.fill_bytes(&mut entropy);
+
+        let mnemonic = Mnemonic::from_entropy(&entropy)?;
+        Ok((Self::from_mnemonic(&mnemonic), mnemonic.to_string()))
+    }
+
+    pub(crate) fn restore(phrase: &str) -> Result<Self> {
+        let mnemonic = Mnemonic::parse(phrase)?;
+        Ok(Self::from_mnemonic(&mnemonic))
+    }
+
+    fn from_mnemonic(mnemonic: &Mnemonic) -> Self {
+        Self {
+            seed: mnemonic.to_seed("").to_vec(),
+            next_index: BTreeMap::new(),
+            paths: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn path(&self, address: &str) -> Option<KeyPath> {
+        self.paths.get(address).copied()
+    }
+
+    //? Receiving addresses first, then change, each chain in index order
+    pub(crate) fn account_addresses(&self, account: u32) -> Vec<String> {
+        let mut addresses: Vec<(&String, &KeyPath)> = self
+            .paths
+            .iter()
+            .filter(|(_, path)| path.account == account)
+            .collect();
+        addresses.sort_by_key(|(address, path)| (path.change, path.index, *address));
+        addresses
+            .into_iter()
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    pub(crate) fn derive(&self, path: &KeyPath) -> Result<Wallet> {
+        check_path(path)?;
+
+        let mut key = ExtendedKey::master(&self.seed)?;
+        for index in [
+            PURPOSE | HARDENED,
+            COIN_TYPE | HARDENED,
+            path.account | HARDENED,
+            path.change,
+            path.index,
+        ] {
+            key = key.child(index)?;
+        }
+
+        Ok(Wallet::from_secret_key(key.secret_key))
+    }
+
+    //? Hands out the next unused address of the account chain
+    pub(crate) fn next(&mut self, account: u32, change: u32) -> Result<(KeyPath, Wallet)> {
+        let index = self
+            .next_index
+            .get(&(account, change))
+            .copied()
+            .unwrap_or(0);
+        let path = KeyPath {
+            account,
+            change,
+            index,
+        };
+        let wallet = self.derive(&path)?;
+        self.record(&wallet.address(), path)?;
+
+        Ok((path, wallet))
+    }
+
+    //? Keeps the path of the address and moves the chain past it
+    pub(crate) fn record(&mut self, address: &str, path: KeyPath) -> Result<()> {
+        check_path(&path)?;
+
+        let next = self
+            .next_index
+            .entry((path.account, path.change))
+            .or_default();
+        *next = (*next).max(path.index + 1);
+        self.paths.insert(address.to_owned(), path);
+        Ok(())
+    }
+}
+
+//? Indexes from 2^31 up are the hardened ones, an account there would collide with another one
+fn check_path(path: &KeyPath) -> Result<()> {
+    if path.account >= HARDENED || path.change >= HARDENED || path.index >= HARDENED {
+        return Err(Error::CustomError(
+            "Key path index is out of range!".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //? Test vector 1 of BIP32, m and m/0'/1
+    #[test]
+    fn derives_bip32_test_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+
+        let master = ExtendedKey::master(&seed).unwrap();
+        assert_eq!(
+            hex::encode(master.secret_key.secret_bytes()),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508"
+        );
+
+        let hardened = master.child(HARDENED).unwrap();
+        assert_eq!(
+            hex::encode(hardened.secret_key.secret_bytes()),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        assert_eq!(
+            hex::encode(hardened.chain_code),
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141"
+        );
+
+        let normal = hardened.child(1).unwrap();
+        assert_eq!(
+            hex::encode(normal.secret_key.secret_bytes()),
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+        );
+        assert_eq!(
+            hex::encode(normal.chain_code),
+            "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19"
+        );
+    }
+
+    #[test]
+    fn rejects_hardened_range_indexes() {
+        let (hd, _) = HdWallet::generate(12).unwrap();
+        let path = KeyPath {
+            account: HARDENED,
+            change: RECEIVE,
+            index: 0,
+        };
+        assert!(hd.derive(&path).is_err());
+    }
+}
@@ -1,5 +1,11 @@
+mod hd;
+mod vault;
 mod wallet;
 mod wallets;
 
-pub use wallet::public_key_hash_from_address;
+pub use hd::KeyPath;
+pub use vault::WalletKey;
+pub use wallet::{address_from_public_key_hash, public_key_hash_from_address};
 pub use wallets::Wallets;
+
+pub(crate) use wallet::{hash160, validate_address, PUBLIC_KEY_HASH_LENGTH};
@@ -0,0 +1,220 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use secp256k1::{rand::rngs::OsRng, rand::RngCore};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+static KEY_LENGTH: usize = 32;
+static SALT_LENGTH: usize = 16;
+static NONCE_LENGTH: usize = 24;
+//? Argon2id with 64 MiB makes each passphrase guess cost as much memory
+static KDF_MEMORY_KIB: u32 = 64 * 1024;
+static KDF_ITERATIONS: u32 = 3;
+static KDF_LANES: u32 = 1;
+//? Bounds for parameters read back from a file, so a tampered one can't exhaust the machine
+static MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+static MAX_KDF_ITERATIONS: u32 = 16;
+static MAX_KDF_LANES: u32 = 8;
+
+//? Parameters are stored with the vault so they can be raised without breaking older files
+#[derive(Serialize, Deserialize, Clone)]
+struct Kdf {
+    salt: Vec<u8>,
+    memory: u32,
+    iterations: u32,
+    lanes: u32,
+}
+
+//? The key derived from a passphrase, held while the wallet is unlocked
+#[derive(Clone)]
+pub struct WalletKey {
+    key: [u8; 32],
+    kdf: Kdf,
+}
+
+impl WalletKey {
+    //? A fresh salt, so a new passphrase never derives a key seen before
+    pub(crate) fn generate(passphrase: &str) -> Result<Self> {
+        if passphrase.is_empty() {
+            return Err(Error::CustomError("Passphrase can't be empty!".to_owned()));
+        }
+
+        let mut salt = vec![0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(
+            passphrase,
+            Kdf {
+                salt,
+                memory: KDF_MEMORY_KIB,
+                iterations: KDF_ITERATIONS,
+                lanes: KDF_LANES,
+            },
+        )
+    }
+
+    fn derive(passphrase: &str, kdf: Kdf) -> Result<Self> {
+        let params = match Params::new(kdf.memory, kdf.iterations, kdf.lanes, Some(KEY_LENGTH)) {
+            Ok(params) => params,
+            Err(_) => return Err(Error::CustomError("KDF parameters are invalid!".to_owned())),
+        };
+
+        let mut key = [0u8; 32];
+        if Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &kdf.salt, &mut key)
+            .is_err()
+        {
+            return Err(Error::CustomError(
+                "Passphrase derivation failed!".to_owned(),
+            ));
+        }
+
+        Ok(Self { key, kdf })
+    }
+}
+
+//? Keys and seed sealed with XChaCha20-Poly1305, a wrong passphrase fails the authentication
+//? The KDF parameters and addresses stay in the clear but are authenticated, editing them fails it too
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Vault {
+    kdf: Kdf,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    //? Kept in the clear so a locked wallet still lists and checks its addresses
+    pub(crate) addresses: Vec<String>,
+}
+
+impl Vault {
+    //? Every seal takes a new nonce, the key and salt stay until the passphrase changes
+    pub(crate) fn seal(key: &WalletKey, plaintext: &[u8], addresses: Vec<String>) -> Result<Self> {
+        let mut nonce = vec![0u8; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut nonce);
+
+        let payload = Payload {
+            msg: plaintext,
+            aad: &associated_data(&key.kdf, &addresses)?,
+        };
+        let ciphertext = match cipher(key).encrypt(XNonce::from_slice(&nonce), payload) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err(Error::CustomError("Wallet encryption failed!".to_owned())),
+        };
+
+        Ok(Self {
+            kdf: key.kdf.clone(),
+            nonce,
+            ciphertext,
+            addresses,
+        })
+    }
+
+    pub(crate) fn key(&self, passphrase: &str) -> Result<WalletKey> {
+        let kdf = Kdf {
+            salt: self.kdf.salt.clone(),
+            memory: self.kdf.memory.min(MAX_KDF_MEMORY_KIB),
+            iterations: self.kdf.iterations.min(MAX_KDF_ITERATIONS),
+            lanes: self.kdf.lanes.min(MAX_KDF_LANES),
+        };
+        WalletKey::derive(passphrase, kdf)
+    }
+
+    pub(crate) fn open(&self, key: &WalletKey) -> Result<Vec<u8>> {
+        if self.nonce.len() != NONCE_LENGTH {
+            return Err(Error::CustomError("Wallet file is corrupted!".to_owned()));
+        }
+
+        let payload = Payload {
+            msg: self.ciphertext.as_slice(),
+            aad: &associated_data(&self.kdf, &self.addresses)?,
+        };
+        match cipher(key).decrypt(XNonce::from_slice(&self.nonce), payload) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(Error::CustomError("Passphrase is incorrect!".to_owned())),
+        }
+    }
+}
+
+fn cipher(key: &WalletKey) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(Key::from_slice(&key.key))
+}
+
+fn associated_data(kdf: &Kdf, addresses: &[String]) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(kdf, addresses))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //? The smallest parameters Argon2 takes, the tests don't need the memory cost
+    fn key(passphrase: &str) -> WalletKey {
+        let mut salt = vec![0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        WalletKey::derive(
+            passphrase,
+            Kdf {
+                salt,
+                memory: 8,
+                iterations: 1,
+                lanes: KDF_LANES,
+            },
+        )
+        .unwrap()
+    }
+
+    fn sealed() -> Vault {
+        Vault::seal(
+            &key("passphrase"),
+            b"secret keys",
+            vec!["address".to_owned()],
+        )
+        .unwrap()
+    }
+
+    fn message(result: Result<Vec<u8>>) -> String {
+        match result {
+            Err(Error::CustomError(message)) => message,
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("vault opened"),
+        }
+    }
+
+    #[test]
+    fn opens_with_the_passphrase_it_was_sealed_with() {
+        let vault = sealed();
+        let key = vault.key("passphrase").unwrap();
+        assert_eq!(vault.open(&key).unwrap(), b"secret keys");
+
+        //? A reseal takes a new nonce but the same key still opens it
+        let resealed = Vault::seal(&key, b"more keys", vault.addresses.clone()).unwrap();
+        assert_ne!(resealed.nonce, vault.nonce);
+        assert_eq!(resealed.open(&key).unwrap(), b"more keys");
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let vault = sealed();
+        let key = vault.key("wrong passphrase").unwrap();
+        assert_eq!(message(vault.open(&key)), "Passphrase is incorrect!");
+    }
+
+    #[test]
+    fn edited_parameters_or_addresses_are_rejected() {
+        let vault = sealed();
+        let key = vault.key("passphrase").unwrap();
+
+        let mut edited = sealed();
+        edited.kdf = vault.kdf.clone();
+        edited.nonce = vault.nonce.clone();
+        edited.ciphertext = vault.ciphertext.clone();
+        edited.addresses.push("another address".to_owned());
+        assert!(vault.open(&key).is_ok());
+        assert!(edited.open(&key).is_err());
+
+        edited.addresses = vault.addresses.clone();
+        edited.kdf.iterations += 1;
+        assert!(edited.open(&key).is_err());
+    }
+}
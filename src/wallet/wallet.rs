@@ -1,13 +1,18 @@
 use rand::rngs::OsRng;
+use ripemd::Ripemd160;
 use secp256k1::{ecdsa::Signature, rand, Message, PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::Result;
+use crate::{Error, Result};
 
 static CHECKSUM_LENGTH: usize = 4;
-static VERSION: u8 = 0x00;
+//? Legacy addresses embed the whole compressed key, newer ones its hash160
+static VERSION_PUBLIC_KEY: u8 = 0x00;
+static VERSION_PUBLIC_KEY_HASH: u8 = 0x1c;
+static PUBLIC_KEY_LENGTH: usize = 33;
+pub(crate) static PUBLIC_KEY_HASH_LENGTH: usize = 20;
 
 #[derive(Serialize, Deserialize)]
 pub struct Wallet {
@@ -26,16 +31,27 @@ impl Wallet {
         })
     }
 
-    pub(crate) fn address(&self) -> String {
-        let public_key_hash = &self.public_key.0.serialize().to_vec();
+    pub(crate) fn from_secret_key(secret_key: SecretKey) -> Self {
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
 
-        let mut full_hash = vec![VERSION];
-        full_hash.extend_from_slice(public_key_hash);
+        Self {
+            private_key: WalletPrivateKey(secret_key),
+            public_key: WalletPublicKey(public_key),
+        }
+    }
 
-        let checksum = checksum(&full_hash);
-        full_hash.extend_from_slice(&checksum);
+    pub(crate) fn address(&self) -> String {
+        address_from_public_key_hash(&hash160(&self.public_key()))
+    }
 
-        bs58::encode(&full_hash).into_string()
+    //? What the wallet handed out before hash addresses, outputs locked to it stay spendable
+    pub(crate) fn legacy_address(&self) -> String {
+        address_from_public_key_hash(&self.public_key())
+    }
+
+    pub(crate) fn public_key(&self) -> Vec<u8> {
+        self.public_key.0.serialize().to_vec()
     }
 
     pub(crate) fn sign(&self, tx_id: &[u8]) -> Result<Signature> {
@@ -47,18 +63,49 @@ impl Wallet {
     }
 }
 
+pub(crate) fn hash160(public_key: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(Sha256::digest(public_key)).to_vec()
+}
+
+//? The version follows the lock, a hash or a legacy full key
+pub fn address_from_public_key_hash(public_key_hash: &[u8]) -> String {
+    let version = if public_key_hash.len() == PUBLIC_KEY_HASH_LENGTH {
+        VERSION_PUBLIC_KEY_HASH
+    } else {
+        VERSION_PUBLIC_KEY
+    };
+
+    let mut full_hash = vec![version];
+    full_hash.extend_from_slice(public_key_hash);
+
+    let checksum = checksum(&full_hash);
+    full_hash.extend_from_slice(&checksum);
+
+    bs58::encode(&full_hash).into_string()
+}
+
+//? The legacy form embeds the whole key, so its hash form is known without the private key
+pub(crate) fn hash_address(address: &str) -> Result<Option<String>> {
+    let public_key = public_key_hash_from_address(address)?;
+    if public_key.len() != PUBLIC_KEY_LENGTH {
+        return Ok(None);
+    }
+    Ok(Some(address_from_public_key_hash(&hash160(&public_key))))
+}
+
 pub fn public_key_hash_from_address(address: &str) -> Result<Vec<u8>> {
     let decoded_address: Vec<u8> = bs58::decode(address).into_vec()?;
-    Ok(decoded_address
-        .iter()
-        .skip(1)
-        .take(decoded_address.len() - CHECKSUM_LENGTH - 1)
-        .cloned()
-        .collect())
+    if decoded_address.len() <= CHECKSUM_LENGTH + 1 {
+        return Err(Error::CustomError("Address is too short!".to_owned()));
+    }
+    Ok(decoded_address[1..decoded_address.len() - CHECKSUM_LENGTH].to_vec())
 }
 
 pub(crate) fn validate_address(address: &str) -> Result<bool> {
     let decoded_address: Vec<u8> = bs58::decode(address).into_vec()?;
+    if decoded_address.len() <= CHECKSUM_LENGTH + 1 {
+        return Ok(false);
+    }
 
     let public_key_hash_len = decoded_address.len();
 
@@ -78,12 +125,16 @@ pub(crate) fn validate_address(address: &str) -> Result<bool> {
         .cloned()
         .collect();
 
+    let known_version = (*version == VERSION_PUBLIC_KEY_HASH
+        && public_key_hash.len() == PUBLIC_KEY_HASH_LENGTH)
+        || (*version == VERSION_PUBLIC_KEY && public_key_hash.len() == PUBLIC_KEY_LENGTH);
+
     let mut full_hash = vec![*version];
     full_hash.extend_from_slice(&public_key_hash);
 
     let tartget_checksum = checksum(&full_hash);
 
-    Ok(actual_checksum == tartget_checksum)
+    Ok(known_version && actual_checksum == tartget_checksum)
 }
 
 fn checksum(payload: &[u8]) -> Vec<u8> {
@@ -139,3 +190,70 @@ impl Serialize for WalletPublicKey {
         serializer.serialize_bytes(&encoded)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(version: u8, payload: &[u8]) -> String {
+        let mut full_hash = vec![version];
+        full_hash.extend_from_slice(payload);
+        let checksum = checksum(&full_hash);
+        full_hash.extend_from_slice(&checksum);
+        bs58::encode(&full_hash).into_string()
+    }
+
+    #[test]
+    fn both_address_versions_round_trip() {
+        let wallet = Wallet::new().unwrap();
+        let public_key = wallet.public_key();
+
+        let address = wallet.address();
+        assert!(validate_address(&address).unwrap());
+        assert_eq!(
+            bs58::decode(&address).into_vec().unwrap()[0],
+            VERSION_PUBLIC_KEY_HASH
+        );
+        assert_eq!(
+            public_key_hash_from_address(&address).unwrap(),
+            hash160(&public_key)
+        );
+        assert_eq!(hash_address(&address).unwrap(), None);
+
+        let legacy_address = wallet.legacy_address();
+        assert!(validate_address(&legacy_address).unwrap());
+        assert_eq!(
+            bs58::decode(&legacy_address).into_vec().unwrap()[0],
+            VERSION_PUBLIC_KEY
+        );
+        assert_eq!(
+            public_key_hash_from_address(&legacy_address).unwrap(),
+            public_key
+        );
+        assert_eq!(hash_address(&legacy_address).unwrap(), Some(address));
+    }
+
+    #[test]
+    fn each_version_takes_only_its_own_length() {
+        let wallet = Wallet::new().unwrap();
+        let hash = hash160(&wallet.public_key());
+        let public_key = wallet.public_key();
+
+        assert!(validate_address(&encode(VERSION_PUBLIC_KEY_HASH, &hash)).unwrap());
+        assert!(!validate_address(&encode(VERSION_PUBLIC_KEY_HASH, &public_key)).unwrap());
+        assert!(!validate_address(&encode(VERSION_PUBLIC_KEY_HASH, &hash[1..])).unwrap());
+
+        assert!(validate_address(&encode(VERSION_PUBLIC_KEY, &public_key)).unwrap());
+        assert!(!validate_address(&encode(VERSION_PUBLIC_KEY, &hash)).unwrap());
+        assert!(!validate_address(&encode(VERSION_PUBLIC_KEY, &public_key[1..])).unwrap());
+    }
+
+    #[test]
+    fn short_addresses_are_rejected() {
+        for length in 0..=CHECKSUM_LENGTH + 1 {
+            let address = bs58::encode(vec![VERSION_PUBLIC_KEY_HASH; length]).into_string();
+            assert!(public_key_hash_from_address(&address).is_err());
+            assert!(!validate_address(&address).unwrap());
+        }
+    }
+}
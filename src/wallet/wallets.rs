@@ -2,80 +2,380 @@ use secp256k1::ecdsa::Signature;
 use serde_derive::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fs::{create_dir_all, File, OpenOptions},
+    fs::{create_dir_all, remove_file, rename, File, OpenOptions},
     io::{Read, Write},
     path::Path,
 };
 
 use crate::{Error, Result};
 
-use super::wallet::{validate_address, Wallet};
+use super::{
+    hd::{HdWallet, KeyPath, CHANGE, GAP_LIMIT, RECEIVE},
+    vault::{Vault, WalletKey},
+    wallet::{hash_address, validate_address, Wallet},
+};
 
 static WALLET_PATH: &str = "./tmp/wallet";
 static WALLET_FILE: &str = "./tmp/wallet/wallets.data";
+static HD_FILE: &str = "./tmp/wallet/hd.data";
+//? Replaces both files above once the wallet is encrypted
+static VAULT_FILE: &str = "./tmp/wallet/wallets.vault";
 
+//? Derived keys are kept next to the random ones, the HD seed lives in its own file
+//? An encrypted wallet loads locked, with no keys nor seed until it is unlocked
 #[derive(Serialize, Deserialize)]
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    #[serde(skip)]
+    hd: Option<HdWallet>,
+    #[serde(skip)]
+    vault: Option<Vault>,
+    #[serde(skip)]
+    key: Option<WalletKey>,
 }
 
 impl Wallets {
     pub fn create_wallets() -> Result<Self> {
         let mut wallets = Wallets {
             wallets: HashMap::new(),
+            hd: None,
+            vault: None,
+            key: None,
         };
+        if Path::new(VAULT_FILE).exists() {
+            wallets.vault = Some(bincode::deserialize(&read_file(VAULT_FILE)?)?);
+            return Ok(wallets);
+        }
+
         if Path::new(WALLET_FILE).exists() {
-            let mut file = File::open(WALLET_FILE)?;
-            let mut buffer = vec![];
-            file.read_to_end(&mut buffer)?;
-            wallets = bincode::deserialize(&buffer)?;
+            wallets.wallets = bincode::deserialize(&read_file(WALLET_FILE)?)?;
         } else {
             create_dir_all(WALLET_PATH)?;
             File::create(WALLET_FILE)?;
             wallets.save_file()?;
         }
+
+        if Path::new(HD_FILE).exists() {
+            wallets.hd = Some(bincode::deserialize(&read_file(HD_FILE)?)?);
+        }
         Ok(wallets)
     }
 
-    pub fn add_wallet(&mut self) -> Result<String> {
-        let wallet = Wallet::new()?;
+    //? Nothing is read from or written to the wallet files until save_file
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Self {
+        Wallets {
+            wallets: HashMap::new(),
+            hd: None,
+            vault: None,
+            key: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.vault.is_some() || self.key.is_some()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.key.is_none() && self.vault.is_some()
+    }
+
+    fn check_unlocked(&self) -> Result<()> {
+        if self.is_locked() {
+            return Err(Error::CustomError(
+                "Wallet is locked, unlock it with its passphrase first!".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    //? The returned key unlocks the wallet again without paying for the derivation
+    pub fn unlock(&mut self, passphrase: &str) -> Result<WalletKey> {
+        let key = match &self.vault {
+            Some(vault) => vault.key(passphrase)?,
+            None => return Err(Error::CustomError("Wallet isn't encrypted!".to_owned())),
+        };
+        self.unlock_with(&key)?;
+
+        Ok(key)
+    }
+
+    pub fn unlock_with(&mut self, key: &WalletKey) -> Result<()> {
+        let plaintext = match &self.vault {
+            Some(vault) => vault.open(key)?,
+            None => return Err(Error::CustomError("Wallet isn't encrypted!".to_owned())),
+        };
+
+        let (wallets, hd) = bincode::deserialize(&plaintext)?;
+        self.wallets = wallets;
+        self.hd = hd;
+        self.key = Some(key.clone());
+        Ok(())
+    }
+
+    //? The plain files are removed, copies of them taken before stay readable
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(Error::CustomError(
+                "Wallet is already encrypted!".to_owned(),
+            ));
+        }
+
+        self.key = Some(WalletKey::generate(passphrase)?);
+        self.save_file()?;
+
+        for path in [WALLET_FILE, HD_FILE] {
+            if Path::new(path).exists() {
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn change_passphrase(&mut self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        self.unlock(old_passphrase)?;
+        self.key = Some(WalletKey::generate(new_passphrase)?);
+        self.save_file()
+    }
+
+    //? Derives the next receiving address of the account once there is an HD seed, else makes a random key
+    pub fn add_wallet(&mut self, account: Option<u32>) -> Result<String> {
+        self.check_unlocked()?;
+        let wallet = match (&mut self.hd, account) {
+            (Some(hd), account) => hd.next(account.unwrap_or(0), RECEIVE)?.1,
+            (None, None) => Wallet::new()?,
+            (None, Some(_)) => {
+                return Err(Error::CustomError(
+                    "HD seed doesn't exists, accounts need one!".to_owned(),
+                ))
+            }
+        };
+        let address = wallet.address();
+        self.wallets.insert(address.clone(), wallet);
+
+        Ok(address)
+    }
+
+    //? Returns the mnemonic, the only backup the derived keys need
+    pub fn create_hd_wallet(&mut self, words: usize) -> Result<String> {
+        self.check_unlocked()?;
+        if self.hd.is_some() {
+            return Err(Error::CustomError("HD seed already exists!".to_owned()));
+        }
+
+        let (hd, mnemonic) = HdWallet::generate(words)?;
+        self.hd = Some(hd);
+        Ok(mnemonic)
+    }
+
+    pub fn restore_hd_wallet(&mut self, mnemonic: &str) -> Result<()> {
+        self.check_unlocked()?;
+        if self.hd.is_some() {
+            return Err(Error::CustomError("HD seed already exists!".to_owned()));
+        }
+
+        self.hd = Some(HdWallet::restore(mnemonic)?);
+        Ok(())
+    }
+
+    //? Change of an HD address goes to a fresh address of its account, a random key gets its change back
+    pub fn change_address(&mut self, from: &str) -> Result<String> {
+        self.check_unlocked()?;
+        let hd = match &mut self.hd {
+            Some(hd) => hd,
+            None => return Ok(from.to_owned()),
+        };
+        let path = match hd.path(from) {
+            Some(path) => path,
+            None => return Ok(from.to_owned()),
+        };
+
+        let wallet = hd.next(path.account, CHANGE)?.1;
         let address = wallet.address();
         self.wallets.insert(address.clone(), wallet);
 
         Ok(address)
     }
 
-    pub fn get_wallet(&mut self, address: &str) -> Result<Option<&mut Wallet>> {
+    //? The address itself first, then the other addresses of its HD account, change included
+    pub(crate) fn account_addresses(&self, address: &str) -> Vec<String> {
+        let mut addresses = vec![address.to_owned()];
+        if let (Some(hd), Some(path)) = (&self.hd, self.key_path(address)) {
+            addresses.extend(
+                hd.account_addresses(path.account)
+                    .into_iter()
+                    .filter(|other| other != address),
+            );
+        }
+        addresses
+    }
+
+    pub fn key_path(&self, address: &str) -> Option<KeyPath> {
+        self.hd.as_ref().and_then(|hd| hd.path(address))
+    }
+
+    pub(crate) fn is_change(&self, address: &str) -> bool {
+        self.key_path(address)
+            .is_some_and(|path| path.change == CHANGE)
+    }
+
+    //? Walks each account's receiving and change chains until GAP_LIMIT unused addresses in a row,
+    //? then the next account while the previous one was used, returns the addresses it added
+    pub fn rescan(&mut self, is_used: impl Fn(&str) -> Result<bool>) -> Result<Vec<String>> {
+        self.check_unlocked()?;
+        let hd = match &mut self.hd {
+            Some(hd) => hd,
+            None => return Err(Error::CustomError("HD seed doesn't exists!".to_owned())),
+        };
+
+        let mut found = vec![];
+        let mut account = 0;
+        loop {
+            let mut account_used = false;
+            for change in [RECEIVE, CHANGE] {
+                let mut index = 0;
+                let mut unused = 0;
+                while unused < GAP_LIMIT {
+                    let path = KeyPath {
+                        account,
+                        change,
+                        index,
+                    };
+                    //? Seeds from before hash addresses may have used the legacy form of the key
+                    let wallet = hd.derive(&path)?;
+                    let mut used = false;
+                    for address in [wallet.address(), wallet.legacy_address()] {
+                        if !is_used(&address)? {
+                            continue;
+                        }
+                        used = true;
+                        hd.record(&address, path)?;
+                        if !self.wallets.contains_key(&address) {
+                            self.wallets.insert(address.clone(), hd.derive(&path)?);
+                            found.push(address);
+                        }
+                    }
+
+                    if used {
+                        account_used = true;
+                        unused = 0;
+                    } else {
+                        unused += 1;
+                    }
+                    index += 1;
+                }
+            }
+
+            if !account_used {
+                break;
+            }
+            account += 1;
+        }
+
+        Ok(found)
+    }
+
+    //? Works on a locked wallet too, its addresses are stored in the clear
+    pub fn check_address(&self, address: &str) -> Result<()> {
         if !validate_address(address)? {
             return Err(Error::CustomError("Wallet is invalid!".to_owned()));
         };
 
-        if let Some(wallet) = self.wallets.get_mut(address) {
-            Ok(Some(wallet))
+        if self
+            .list_addresses()
+            .iter()
+            .any(|known| known == address || self.hash_address(known).as_deref() == Some(address))
+        {
+            Ok(())
         } else {
             Err(Error::CustomError("Address doesn't exists!".to_owned()))
         }
     }
 
     pub fn list_addresses(&self) -> Vec<String> {
-        self.wallets.keys().cloned().collect()
+        match &self.vault {
+            Some(vault) if self.is_locked() => vault.addresses.clone(),
+            _ => self.wallets.keys().cloned().collect(),
+        }
+    }
+
+    //? The hash form of a legacy key, the same key answers for both addresses
+    pub fn hash_address(&self, address: &str) -> Option<String> {
+        hash_address(address).ok().flatten()
+    }
+
+    fn wallet(&self, address: &str) -> Option<&Wallet> {
+        self.wallets.get(address).or_else(|| {
+            self.wallets
+                .iter()
+                .find(|(known, _)| self.hash_address(known).as_deref() == Some(address))
+                .map(|(_, wallet)| wallet)
+        })
     }
 
+    //? An unlocked encrypted wallet is sealed again, a locked one can't be written
     pub fn save_file(&self) -> Result<()> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(WALLET_FILE)?;
-
-        let encoded = bincode::serialize(&self.wallets)?;
-        file.write_all(&encoded)?;
+        if let Some(key) = &self.key {
+            let plaintext = bincode::serialize(&(&self.wallets, &self.hd))?;
+            let vault = Vault::seal(key, &plaintext, self.wallets.keys().cloned().collect())?;
+            return write_file(VAULT_FILE, &bincode::serialize(&vault)?);
+        }
+        self.check_unlocked()?;
+
+        write_file(WALLET_FILE, &bincode::serialize(&self.wallets)?)?;
+        if let Some(hd) = &self.hd {
+            write_file(HD_FILE, &bincode::serialize(hd)?)?;
+        }
         Ok(())
     }
 
-    pub fn sign_tx(&mut self, tx_id: &[u8], address: &str) -> Result<Signature> {
-        self.get_wallet(address)?
-            .expect("Wallet doesn't exists!")
-            .sign(tx_id)
+    pub(crate) fn public_key(&self, address: &str) -> Result<Vec<u8>> {
+        self.check_unlocked()?;
+
+        match self.wallet(address) {
+            Some(wallet) => Ok(wallet.public_key()),
+            None => Err(Error::CustomError("Address doesn't exists!".to_owned())),
+        }
+    }
+
+    //? Either address of the key will do, both sign the same
+    pub(crate) fn find_address(&self, public_key: &[u8]) -> Result<Option<String>> {
+        self.check_unlocked()?;
+
+        Ok(self
+            .wallets
+            .iter()
+            .find(|(_, wallet)| wallet.public_key() == public_key)
+            .map(|(address, _)| address.clone()))
     }
+
+    pub fn sign_tx(&self, tx_id: &[u8], address: &str) -> Result<Signature> {
+        self.check_unlocked()?;
+
+        match self.wallet(address) {
+            Some(wallet) => wallet.sign(tx_id),
+            None => Err(Error::CustomError("Address doesn't exists!".to_owned())),
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+//? Written aside then renamed over, a crash mid-write leaves the previous keys whole
+fn write_file(path: &str, data: &[u8]) -> Result<()> {
+    let temporary = format!("{}.tmp", path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename(&temporary, path)?;
+    Ok(())
 }